@@ -0,0 +1,201 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::batch::FileReport;
+use crate::outcome::{Failure, Outcome};
+use crate::output::{hold_in_memory, release_from_memory, take_from_memory, ExistingOutput};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+// Already compressed, deflating them again only costs time
+const STORED_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "gif"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn of_path(path: &str) -> Result<Self, String> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+            Ok(ArchiveFormat::TarGz)
+        } else if lower.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else {
+            Err(format!("Unsupported archive {}, expected a .zip, .tar.gz, .tgz or .tar file", path))
+        }
+    }
+}
+
+enum Writer {
+    Zip(ZipWriter<BufWriter<File>>),
+    Tar(tar::Builder<BufWriter<File>>),
+    TarGz(tar::Builder<GzEncoder<BufWriter<File>>>),
+}
+
+fn tar_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()));
+    header.set_entry_type(tar::EntryType::Regular);
+    header
+}
+
+impl Writer {
+    fn append(&mut self, name: &str, size: u64, data: &mut impl Read) -> io::Result<()> {
+        match self {
+            Writer::Zip(zip) => {
+                let extension = Path::new(name).extension().map_or(String::new(), |ext| ext.to_string_lossy().to_lowercase());
+                let method = if STORED_EXTENSIONS.contains(&extension.as_str()) { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+                let options = SimpleFileOptions::default()
+                    .compression_method(method)
+                    .large_file(size > u32::MAX as u64)
+                    .unix_permissions(0o644);
+                zip.start_file(name, options)?;
+                io::copy(data, zip)?;
+                Ok(())
+            }
+            Writer::Tar(tar) => tar.append_data(&mut tar_header(size), name, data),
+            Writer::TarGz(tar) => tar.append_data(&mut tar_header(size), name, data),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let file = match self {
+            Writer::Zip(zip) => zip.finish()?,
+            Writer::Tar(tar) => tar.into_inner()?,
+            Writer::TarGz(tar) => tar.into_inner()?.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+// One archived output
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    generator: String,
+    files: &'a [FileReport],
+    entries: &'a [ManifestEntry],
+}
+
+struct State {
+    writer: Option<Writer>,
+    entries: Vec<ManifestEntry>,
+}
+
+// Extraction outputs collected into a single archive. Outputs are written under the archive's
+// path as if it were a directory, but are only held in memory until their input is done and
+// they are appended to the archive; nothing is written to disk besides the archive itself.
+// The archive is written next to its final path and moved there by `finish`; dropping it
+// unfinished throws everything away.
+pub struct Archive {
+    target: PathBuf,
+    partial: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Archive {
+    pub fn create(path: &str, mode: ExistingOutput) -> Result<Archive, Box<dyn Error>> {
+        let format = ArchiveFormat::of_path(path)?;
+        let target = PathBuf::from(path);
+        if target.is_dir() {
+            return Err(format!("{} is a directory, not an archive", target.display()).into());
+        }
+        if mode == ExistingOutput::Refuse && target.exists() {
+            return Err(format!("{} already exists, use --overwrite or --clean to replace it", target.display()).into());
+        }
+        if let Some(parent) = target.parent().filter(|parent| *parent != Path::new("")) {
+            fs::create_dir_all(parent)?;
+        }
+        let name = target.file_name().map_or("archive".to_string(), |name| name.to_string_lossy().to_string());
+        let partial = target.with_file_name(format!(".{}.partial-{}", name, std::process::id()));
+        let file = BufWriter::new(File::create(&partial)?);
+        let writer = match format {
+            ArchiveFormat::Zip => Writer::Zip(ZipWriter::new(file)),
+            ArchiveFormat::Tar => Writer::Tar(tar::Builder::new(file)),
+            ArchiveFormat::TarGz => Writer::TarGz(tar::Builder::new(GzEncoder::new(file, Compression::default()))),
+        };
+
+        hold_in_memory(&target);
+        Ok(Archive { target, partial, state: Mutex::new(State { writer: Some(writer), entries: Vec::new() }) })
+    }
+
+    // Where the outputs are written to be archived
+    pub fn root(&self) -> String {
+        self.target.to_string_lossy().to_string()
+    }
+
+    // Append the outputs written at or under `path` to the archive
+    pub fn add(&self, path: &Path, input: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let State { writer, entries } = &mut *state;
+        let writer = writer.as_mut().ok_or("Archive already finished")?;
+        for (file, bytes) in take_from_memory(path) {
+            let name = file.strip_prefix(&self.target)?.to_string_lossy().replace('\\', "/");
+            writer.append(&name, bytes.len() as u64, &mut bytes.as_slice()).map_err(|e| format!("Cannot write {} to {}: {}", name, self.target.display(), e))?;
+            let sha256 = Sha256::digest(&bytes).iter().map(|b| format!("{:02x}", b)).collect();
+            entries.push(ManifestEntry { path: name, input: input.map(str::to_string), size: bytes.len() as u64, sha256 });
+        }
+        Ok(())
+    }
+
+    // Archive what one input produced; a failure to do so makes it an output error
+    pub fn add_report(&self, report: &mut FileReport) {
+        if let Err(e) = self.add(Path::new(&report.output_dir), Some(&report.input)) {
+            report.errors.push(e.to_string());
+            report.outcome = report.outcome.max(Outcome::OutputError);
+            report.status = "failed".to_string();
+        }
+    }
+
+    // Write the manifest and move the archive into place
+    pub fn finish(self, reports: &[FileReport]) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut writer = state.writer.take().ok_or("Archive already finished")?;
+        let manifest = serde_json::to_vec_pretty(&Manifest {
+            generator: format!("octExtractor {}", env!("CARGO_PKG_VERSION")),
+            files: reports,
+            entries: &state.entries,
+        })?;
+        writer.append(MANIFEST_NAME, manifest.len() as u64, &mut manifest.as_slice()).map_err(Failure::output)?;
+        writer.finish().map_err(Failure::output)?;
+        fs::rename(&self.partial, &self.target).map_err(Failure::output)?;
+        Ok(())
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        release_from_memory(&self.target);
+        if self.partial.exists() {
+            if let Err(e) = fs::remove_file(&self.partial) {
+                log::warn!("Could not remove {}: {}", self.partial.display(), e);
+            }
+        }
+    }
+}
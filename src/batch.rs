@@ -0,0 +1,250 @@
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use walkdir::WalkDir;
+
+use crate::archive::Archive;
+use crate::extract::{extract, file_token, ExtractOptions};
+use crate::formats::detect_format;
+use crate::outcome::{Failure, Outcome};
+use crate::output::{create_output_dir, with_staging, write_output, ExistingOutput};
+
+// One row of the batch summary
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub input: String,
+    pub output_dir: String,
+    pub format: String,
+    // ok, partial (some outputs failed) or failed
+    pub status: String,
+    pub outcome: Outcome,
+    pub bscans: usize,
+    pub missing_chunks: Vec<String>,
+    pub errors: Vec<String>,
+    pub duration_ms: u128,
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+// Files under `dir` in a format we can read; anything else in the tree is skipped
+fn walk_directory(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.map_err(|e| log::warn!("{}", e)).ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| detect_format(&path.to_string_lossy()).is_ok())
+        .collect()
+}
+
+// Files, directories (searched recursively) and glob patterns to a sorted list of files
+pub fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            files.extend(walk_directory(path));
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else if is_glob(input) {
+            for entry in glob::glob(input)? {
+                let entry = entry?;
+                if entry.is_dir() {
+                    files.extend(walk_directory(&entry));
+                } else {
+                    files.push(entry);
+                }
+            }
+        } else {
+            return Err(Failure::input(format!("{} does not exist", input)).into());
+        }
+    }
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+    Ok(files)
+}
+
+// One subfolder per input, named after the file or its de-identified token; repeated names get _2, _3, ...
+pub fn output_dirs(files: &[PathBuf], output_dir: &str, options: &ExtractOptions) -> Vec<String> {
+    let mut used = HashSet::new();
+    files
+        .iter()
+        .map(|file| {
+            let stem = file_token(&file.to_string_lossy(), options);
+            let mut name = stem.clone();
+            let mut n = 2;
+            while !used.insert(name.to_lowercase()) {
+                name = format!("{}_{}", stem, n);
+                n += 1;
+            }
+            format!("{}/{}", output_dir, name)
+        })
+        .collect()
+}
+
+pub fn extract_one(file: &Path, output_dir: &str, existing: ExistingOutput, options: &ExtractOptions) -> FileReport {
+    let input = file.to_string_lossy().to_string();
+    // With de-identification the input is reported by the name of its output folder, which is
+    // built from the de-identified token and unique within the run
+    let reported = match Path::new(output_dir).file_name() {
+        Some(name) if options.deidentifier.is_some() => name.to_string_lossy().to_string(),
+        _ => input.clone(),
+    };
+    let start = Instant::now();
+    let mut report = FileReport {
+        input: reported.clone(),
+        output_dir: output_dir.to_string(),
+        format: String::new(),
+        status: "failed".to_string(),
+        outcome: Outcome::Failed,
+        bscans: 0,
+        missing_chunks: Vec::new(),
+        errors: Vec::new(),
+        duration_ms: 0,
+    };
+    match with_staging(output_dir, existing, |staging| extract(&input, staging, options)) {
+        Ok(extracted) => {
+            report.outcome = extracted.outcome();
+            report.status = if extracted.errors.is_empty() { "ok" } else { "partial" }.to_string();
+            report.format = extracted.format;
+            report.bscans = extracted.bscans;
+            report.missing_chunks = extracted.missing_chunks;
+            report.errors = extracted.errors;
+        }
+        Err(e) => {
+            report.outcome = Outcome::of_error(e.as_ref());
+            report.errors.push(e.to_string());
+        }
+    }
+    if reported != input {
+        report.errors = report.errors.iter().map(|e| e.replace(&input, &reported)).collect();
+    }
+    report.duration_ms = start.elapsed().as_millis();
+    report
+}
+
+// Extract every file into its own subfolder of `output_dir`, at most `jobs` files at a time.
+// With an archive, each subfolder is moved into it as soon as its file is done.
+pub fn run_batch(
+    files: &[PathBuf],
+    output_dir: &str,
+    existing: ExistingOutput,
+    options: &ExtractOptions,
+    jobs: Option<usize>,
+    archive: Option<&Archive>,
+) -> Result<Vec<FileReport>, Box<dyn Error>> {
+    create_output_dir(output_dir)?;
+    let dirs = output_dirs(files, output_dir, options);
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs.unwrap_or(0)).build()?;
+    Ok(pool.install(|| {
+        files
+            .par_iter()
+            .zip(dirs.par_iter())
+            .map(|(file, dir)| {
+                let mut report = extract_one(file, dir, existing, options);
+                if let Some(archive) = archive {
+                    archive.add_report(&mut report);
+                }
+                report
+            })
+            .collect()
+    }))
+}
+
+// JSON when the path ends in .json, CSV otherwise
+pub fn write_summary(reports: &[FileReport], path: &str) -> Result<(), Box<dyn Error>> {
+    if path.to_lowercase().ends_with(".json") {
+        write_output(path, serde_json::to_string_pretty(reports)?.as_bytes())?;
+        return Ok(());
+    }
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["input", "output_dir", "format", "status", "outcome", "bscans", "missing_chunks", "errors", "duration_ms"])?;
+    for report in reports {
+        writer.write_record([
+            report.input.clone(),
+            report.output_dir.clone(),
+            report.format.clone(),
+            report.status.clone(),
+            report.outcome.description().to_string(),
+            report.bscans.to_string(),
+            report.missing_chunks.join(";"),
+            report.errors.join(";"),
+            report.duration_ms.to_string(),
+        ])?;
+    }
+    write_output(path, &writer.into_inner().map_err(|e| e.into_error())?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExtractConfig;
+    use std::fs;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("octExtractor-batch-test-{}-{}", std::process::id(), name));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn options() -> ExtractOptions {
+        ExtractConfig { extension: Some("png".to_string()), ..Default::default() }.build().unwrap().options
+    }
+
+    #[test]
+    fn inputs_are_expanded_and_deduplicated() {
+        let dir = scratch("inputs");
+        fs::create_dir_all(dir.join("study/eye")).unwrap();
+        fs::write(dir.join("study/eye/b.fda"), b"FOCTFDA").unwrap();
+        fs::write(dir.join("study/a.fds"), b"FOCTFDS").unwrap();
+        // Not in a format we read, skipped when found in a directory
+        fs::write(dir.join("study/notes.txt"), b"notes").unwrap();
+        let study = dir.join("study").to_string_lossy().to_string();
+        let pattern = dir.join("study/*.fds").to_string_lossy().to_string();
+        let files = expand_inputs(&[study, pattern]).unwrap();
+        assert_eq!(files, vec![dir.join("study/a.fds"), dir.join("study/eye/b.fda")]);
+        assert!(expand_inputs(&[dir.join("missing.fda").to_string_lossy().to_string()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_names_get_numbered_folders() {
+        let files = ["a/scan.fda", "b/scan.fda", "b/SCAN.fds", "c/other.fda"].map(PathBuf::from);
+        assert_eq!(output_dirs(&files, "out", &options()), vec!["out/scan", "out/scan_2", "out/SCAN_3", "out/other"]);
+    }
+
+    #[test]
+    fn summary_is_csv_or_json() {
+        let dir = scratch("summary");
+        let report = FileReport {
+            input: "scan.fda".to_string(),
+            output_dir: "out/scan".to_string(),
+            format: "Topcon FDA".to_string(),
+            status: "partial".to_string(),
+            outcome: Outcome::Partial,
+            bscans: 8,
+            missing_chunks: vec!["@THUMBNAIL".to_string(), "@IMG_TRC_02".to_string()],
+            errors: vec!["montage failed".to_string()],
+            duration_ms: 12,
+        };
+        let csv = dir.join("summary.csv").to_string_lossy().to_string();
+        write_summary(std::slice::from_ref(&report), &csv).unwrap();
+        let lines: Vec<String> = fs::read_to_string(&csv).unwrap().lines().map(str::to_string).collect();
+        assert_eq!(lines[1], "scan.fda,out/scan,Topcon FDA,partial,partial,8,@THUMBNAIL;@IMG_TRC_02,montage failed,12");
+        let json = dir.join("summary.json").to_string_lossy().to_string();
+        write_summary(&[report], &json).unwrap();
+        let rows: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(rows[0]["bscans"], 8);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
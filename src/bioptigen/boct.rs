@@ -0,0 +1,275 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use image::{DynamicImage, GrayImage};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::sync::OnceLock;
+
+use crate::formats::{bscan_entries, ImageEntry, Metadata, OctReader};
+use crate::volume::{Volume, VoxelSpacing};
+
+// Files start with a 4-byte magic number, a u16 version and a u16 frame header
+// flag, followed by tags: u32 key length, key, u32 data length, data
+pub const BIOPTIGEN_MAGIC: u32 = 0x0001_FFFF;
+
+// Header tags holding numbers; everything else is kept as text
+const U32_TAGS: [&str; 8] = ["FRAMECOUNT", "LINECOUNT", "LINELENGTH", "SAMPLEFORMAT", "SCANTYPE", "SCANS", "FRAMES", "DOPPLERFLAG"];
+const F64_TAGS: [&str; 10] = ["XMIN", "XMAX", "YMIN", "YMAX", "SCANDEPTH", "SCANLENGTH", "AZSCANLENGTH", "ELSCANLENGTH", "OBJECTDISTANCE", "SCANANGLE"];
+
+#[derive(Debug)]
+pub struct BioptigenTag {
+    pub key: String,
+    pub data: Vec<u8>,
+}
+
+fn too_long(what: &str, length: u64, remaining: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Tag {} of {} bytes runs past the end, {} bytes left", what, length, remaining))
+}
+
+impl BioptigenTag {
+    // Key and data length of the next tag, leaving the reader at its data. `remaining` is what is
+    // left of the file or frame, no tag may claim more than that.
+    pub fn header_from_reader<R: Read>(reader: &mut R, remaining: u64) -> io::Result<(String, u64)> {
+        let key_length = reader.read_u32::<LittleEndian>()? as u64;
+        if key_length + 8 > remaining {
+            return Err(too_long("key", key_length, remaining));
+        }
+        let mut key = vec![0; key_length as usize];
+        reader.read_exact(&mut key)?;
+        let key = String::from_utf8_lossy(&key).to_string();
+        let data_length = reader.read_u32::<LittleEndian>()? as u64;
+        if key_length + data_length + 8 > remaining {
+            return Err(too_long(&format!("{} data", key), data_length, remaining - key_length - 8));
+        }
+        Ok((key, data_length))
+    }
+
+    pub fn from_reader<R: Read>(reader: &mut R, remaining: u64) -> io::Result<Self> {
+        let (key, data_length) = BioptigenTag::header_from_reader(reader, remaining)?;
+        let mut data = vec![0; data_length as usize];
+        reader.read_exact(&mut data)?;
+        Ok(BioptigenTag { key, data })
+    }
+
+    fn as_u32(&self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.data.get(..4)?.try_into().ok()?))
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.data.get(..8)?.try_into().ok()?))
+    }
+
+    fn value_string(&self) -> String {
+        if U32_TAGS.contains(&self.key.as_str()) {
+            self.as_u32().map_or_else(String::new, |v| v.to_string())
+        } else if F64_TAGS.contains(&self.key.as_str()) {
+            self.as_f64().map_or_else(String::new, |v| v.to_string())
+        } else {
+            String::from_utf8_lossy(&self.data).replace('\u{0000}', "").trim().to_string()
+        }
+    }
+}
+
+pub fn has_bioptigen_signature(signature: &[u8]) -> bool {
+    signature.starts_with(&BIOPTIGEN_MAGIC.to_le_bytes())
+}
+
+// One frame: its sub-tags, of which FRAMESAMPLES holds the A-scans as u16
+fn read_frame(tag: &BioptigenTag, line_length: usize) -> Result<(Vec<u16>, usize), Box<dyn Error>> {
+    let mut cursor = io::Cursor::new(&tag.data);
+    let mut lines = None;
+    while (cursor.position() as usize) < tag.data.len() {
+        let remaining = tag.data.len() as u64 - cursor.position();
+        let sub_tag = BioptigenTag::from_reader(&mut cursor, remaining)?;
+        match sub_tag.key.as_str() {
+            "FRAMELINES" => lines = sub_tag.as_u32().map(|v| v as usize),
+            "FRAMESAMPLES" => {
+                let samples: Vec<u16> = sub_tag.data.chunks_exact(2).map(|px| u16::from_le_bytes([px[0], px[1]])).collect();
+                let lines = lines.unwrap_or(samples.len() / line_length.max(1));
+                if line_length == 0 || samples.len() < lines * line_length {
+                    return Err(format!("FRAMESAMPLES holds {} samples, expected {}x{}", samples.len(), lines, line_length).into());
+                }
+                return Ok((samples, lines));
+            }
+            _ => {}
+        }
+    }
+    Err("Frame without FRAMESAMPLES".into())
+}
+
+// Each line is one A-scan, so the B-scan is the transposed frame
+fn frame_image(samples: &[u16], lines: usize, line_length: usize, max: u16) -> GrayImage {
+    let max = max.max(1) as f64;
+    GrayImage::from_fn(lines as u32, line_length as u32, |x, z| {
+        let value = samples[x as usize * line_length + z as usize] as f64;
+        image::Luma([(value / max * 255.0).round() as u8])
+    })
+}
+
+pub struct BioptigenReader {
+    filepath: String,
+    header: HashMap<String, BioptigenTag>,
+    // Offset and length of the data of every FRAMEDATA tag
+    frames: Vec<(u64, u64)>,
+    line_length: usize,
+    info: HashMap<String, String>,
+    // Samples are scaled by the largest one in the file, found the first time a frame is read
+    max_sample: OnceLock<u16>,
+}
+
+impl BioptigenReader {
+    fn read_samples(&self, index: usize) -> Result<(Vec<u16>, usize), Box<dyn Error>> {
+        let &(offset, length) = self.frames.get(index).ok_or_else(|| format!("Frame {} out of range, the file has {}", index, self.frames.len()))?;
+        let mut file = File::open(&self.filepath)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; length as usize];
+        file.read_exact(&mut data)?;
+        read_frame(&BioptigenTag { key: "FRAMEDATA".to_string(), data }, self.line_length).map_err(|e| format!("Frame {}: {}", index, e).into())
+    }
+
+    // Reads, but does not convert, every frame
+    fn max_sample(&self) -> Result<u16, Box<dyn Error>> {
+        if let Some(max) = self.max_sample.get() {
+            return Ok(*max);
+        }
+        let mut max = 0;
+        for index in 0..self.frames.len() {
+            let (samples, _) = self.read_samples(index)?;
+            max = samples.into_iter().fold(max, u16::max);
+        }
+        Ok(*self.max_sample.get_or_init(|| max))
+    }
+}
+
+impl OctReader for BioptigenReader {
+    // Only the header tags are read, the frames are located and skipped
+    fn open(filepath: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(filepath)?;
+        let file_size = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        let magic = file.read_u32::<LittleEndian>()?;
+        if magic != BIOPTIGEN_MAGIC {
+            return Err(format!("Not a Bioptigen OCT file (magic {:#010x})", magic).into());
+        }
+        let version = file.read_u16::<LittleEndian>()?;
+        let _frame_header = file.read_u16::<LittleEndian>()?;
+
+        let mut info = HashMap::new();
+        info.insert("magic".to_string(), format!("{:#010x}", magic));
+        info.insert("version".to_string(), format!("{:#06x}", version));
+
+        let mut header = HashMap::new();
+        let mut frames = Vec::new();
+        // Tags run to the end of the file, one cut short is an error rather than the end
+        let mut position = file.stream_position()?;
+        while position < file_size {
+            let (key, data_length) = BioptigenTag::header_from_reader(&mut file, file_size - position)
+                .map_err(|e| format!("Truncated or corrupt tag at byte {}: {}", position, e))?;
+            if key == "FRAMEDATA" {
+                frames.push((file.stream_position()?, data_length));
+                file.seek_relative(data_length as i64)?;
+            } else {
+                let mut data = vec![0; data_length as usize];
+                file.read_exact(&mut data)?;
+                if frames.is_empty() {
+                    header.insert(key.clone(), BioptigenTag { key, data });
+                }
+            }
+            position = file.stream_position()?;
+        }
+        if frames.is_empty() {
+            return Err("Bioptigen file holds no frames".into());
+        }
+
+        let frame_count = header.get("FRAMECOUNT").and_then(BioptigenTag::as_u32).unwrap_or(0) as usize;
+        if frame_count != frames.len() {
+            log::warn!("Header announces {} frames, found {}", frame_count, frames.len());
+        }
+        for (key, tag) in &header {
+            info.insert(key.to_lowercase(), tag.value_string());
+        }
+        let line_length = header.get("LINELENGTH").and_then(BioptigenTag::as_u32).unwrap_or(0) as usize;
+        Ok(BioptigenReader { filepath: filepath.to_string(), header, frames, line_length, info, max_sample: OnceLock::new() })
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Bioptigen OCT"
+    }
+
+    fn metadata(&self) -> Result<Metadata, Box<dyn Error>> {
+        Ok(HashMap::from([("BIOPTIGEN_HEADER".to_string(), self.info.clone())]))
+    }
+
+    fn list_images(&self) -> Result<Vec<ImageEntry>, Box<dyn Error>> {
+        Ok(bscan_entries(self.frames.len()))
+    }
+
+    fn read_image(&self, entry: &ImageEntry) -> Result<DynamicImage, Box<dyn Error>> {
+        let (samples, lines) = self.read_samples(entry.index)?;
+        Ok(DynamicImage::ImageLuma8(frame_image(&samples, lines, self.line_length, self.max_sample()?)))
+    }
+
+    fn read_volume(&self) -> Result<Volume, Box<dyn Error>> {
+        let raw_frames = (0..self.frames.len()).map(|index| self.read_samples(index)).collect::<Result<Vec<_>, _>>()?;
+        let max = raw_frames.iter().flat_map(|(samples, _)| samples.iter()).copied().max().unwrap_or(0);
+        let slices: Vec<GrayImage> = raw_frames.iter().map(|(samples, lines)| frame_image(samples, *lines, self.line_length, max)).collect();
+
+        let header_f64 = |key: &str| self.header.get(key).and_then(BioptigenTag::as_f64).unwrap_or(0.0);
+        let lines = slices.first().map_or(0, |s| s.width() as usize);
+        let spacing = VoxelSpacing {
+            x_mm: header_f64("SCANLENGTH") / lines.max(1) as f64,
+            y_mm: header_f64("ELSCANLENGTH") / slices.len().max(1) as f64,
+            z_mm: header_f64("SCANDEPTH") / self.line_length.max(1) as f64,
+        };
+        Volume::from_slices(&slices, spacing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = (key.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn reads_a_tag() {
+        let bytes = tag(b"LINECOUNT", &80u32.to_le_bytes());
+        let read = BioptigenTag::from_reader(&mut bytes.as_slice(), bytes.len() as u64).unwrap();
+        assert_eq!(read.key, "LINECOUNT");
+        assert_eq!(read.value_string(), "80");
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_rejected_before_allocating() {
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"KEY");
+        assert!(BioptigenTag::from_reader(&mut bytes.as_slice(), bytes.len() as u64).is_err());
+
+        let mut bytes = tag(b"FRAMEDATA", &[0; 16]);
+        bytes[13..17].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BioptigenTag::from_reader(&mut bytes.as_slice(), bytes.len() as u64).is_err());
+    }
+
+    #[test]
+    fn a_truncated_tag_is_an_error() {
+        let bytes = tag(b"FRAMEDATA", &[0; 16]);
+        let cut = &bytes[..bytes.len() - 4];
+        assert!(BioptigenTag::from_reader(&mut &cut[..], cut.len() as u64).is_err());
+    }
+
+    #[test]
+    fn detected_on_the_magic_number() {
+        let mut signature = BIOPTIGEN_MAGIC.to_le_bytes().to_vec();
+        signature.extend_from_slice(&[0xFE, 0xFF, 0, 0]);
+        signature.extend(tag(b"DESCRIPTION", b"x"));
+        assert!(has_bioptigen_signature(&signature));
+        assert!(!has_bioptigen_signature(&tag(b"FRAMECOUNT", &[0; 4])));
+    }
+}
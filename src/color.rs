@@ -0,0 +1,374 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{DynamicImage, ImageFormat, RgbImage};
+use std::error::Error;
+use std::io::{Cursor, Write};
+
+const REFERENCE_KELVIN: f64 = 6500.0;
+const SOFTWARE: &str = "octExtractor";
+
+pub struct ColorOptions {
+    pub white_balance: bool,
+    pub embed_profile: bool,
+}
+
+impl ColorOptions {
+    pub fn is_active(&self) -> bool {
+        self.white_balance || self.embed_profile
+    }
+}
+
+// Camera fields recorded in @PARAM_OBS_02
+#[derive(Debug, Clone, Default)]
+pub struct CameraInfo {
+    pub camera_model: String,
+    pub color_temperature: String,
+    pub color_temperature_value: u16,
+}
+
+impl CameraInfo {
+    pub fn kelvin(&self) -> Option<f64> {
+        let kelvin = self.color_temperature_value as f64;
+        (1000.0..=40000.0).contains(&kelvin).then_some(kelvin)
+    }
+
+    fn description(&self, white_balanced: bool) -> String {
+        format!(
+            "Color temperature: {} ({} K); white balance: {}",
+            self.color_temperature.trim(),
+            self.color_temperature_value,
+            if white_balanced { "corrected to 6500 K" } else { "as recorded" }
+        )
+    }
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Approximate sRGB colour of a black body at the given temperature (Tanner Helland fit)
+fn kelvin_to_rgb(kelvin: f64) -> [f64; 3] {
+    let t = kelvin / 100.0;
+    let red = if t <= 66.0 { 255.0 } else { 329.698727446 * (t - 60.0).powf(-0.1332047592) };
+    let green = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    [red, green, blue].map(|c| c.clamp(1.0, 255.0) / 255.0)
+}
+
+// Von Kries style correction from the recorded illuminant to D65, applied in linear light
+pub fn white_balance(image: &mut RgbImage, kelvin: f64) {
+    let source = kelvin_to_rgb(kelvin).map(srgb_to_linear);
+    let reference = kelvin_to_rgb(REFERENCE_KELVIN).map(srgb_to_linear);
+    let gains: Vec<f64> = (0..3).map(|c| reference[c] / source[c]).collect();
+    // Keep the green channel unchanged so overall brightness is preserved
+    let gains: Vec<f64> = gains.iter().map(|g| g / gains[1]).collect();
+
+    let luts: Vec<[u8; 256]> = gains
+        .iter()
+        .map(|gain| {
+            let mut lut = [0u8; 256];
+            for (value, entry) in lut.iter_mut().enumerate() {
+                let linear = srgb_to_linear(value as f64 / 255.0) * gain;
+                *entry = (linear_to_srgb(linear.min(1.0)) * 255.0).round() as u8;
+            }
+            lut
+        })
+        .collect();
+
+    for pixel in image.pixels_mut() {
+        for c in 0..3 {
+            pixel.0[c] = luts[c][pixel.0[c] as usize];
+        }
+    }
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(x: f64, y: f64, z: f64) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in [x, y, z] {
+        tag.extend_from_slice(&s15_fixed16(value));
+    }
+    tag
+}
+
+// Minimal ICC v2 display profile for sRGB (D50 adapted primaries, sampled sRGB tone curve)
+pub fn srgb_icc_profile() -> Vec<u8> {
+    let mut description = b"desc\0\0\0\0".to_vec();
+    let name = b"sRGB IEC61966-2.1\0";
+    description.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    description.extend_from_slice(name);
+    description.extend_from_slice(&[0u8; 4 + 4 + 2 + 1 + 67]);
+
+    let mut copyright = b"text\0\0\0\0".to_vec();
+    copyright.extend_from_slice(b"No copyright, use freely\0");
+
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    curve.extend_from_slice(&1024u32.to_be_bytes());
+    for i in 0..1024 {
+        let value = srgb_to_linear(i as f64 / 1023.0);
+        curve.extend_from_slice(&((value * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", description),
+        (b"cprt", copyright),
+        (b"wtpt", xyz_tag(0.9642, 1.0, 0.8249)),
+        (b"rXYZ", xyz_tag(0.4361, 0.2225, 0.0139)),
+        (b"gXYZ", xyz_tag(0.3851, 0.7169, 0.0971)),
+        (b"bXYZ", xyz_tag(0.1431, 0.0606, 0.7141)),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let data_start = 128 + 4 + 12 * tags.len();
+    for (signature, tag) in &tags {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+    }
+
+    let size = 128 + table.len() + data.len();
+    let mut header = Vec::with_capacity(128);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[0u8; 4]); // preferred CMM
+    header.extend_from_slice(&[0x02, 0x10, 0x00, 0x00]); // version 2.1
+    header.extend_from_slice(b"mntrRGB XYZ ");
+    header.extend_from_slice(&[0u8; 12]); // creation date
+    header.extend_from_slice(b"acsp");
+    header.extend_from_slice(&[0u8; 24]); // platform, flags, manufacturer, model, attributes
+    header.extend_from_slice(&[0u8; 4]); // rendering intent: perceptual
+    header.extend_from_slice(&s15_fixed16(0.9642));
+    header.extend_from_slice(&s15_fixed16(1.0));
+    header.extend_from_slice(&s15_fixed16(0.8249));
+    header.resize(128, 0);
+
+    [header, table, data].concat()
+}
+
+// Little-endian TIFF structure with the camera fields, as used by EXIF APP1 segments and PNG eXIf chunks
+fn exif_payload(camera: &CameraInfo, white_balanced: bool) -> Vec<u8> {
+    let entries: Vec<(u16, String)> = vec![
+        (0x010E, camera.description(white_balanced)), // ImageDescription
+        (0x0110, camera.camera_model.trim().to_string()), // Model
+        (0x0131, SOFTWARE.to_string()), // Software
+    ];
+
+    let mut tiff = b"II\x2A\x00\x08\x00\x00\x00".to_vec();
+    let ifd_size = 2 + entries.len() * 12 + 4;
+    let mut values = Vec::new();
+    tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, text) in &entries {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes()); // ASCII
+        tiff.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if value.len() <= 4 {
+            value.resize(4, 0);
+            tiff.extend_from_slice(&value);
+        } else {
+            let offset = 8 + ifd_size + values.len();
+            tiff.extend_from_slice(&(offset as u32).to_le_bytes());
+            values.extend_from_slice(&value);
+            if values.len() % 2 != 0 {
+                values.push(0);
+            }
+        }
+    }
+    tiff.extend_from_slice(&0u32.to_le_bytes()); // no next IFD
+    tiff.extend_from_slice(&values);
+    tiff
+}
+
+fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+fn embed_png(encoded: &[u8], camera: &CameraInfo, white_balanced: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Signature (8) + IHDR chunk (25): the new chunks go right after IHDR
+    let ihdr_end = 8 + 25;
+    if encoded.len() < ihdr_end || &encoded[12..16] != b"IHDR" {
+        return Err("Unexpected PNG layout".into());
+    }
+
+    let mut compressor = ZlibEncoder::new(Vec::new(), Compression::default());
+    compressor.write_all(&srgb_icc_profile())?;
+    let mut iccp = b"sRGB\0\0".to_vec();
+    iccp.extend_from_slice(&compressor.finish()?);
+
+    let mut output = encoded[..ihdr_end].to_vec();
+    output.extend_from_slice(&png_chunk(b"iCCP", &iccp));
+    output.extend_from_slice(&png_chunk(b"eXIf", &exif_payload(camera, white_balanced)));
+    for (keyword, text) in [
+        ("Model", camera.camera_model.trim().to_string()),
+        ("Description", camera.description(white_balanced)),
+        ("Software", SOFTWARE.to_string()),
+    ] {
+        let mut data = keyword.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(text.as_bytes());
+        output.extend_from_slice(&png_chunk(b"tEXt", &data));
+    }
+    output.extend_from_slice(&encoded[ihdr_end..]);
+    Ok(output)
+}
+
+fn jpeg_segment(marker: u8, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let length = u16::try_from(data.len() + 2).map_err(|_| "JPEG segment too large")?;
+    let mut segment = vec![0xFF, marker];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(data);
+    Ok(segment)
+}
+
+fn embed_jpeg(encoded: &[u8], camera: &CameraInfo, white_balanced: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    if encoded.len() < 4 || encoded[..2] != [0xFF, 0xD8] {
+        return Err("Unexpected JPEG layout".into());
+    }
+    // Keep the JFIF APP0 segment first when the encoder wrote one
+    let mut insert_at = 2;
+    if encoded[2..4] == [0xFF, 0xE0] && encoded.len() >= 6 {
+        insert_at += 2 + u16::from_be_bytes([encoded[4], encoded[5]]) as usize;
+    }
+
+    let mut exif = b"Exif\0\0".to_vec();
+    exif.extend_from_slice(&exif_payload(camera, white_balanced));
+    let mut icc = b"ICC_PROFILE\0\x01\x01".to_vec();
+    icc.extend_from_slice(&srgb_icc_profile());
+
+    let mut output = encoded[..insert_at].to_vec();
+    output.extend_from_slice(&jpeg_segment(0xE1, &exif)?);
+    output.extend_from_slice(&jpeg_segment(0xE2, &icc)?);
+    output.extend_from_slice(&encoded[insert_at..]);
+    Ok(output)
+}
+
+// Encode an image and, where the format allows it, embed the sRGB profile and camera fields
+pub fn encode_with_color_metadata(image: &DynamicImage, format: ImageFormat, camera: Option<&CameraInfo>, white_balanced: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format)?;
+    let encoded = encoded.into_inner();
+
+    let Some(camera) = camera else {
+        return Ok(encoded);
+    };
+    match format {
+        ImageFormat::Png => embed_png(&encoded, camera, white_balanced),
+        ImageFormat::Jpeg => embed_jpeg(&encoded, camera, white_balanced),
+        _ => {
+            log::warn!("Colour profile embedding is only supported for png and jpg output");
+            Ok(encoded)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn camera() -> CameraInfo {
+        CameraInfo { camera_model: "Nikon D7000 ".to_string(), color_temperature: "3000K".to_string(), color_temperature_value: 3000 }
+    }
+
+    fn sample() -> RgbImage {
+        RgbImage::from_fn(4, 4, |x, y| Rgb([(x * 60) as u8, (y * 60) as u8, 128]))
+    }
+
+    #[test]
+    fn temperatures_outside_the_plausible_range_are_ignored() {
+        assert_eq!(camera().kelvin(), Some(3000.0));
+        assert_eq!(CameraInfo::default().kelvin(), None);
+        assert_eq!(CameraInfo { color_temperature_value: 60000, ..camera() }.kelvin(), None);
+    }
+
+    #[test]
+    fn warm_light_is_cooled_down() {
+        let mut image = RgbImage::from_pixel(1, 1, Rgb([128, 128, 128]));
+        white_balance(&mut image, 3000.0);
+        let [red, green, blue] = image.get_pixel(0, 0).0;
+        assert!(red < 128);
+        assert_eq!(green, 128);
+        assert!(blue > 128);
+
+        // The reference temperature leaves the image as it is
+        let mut image = sample();
+        white_balance(&mut image, REFERENCE_KELVIN);
+        assert_eq!(image, sample());
+    }
+
+    #[test]
+    fn profile_header_is_consistent() {
+        let profile = srgb_icc_profile();
+        assert_eq!(u32::from_be_bytes(profile[..4].try_into().unwrap()) as usize, profile.len());
+        assert_eq!(&profile[36..40], b"acsp");
+        assert_eq!(profile.len() % 4, 0);
+    }
+
+    #[test]
+    fn png_carries_the_profile_and_camera_fields() {
+        let image = DynamicImage::ImageRgb8(sample());
+        let encoded = encode_with_color_metadata(&image, ImageFormat::Png, Some(&camera()), true).unwrap();
+        let reader = png::Decoder::new(Cursor::new(&encoded)).read_info().unwrap();
+        assert_eq!(reader.info().icc_profile.as_deref(), Some(srgb_icc_profile().as_slice()));
+        let text: Vec<(&str, &str)> = reader.info().uncompressed_latin1_text.iter().map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str())).collect();
+        assert!(text.contains(&("Model", "Nikon D7000")));
+        assert!(text.contains(&("Description", "Color temperature: 3000K (3000 K); white balance: corrected to 6500 K")));
+        assert_eq!(image::load_from_memory(&encoded).unwrap().to_rgb8(), sample());
+    }
+
+    #[test]
+    fn jpeg_carries_exif_and_the_profile() {
+        let image = DynamicImage::ImageRgb8(sample());
+        let encoded = encode_with_color_metadata(&image, ImageFormat::Jpeg, Some(&camera()), false).unwrap();
+        let contains = |needle: &[u8]| encoded.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"Exif\0\0II\x2A\0"));
+        assert!(contains(b"ICC_PROFILE\0\x01\x01"));
+        assert!(contains(b"white balance: as recorded"));
+        assert!(image::load_from_memory(&encoded).is_ok());
+
+        // Nothing is added without camera fields
+        let mut plain = Cursor::new(Vec::new());
+        image.write_to(&mut plain, ImageFormat::Jpeg).unwrap();
+        assert_eq!(encode_with_color_metadata(&image, ImageFormat::Jpeg, None, false).unwrap(), plain.into_inner());
+    }
+}
@@ -0,0 +1,312 @@
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+
+use crate::archive::ArchiveFormat;
+use crate::color::ColorOptions;
+use crate::deidentify::{Deidentifier, Policy};
+use crate::extract::{ExtractOptions, PreviewOptions, Selection, VolumeOptions};
+use crate::formats::ImageKind;
+use crate::naming::NamingTemplate;
+use crate::output::ExistingOutput;
+use crate::preview::PreviewFormat;
+use crate::volume::{Interpolation, SliceRange, TargetSpacing};
+
+const DEFAULT_OUTPUT_DIR: &str = "extraction";
+const DEFAULT_INTERPOLATION: &str = "trilinear";
+const DEFAULT_PREVIEW_DELAY_MS: u16 = 100;
+
+// An extraction profile, as read from a TOML or JSON file or from the command line.
+// Keys are the long option names of the extract command; unset keys take the command's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExtractConfig {
+    pub extension: Option<String>,
+    pub output: Option<String>,
+    pub overwrite: Option<bool>,
+    pub clean: Option<bool>,
+    pub only: Option<Vec<String>>,
+    pub skip: Option<Vec<String>>,
+    pub slices: Option<String>,
+    pub metadata_only: Option<bool>,
+    pub naming: Option<String>,
+    pub resample: Option<String>,
+    pub interpolation: Option<String>,
+    pub reslice: Option<bool>,
+    pub preview: Option<Vec<String>>,
+    pub preview_fundus: Option<bool>,
+    pub preview_delay: Option<u16>,
+    pub montage: Option<bool>,
+    pub white_balance: Option<bool>,
+    pub embed_profile: Option<bool>,
+    pub deidentify: Option<String>,
+    pub salt: Option<String>,
+    pub pseudonyms: Option<String>,
+    pub shift_dates: Option<bool>,
+    pub strict: Option<bool>,
+    pub jobs: Option<u16>,
+    pub summary: Option<String>,
+    pub archive: Option<String>,
+}
+
+// Everything an extract run needs besides its inputs
+pub struct Profile {
+    pub options: ExtractOptions,
+    pub output_dir: String,
+    pub existing: ExistingOutput,
+    pub jobs: Option<usize>,
+    pub summary: Option<String>,
+    pub archive: Option<String>,
+}
+
+fn overlay<T>(base: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *base = value;
+    }
+}
+
+fn parse<T: FromStr<Err = String>>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|e| format!("{}: {}", key, e))
+}
+
+fn parse_list<T: FromStr<Err = String>>(key: &str, values: &[String]) -> Result<Vec<T>, String> {
+    values.iter().map(|value| parse(key, value)).collect()
+}
+
+fn parse_extension(extension: &str) -> Result<ImageFormat, String> {
+    match extension {
+        "bmp" => Ok(ImageFormat::Bmp),
+        "jpg" => Ok(ImageFormat::Jpeg),
+        "png" => Ok(ImageFormat::Png),
+        "tiff" => Ok(ImageFormat::Tiff),
+        other => Err(format!("extension: '{}' is not one of bmp, jpg, png, tiff", other)),
+    }
+}
+
+impl ExtractConfig {
+    // TOML, or JSON when the file name ends in .json
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read config {}: {}", path, e))?;
+        let config = if path.to_lowercase().ends_with(".json") {
+            serde_json::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path, e))?
+        } else {
+            toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path, e))?
+        };
+        Ok(config)
+    }
+
+    // Values set in `other` win; the selection, existing-output and destination options are replaced
+    // as a group so that e.g. --skip on the command line is not combined with `only` from a file
+    pub fn merge(&mut self, other: ExtractConfig) {
+        if other.only.is_some() || other.skip.is_some() || other.metadata_only.is_some() {
+            self.only = None;
+            self.skip = None;
+            self.metadata_only = None;
+        }
+        if other.overwrite.is_some() || other.clean.is_some() {
+            self.overwrite = None;
+            self.clean = None;
+        }
+        if other.output.is_some() || other.archive.is_some() {
+            self.output = None;
+            self.archive = None;
+        }
+        overlay(&mut self.extension, other.extension);
+        overlay(&mut self.output, other.output);
+        overlay(&mut self.overwrite, other.overwrite);
+        overlay(&mut self.clean, other.clean);
+        overlay(&mut self.only, other.only);
+        overlay(&mut self.skip, other.skip);
+        overlay(&mut self.slices, other.slices);
+        overlay(&mut self.metadata_only, other.metadata_only);
+        overlay(&mut self.naming, other.naming);
+        overlay(&mut self.resample, other.resample);
+        overlay(&mut self.interpolation, other.interpolation);
+        overlay(&mut self.reslice, other.reslice);
+        overlay(&mut self.preview, other.preview);
+        overlay(&mut self.preview_fundus, other.preview_fundus);
+        overlay(&mut self.preview_delay, other.preview_delay);
+        overlay(&mut self.montage, other.montage);
+        overlay(&mut self.white_balance, other.white_balance);
+        overlay(&mut self.embed_profile, other.embed_profile);
+        overlay(&mut self.deidentify, other.deidentify);
+        overlay(&mut self.salt, other.salt);
+        overlay(&mut self.pseudonyms, other.pseudonyms);
+        overlay(&mut self.shift_dates, other.shift_dates);
+        overlay(&mut self.strict, other.strict);
+        overlay(&mut self.jobs, other.jobs);
+        overlay(&mut self.summary, other.summary);
+        overlay(&mut self.archive, other.archive);
+    }
+
+    // The same combinations the command line refuses
+    fn check_combinations(&self) -> Result<(), String> {
+        let set = |value: bool, name: &'static str| value.then_some(name);
+        let conflicts = [
+            (set(self.only.is_some(), "only"), set(self.skip.is_some(), "skip")),
+            (set(self.overwrite == Some(true), "overwrite"), set(self.clean == Some(true), "clean")),
+            (set(self.output.is_some(), "output"), set(self.archive.is_some(), "archive")),
+        ];
+        for (a, b) in conflicts {
+            if let (Some(a), Some(b)) = (a, b) {
+                return Err(format!("{} and {} cannot be used together", a, b));
+            }
+        }
+        if self.metadata_only == Some(true) {
+            let images = [
+                set(self.only.is_some(), "only"),
+                set(self.skip.is_some(), "skip"),
+                set(self.slices.is_some(), "slices"),
+                set(self.preview.is_some(), "preview"),
+                set(self.montage == Some(true), "montage"),
+                set(self.resample.is_some(), "resample"),
+                set(self.reslice == Some(true), "reslice"),
+            ];
+            if let Some(name) = images.into_iter().flatten().next() {
+                return Err(format!("metadata-only cannot be used with {}", name));
+            }
+        }
+        let requirements = [
+            (set(self.interpolation.is_some(), "interpolation"), self.resample.is_some(), "resample"),
+            (set(self.preview_fundus == Some(true), "preview-fundus"), self.preview.is_some(), "preview"),
+            (set(self.salt.is_some(), "salt"), self.deidentify.is_some(), "deidentify"),
+            (set(self.pseudonyms.is_some(), "pseudonyms"), self.deidentify.is_some(), "deidentify"),
+            (set(self.shift_dates == Some(true), "shift-dates"), self.deidentify.is_some(), "deidentify"),
+        ];
+        for (name, present, required) in requirements {
+            if let (Some(name), false) = (name, present) {
+                return Err(format!("{} requires {}", name, required));
+            }
+        }
+        if self.jobs == Some(0) {
+            return Err("jobs: must be at least 1".to_string());
+        }
+        if self.preview_delay == Some(0) {
+            return Err("preview-delay: must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    // Validate every value and turn the profile into extraction options
+    pub fn build(&self) -> Result<Profile, Box<dyn Error>> {
+        self.check_combinations()?;
+        let extension = self.extension.as_deref().ok_or("No output format, pass -e or set extension in the config")?;
+        let output_format = parse_extension(extension)?;
+
+        let mut selection = Selection::default();
+        if self.metadata_only == Some(true) {
+            selection.kinds.clear();
+        } else if let Some(only) = &self.only {
+            selection.kinds = parse_list::<ImageKind>("only", only)?;
+        } else if let Some(skip) = &self.skip {
+            let skip = parse_list::<ImageKind>("skip", skip)?;
+            selection.kinds.retain(|kind| !skip.contains(kind));
+        }
+        if let Some(slices) = &self.slices {
+            selection.slices = parse::<SliceRange>("slices", slices)?;
+        }
+
+        let volume = VolumeOptions {
+            resample: self.resample.as_deref().map(|value| parse::<TargetSpacing>("resample", value)).transpose()?,
+            interpolation: parse::<Interpolation>("interpolation", self.interpolation.as_deref().unwrap_or(DEFAULT_INTERPOLATION))?,
+            reslice: self.reslice == Some(true),
+        };
+        let preview = PreviewOptions {
+            formats: self.preview.as_deref().map(|formats| parse_list::<PreviewFormat>("preview", formats)).transpose()?.unwrap_or_default(),
+            with_fundus: self.preview_fundus == Some(true),
+            delay_ms: self.preview_delay.unwrap_or(DEFAULT_PREVIEW_DELAY_MS),
+        };
+        let deidentifier = match &self.deidentify {
+            Some(policy) => Some(Deidentifier::new(
+                parse::<Policy>("deidentify", policy)?,
+                self.salt.clone(),
+                self.pseudonyms.as_deref(),
+                self.shift_dates == Some(true),
+            )?),
+            None => None,
+        };
+        if let Some(archive) = &self.archive {
+            ArchiveFormat::of_path(archive).map_err(|e| format!("archive: {}", e))?;
+        }
+        let naming = self.naming.as_deref().map(|template| parse::<NamingTemplate>("naming", template)).transpose()?;
+
+        let existing = if self.clean == Some(true) {
+            ExistingOutput::Clean
+        } else if self.overwrite == Some(true) {
+            ExistingOutput::Overwrite
+        } else {
+            ExistingOutput::Refuse
+        };
+
+        Ok(Profile {
+            options: ExtractOptions {
+                output_format,
+                volume,
+                preview,
+                montage: self.montage == Some(true),
+                color: ColorOptions {
+                    white_balance: self.white_balance == Some(true),
+                    embed_profile: self.embed_profile == Some(true),
+                },
+                deidentifier,
+                selection,
+                naming,
+                strict: self.strict == Some(true),
+            },
+            output_dir: self.output.clone().unwrap_or_else(|| DEFAULT_OUTPUT_DIR.to_string()),
+            existing,
+            jobs: self.jobs.map(|jobs| jobs as usize),
+            summary: self.summary.clone(),
+            archive: self.archive.clone(),
+        })
+    }
+
+    // watch writes each file's outputs as it arrives, with no run-wide summary or archive
+    pub fn build_for_watch(&self) -> Result<Profile, Box<dyn Error>> {
+        for (set, key) in [(self.summary.is_some(), "summary"), (self.archive.is_some(), "archive")] {
+            if set {
+                return Err(format!("{} cannot be used with watch", key).into());
+            }
+        }
+        self.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_replaces_grouped_options() {
+        let mut config: ExtractConfig = toml::from_str("extension = \"png\"\nonly = [\"bscan\"]\nclean = true\noutput = \"out\"\nstrict = true").unwrap();
+        config.merge(ExtractConfig { skip: Some(vec!["fundus".to_string()]), overwrite: Some(true), archive: Some("out.zip".to_string()), ..Default::default() });
+        assert_eq!(config.only, None);
+        assert_eq!(config.skip, Some(vec!["fundus".to_string()]));
+        assert_eq!((config.clean, config.overwrite), (None, Some(true)));
+        assert_eq!((config.output, config.archive.as_deref()), (None, Some("out.zip")));
+        // Values the command line does not set are kept
+        assert_eq!((config.extension.as_deref(), config.strict), (Some("png"), Some(true)));
+    }
+
+    #[test]
+    fn conflicting_options_are_refused() {
+        let check = |text: &str| toml::from_str::<ExtractConfig>(text).unwrap().check_combinations();
+        assert_eq!(check("only = [\"bscan\"]\nskip = [\"fundus\"]"), Err("only and skip cannot be used together".to_string()));
+        assert_eq!(check("metadata-only = true\nslices = \"1..3\""), Err("metadata-only cannot be used with slices".to_string()));
+        assert_eq!(check("interpolation = \"nearest\""), Err("interpolation requires resample".to_string()));
+        assert_eq!(check("shift-dates = true"), Err("shift-dates requires deidentify".to_string()));
+        assert_eq!(check("jobs = 0"), Err("jobs: must be at least 1".to_string()));
+        // A flag set to false does not conflict
+        assert_eq!(check("overwrite = false\nclean = true"), Ok(()));
+    }
+
+    #[test]
+    fn watch_refuses_run_wide_outputs() {
+        let build = |text: &str| toml::from_str::<ExtractConfig>(text).unwrap().build_for_watch().err().map(|e| e.to_string());
+        assert_eq!(build("extension = \"png\"\nsummary = \"runs.csv\""), Some("summary cannot be used with watch".to_string()));
+        assert_eq!(build("extension = \"png\"\narchive = \"runs.zip\""), Some("archive cannot be used with watch".to_string()));
+        assert_eq!(build("extension = \"png\""), None);
+    }
+}
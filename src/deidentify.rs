@@ -0,0 +1,342 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::formats::Metadata;
+
+// What happens to identifying fields (ids, names, labels, serial numbers)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Drop,
+    Hash,
+    Pseudonym,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Policy::Drop),
+            "hash" => Ok(Policy::Hash),
+            "pseudonym" => Ok(Policy::Pseudonym),
+            other => Err(format!("Unknown de-identification policy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    PatientId,
+    Identifying,
+    Date,
+    // Undecoded bytes that may hold anything
+    Opaque,
+}
+
+// Metadata fields holding PHI, by section. PATIENT_INFO and CIRRUS_INFO come
+// from the E2E and Cirrus readers.
+const PHI_FIELDS: [(&str, &str, FieldKind); 19] = [
+    ("PATIENT_INFO_02", "patient_id", FieldKind::PatientId),
+    ("PATIENT_INFO_02", "given_name", FieldKind::Identifying),
+    ("PATIENT_INFO_02", "surname", FieldKind::Identifying),
+    ("PATIENT_INFO_02", "extra_data", FieldKind::Opaque),
+    ("PATIENT_INFO_03", "patient_id", FieldKind::PatientId),
+    ("PATIENT_INFO_03", "given_name", FieldKind::Identifying),
+    ("PATIENT_INFO_03", "surname", FieldKind::Identifying),
+    ("PATIENT_INFO_03", "birth_date", FieldKind::Date),
+    ("CAPTURE_INFO_02", "label", FieldKind::Identifying),
+    ("CAPTURE_INFO_02", "cap_date", FieldKind::Date),
+    ("HW_INFO_03", "serial_number", FieldKind::Identifying),
+    ("HW_INFO_03", "spect_sn", FieldKind::Identifying),
+    ("PATIENT_INFO", "patient_id", FieldKind::PatientId),
+    ("PATIENT_INFO", "given_name", FieldKind::Identifying),
+    ("PATIENT_INFO", "surname", FieldKind::Identifying),
+    ("PATIENT_INFO", "birth_date", FieldKind::Date),
+    ("CIRRUS_INFO", "patient_id", FieldKind::PatientId),
+    ("CIRRUS_INFO", "serial_number", FieldKind::Identifying),
+    ("CIRRUS_INFO", "scan_date", FieldKind::Date),
+];
+
+// @PATIENT_INFO_02 stores the birth date as three separate fields
+const BIRTH_DATE_PARTS: [&str; 3] = ["birth_year", "birth_month", "birth_day"];
+
+// Shifted dates stay within a year of the original
+const MAX_SHIFT_DAYS: u64 = 365;
+
+#[derive(Debug, Clone)]
+pub struct Pseudonym {
+    pub id: String,
+    pub shift_days: Option<i64>,
+}
+
+pub struct Deidentifier {
+    pub policy: Policy,
+    pub salt: Option<String>,
+    // Original patient id -> pseudonym
+    pub pseudonyms: HashMap<String, Pseudonym>,
+    // Shift dates by a per-patient offset instead of dropping them
+    pub shift_dates: bool,
+}
+
+// Lookup CSV with a header row: patient_id,pseudonym and an optional shift_days column
+pub fn load_pseudonyms(path: &str) -> Result<HashMap<String, Pseudonym>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim() == name);
+    let id_column = column("patient_id").ok_or_else(|| format!("{} has no patient_id column", path))?;
+    let pseudonym_column = column("pseudonym").ok_or_else(|| format!("{} has no pseudonym column", path))?;
+    let shift_column = column("shift_days");
+
+    let mut pseudonyms = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |i: usize| record.get(i).unwrap_or("").trim().to_string();
+        let shift_days = match shift_column.map(field).filter(|s| !s.is_empty()) {
+            Some(s) => Some(s.parse::<i64>().map_err(|e| format!("Invalid shift_days '{}' in {}: {}", s, path, e))?),
+            None => None,
+        };
+        pseudonyms.insert(field(id_column), Pseudonym { id: field(pseudonym_column), shift_days });
+    }
+    Ok(pseudonyms)
+}
+
+impl Deidentifier {
+    pub fn new(policy: Policy, salt: Option<String>, pseudonym_csv: Option<&str>, shift_dates: bool) -> Result<Self, Box<dyn Error>> {
+        let pseudonyms = pseudonym_csv.map(load_pseudonyms).transpose()?.unwrap_or_default();
+        if policy == Policy::Hash && salt.is_none() {
+            return Err("The hash policy needs a salt".into());
+        }
+        if policy == Policy::Pseudonym && pseudonym_csv.is_none() {
+            return Err("The pseudonym policy needs a lookup CSV".into());
+        }
+        if shift_dates && salt.is_none() && pseudonyms.values().all(|p| p.shift_days.is_none()) {
+            return Err("Shifting dates needs a salt or a lookup CSV with a shift_days column".into());
+        }
+        Ok(Deidentifier { policy, salt, pseudonyms, shift_dates })
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_deref().unwrap_or("").as_bytes());
+        hasher.update(b":");
+        hasher.update(value.as_bytes());
+        hasher.finalize().iter().take(8).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Stands in for the input file name, which often carries the patient name: a salted hash of
+    // the name, or DEIDENTIFIED without a salt
+    pub fn file_token(&self, filepath: &str) -> String {
+        match (&self.salt, std::path::Path::new(filepath).file_name()) {
+            (Some(_), Some(name)) => format!("file-{}", self.hash(&name.to_string_lossy())),
+            _ => "DEIDENTIFIED".to_string(),
+        }
+    }
+
+    // Same offset for every file of a patient: from the lookup CSV, else from the salted id
+    fn shift_days(&self, patient_id: &str) -> i64 {
+        if let Some(days) = self.pseudonyms.get(patient_id).and_then(|p| p.shift_days) {
+            return days;
+        }
+        let digest = Sha256::digest(format!("{}:shift:{}", self.salt.as_deref().unwrap_or(""), patient_id).as_bytes());
+        let value = u64::from_le_bytes(digest[..8].try_into().expect("digest has 32 bytes"));
+        (value % (2 * MAX_SHIFT_DAYS + 1)) as i64 - MAX_SHIFT_DAYS as i64
+    }
+
+    fn replace_identifier(&self, kind: FieldKind, value: &str, patient_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+        if value.trim().is_empty() {
+            return Ok(Some(value.to_string()));
+        }
+        Ok(match (self.policy, kind) {
+            (Policy::Drop, _) => None,
+            (Policy::Hash, _) => Some(self.hash(value)),
+            (Policy::Pseudonym, FieldKind::PatientId) => {
+                let pseudonym = self.pseudonyms.get(patient_id).ok_or_else(|| format!("Patient id '{}' is not in the pseudonym table", patient_id))?;
+                Some(pseudonym.id.clone())
+            }
+            // The lookup table only covers patient ids
+            (Policy::Pseudonym, _) => None,
+        })
+    }
+
+    // Rewrite the PHI fields of `metadata` in place and record how in a DEIDENTIFICATION section
+    pub fn apply(&self, metadata: &mut Metadata) -> Result<(), Box<dyn Error>> {
+        let patient_id = PHI_FIELDS
+            .iter()
+            .filter(|(_, _, kind)| *kind == FieldKind::PatientId)
+            .filter_map(|(section, field, _)| metadata.get(*section).and_then(|info| info.get(*field)))
+            .map(|value| value.trim().to_string())
+            .find(|value| !value.is_empty())
+            .unwrap_or_default();
+        let shift = self.shift_dates.then(|| self.shift_days(&patient_id));
+
+        for (section, field, kind) in PHI_FIELDS {
+            let Some(info) = metadata.get_mut(section) else { continue };
+            let Some(value) = info.get(field).cloned() else { continue };
+            let replacement = match kind {
+                FieldKind::PatientId | FieldKind::Identifying => self.replace_identifier(kind, &value, &patient_id)?,
+                FieldKind::Date => shift.and_then(|days| shift_date_string(&value, days)),
+                FieldKind::Opaque => None,
+            };
+            match replacement {
+                Some(replacement) => info.insert(field.to_string(), replacement),
+                None => info.remove(field),
+            };
+        }
+
+        if let Some(info) = metadata.get_mut("PATIENT_INFO_02") {
+            let parts: Vec<i64> = BIRTH_DATE_PARTS.iter().filter_map(|part| info.get(*part)?.parse().ok()).collect();
+            let shifted = match (shift, parts.as_slice()) {
+                (Some(days), [year, month, day]) => shift_date(*year, *month, *day, days),
+                _ => None,
+            };
+            for (i, part) in BIRTH_DATE_PARTS.iter().enumerate() {
+                match shifted {
+                    Some(date) => info.insert(part.to_string(), date[i].to_string()),
+                    None => info.remove(*part),
+                };
+            }
+        }
+
+        let mut summary = HashMap::new();
+        summary.insert("policy".to_string(), format!("{:?}", self.policy).to_lowercase());
+        summary.insert("dates".to_string(), if self.shift_dates { "shifted" } else { "dropped" }.to_string());
+        metadata.insert("DEIDENTIFICATION".to_string(), summary);
+        Ok(())
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Hinnant's days_from_civil)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> [i64; 3] {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    [if month <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, month, day]
+}
+
+// None for dates that are not valid, e.g. the all-zero "unknown" date
+fn shift_date(year: i64, month: i64, day: i64, days: i64) -> Option<[i64; 3]> {
+    if year <= 0 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(civil_from_days(days_from_civil(year, month, day) + days))
+}
+
+// Shift the date at the start of `value`, keeping its separators and whatever follows
+// (a time of day, quotes). Year-first and month-day-year orders are recognised; anything
+// else is dropped rather than risk leaking it.
+fn shift_date_string(value: &str, days: i64) -> Option<String> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, c) in value.char_indices().chain(std::iter::once((value.len(), ' '))) {
+        match (c.is_ascii_digit(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                runs.push(s..i);
+                start = None;
+                if runs.len() == 3 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    if runs.len() < 3 {
+        return None;
+    }
+    let numbers: Vec<i64> = runs.iter().map(|r| value[r.clone()].parse().unwrap_or(0)).collect();
+    if numbers.iter().all(|n| *n == 0) {
+        return Some(value.to_string());
+    }
+    let (order, [year, month, day]) = if runs[0].len() == 4 {
+        ([0, 1, 2], [numbers[0], numbers[1], numbers[2]])
+    } else if runs[2].len() == 4 {
+        ([1, 2, 0], [numbers[2], numbers[0], numbers[1]])
+    } else {
+        return None;
+    };
+    let shifted = shift_date(year, month, day, days)?;
+
+    let mut result = value[..runs[0].start].to_string();
+    for (i, run) in runs.iter().enumerate() {
+        result.push_str(&format!("{:0width$}", shifted[order[i]], width = run.len()));
+        let next = runs.get(i + 1).map_or(value.len(), |r| r.start);
+        result.push_str(&value[run.end..next]);
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deidentifier(policy: Policy, salt: Option<&str>) -> Deidentifier {
+        Deidentifier { policy, salt: salt.map(str::to_string), pseudonyms: HashMap::new(), shift_dates: false }
+    }
+
+    #[test]
+    fn file_token_hides_the_file_name() {
+        let hashed = deidentifier(Policy::Hash, Some("salt"));
+        let token = hashed.file_token("/scans/Doe_John_2016.fda");
+        assert!(token.starts_with("file-") && !token.contains("Doe"));
+        assert_eq!(token, hashed.file_token("/elsewhere/Doe_John_2016.fda"));
+        assert_ne!(token, deidentifier(Policy::Hash, Some("other")).file_token("/scans/Doe_John_2016.fda"));
+        assert_eq!(deidentifier(Policy::Drop, None).file_token("/scans/Doe_John_2016.fda"), "DEIDENTIFIED");
+    }
+
+    #[test]
+    fn shifting_keeps_the_date_format() {
+        assert_eq!(shift_date_string("2016-02-28 10:15:00", 2).as_deref(), Some("2016-03-01 10:15:00"));
+        assert_eq!(shift_date_string("12/31/2019", 1).as_deref(), Some("01/01/2020"));
+        assert_eq!(shift_date_string("0000-00-00", 30).as_deref(), Some("0000-00-00"));
+        // Day-month orders with a short year are ambiguous
+        assert_eq!(shift_date_string("31.12.19", 1), None);
+        assert_eq!(shift_date(2020, 13, 1, 1), None);
+    }
+
+    #[test]
+    fn dates_of_a_patient_shift_together() {
+        let shifted = Deidentifier { shift_dates: true, ..deidentifier(Policy::Hash, Some("salt")) };
+        let metadata = |patient_id: &str| -> Metadata {
+            let section = |fields: &[(&str, &str)]| fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            HashMap::from([
+                ("PATIENT_INFO_02".to_string(), section(&[("patient_id", patient_id), ("birth_year", "1960"), ("birth_month", "1"), ("birth_day", "1")])),
+                ("CAPTURE_INFO_02".to_string(), section(&[("cap_date", "1960-01-01")])),
+            ])
+        };
+
+        let mut first = metadata("P001");
+        shifted.apply(&mut first).unwrap();
+        let cap_date = first["CAPTURE_INFO_02"]["cap_date"].clone();
+        let birth = &first["PATIENT_INFO_02"];
+        assert_ne!(cap_date, "1960-01-01");
+        assert_eq!(cap_date, format!("{}-{:02}-{:02}", birth["birth_year"], birth["birth_month"].parse::<u32>().unwrap(), birth["birth_day"].parse::<u32>().unwrap()));
+        let days = days_from_civil(1960, 1, 1) - days_from_civil(cap_date[..4].parse().unwrap(), cap_date[5..7].parse().unwrap(), cap_date[8..].parse().unwrap());
+        assert!(days.unsigned_abs() <= MAX_SHIFT_DAYS);
+
+        let mut again = metadata("P001");
+        shifted.apply(&mut again).unwrap();
+        assert_eq!(again["CAPTURE_INFO_02"]["cap_date"], cap_date);
+
+        // Without shifting every date is dropped
+        let mut dropped = metadata("P001");
+        deidentifier(Policy::Hash, Some("salt")).apply(&mut dropped).unwrap();
+        assert!(!dropped["CAPTURE_INFO_02"].contains_key("cap_date") && !dropped["PATIENT_INFO_02"].contains_key("birth_year"));
+        assert_eq!(dropped["DEIDENTIFICATION"]["dates"], "dropped");
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::{self, Read};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crate::fda::headers::read_padded_string;
+
+// Size of each structure on disk
+pub const DIRECTORY_HEADER_SIZE: u64 = 52;
+pub const CHUNK_HEADER_SIZE: u64 = 60;
+pub const IMAGE_HEADER_SIZE: u64 = 20;
+
+// Chunk types
+pub const CHUNK_PATIENT: u32 = 9;
+pub const CHUNK_LATERALITY: u32 = 11;
+pub const CHUNK_BSCAN_META: u32 = 10004;
+pub const CHUNK_IMAGE: u32 = 0x4000_0000;
+
+#[derive(Debug)]
+pub struct E2eHeader {
+    pub magic: String,
+    pub version: u32,
+}
+
+impl E2eHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let magic = read_padded_string(reader, 12)?;
+        let version = reader.read_u32::<LittleEndian>()?;
+        let mut unknown = [0u8; 20];
+        reader.read_exact(&mut unknown)?;
+        Ok(E2eHeader { magic, version })
+    }
+}
+
+// "MDbMDir" block: the main directory right after the file header, and every
+// directory page in the linked list it points to
+#[derive(Debug)]
+pub struct DirectoryHeader {
+    pub magic: String,
+    pub num_entries: u32,
+    pub current: u32,
+    pub prev: u32,
+}
+
+impl DirectoryHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let magic = read_padded_string(reader, 12)?;
+        let _version = reader.read_u32::<LittleEndian>()?;
+        let mut unknown = [0u8; 20];
+        reader.read_exact(&mut unknown)?;
+        let num_entries = reader.read_u32::<LittleEndian>()?;
+        let current = reader.read_u32::<LittleEndian>()?;
+        let prev = reader.read_u32::<LittleEndian>()?;
+        let _unknown3 = reader.read_u32::<LittleEndian>()?;
+        Ok(DirectoryHeader {
+            magic,
+            num_entries,
+            current,
+            prev,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectoryEntry {
+    pub pos: u32,
+    pub start: u32,
+}
+
+impl DirectoryEntry {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let pos = reader.read_u32::<LittleEndian>()?;
+        let start = reader.read_u32::<LittleEndian>()?;
+        // size, unknown, patient/study/series/slice ids, two u16 and the chunk type
+        // are repeated in the chunk header itself
+        let mut rest = [0u8; 36];
+        reader.read_exact(&mut rest)?;
+        Ok(DirectoryEntry { pos, start })
+    }
+}
+
+// "MDbData" block in front of every chunk payload
+#[derive(Debug, Clone)]
+pub struct ChunkHeader {
+    pub magic: String,
+    pub size: u32,
+    pub patient_id: u32,
+    pub study_id: u32,
+    pub series_id: u32,
+    pub slice_id: i32,
+    pub ind: u16,
+    pub chunk_type: u32,
+}
+
+impl ChunkHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let magic = read_padded_string(reader, 12)?;
+        let _unknown = reader.read_u32::<LittleEndian>()?;
+        let _unknown2 = reader.read_u32::<LittleEndian>()?;
+        let _pos = reader.read_u32::<LittleEndian>()?;
+        let size = reader.read_u32::<LittleEndian>()?;
+        let _unknown3 = reader.read_u32::<LittleEndian>()?;
+        let patient_id = reader.read_u32::<LittleEndian>()?;
+        let study_id = reader.read_u32::<LittleEndian>()?;
+        let series_id = reader.read_u32::<LittleEndian>()?;
+        let slice_id = reader.read_i32::<LittleEndian>()?;
+        let ind = reader.read_u16::<LittleEndian>()?;
+        let _unknown4 = reader.read_u16::<LittleEndian>()?;
+        let chunk_type = reader.read_u32::<LittleEndian>()?;
+        let _unknown5 = reader.read_u32::<LittleEndian>()?;
+        Ok(ChunkHeader {
+            magic,
+            size,
+            patient_id,
+            study_id,
+            series_id,
+            slice_id,
+            ind,
+            chunk_type,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct E2eImageHeader {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl E2eImageHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let _size = reader.read_u32::<LittleEndian>()?;
+        let _image_type = reader.read_u32::<LittleEndian>()?;
+        let _unknown = reader.read_u32::<LittleEndian>()?;
+        let width = reader.read_u32::<LittleEndian>()?;
+        let height = reader.read_u32::<LittleEndian>()?;
+        Ok(E2eImageHeader { width, height })
+    }
+}
+
+#[derive(Debug)]
+pub struct E2ePatientHeader {
+    pub first_name: String,
+    pub surname: String,
+    pub birth_date: String,
+    pub sex: String,
+    pub patient_id: String,
+}
+
+impl E2ePatientHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let first_name = read_padded_string(reader, 31)?;
+        let surname = read_padded_string(reader, 66)?;
+        let birth_date = reader.read_u32::<LittleEndian>()?;
+        let sex = read_padded_string(reader, 1)?;
+        let patient_id = read_padded_string(reader, 25)?;
+        Ok(E2ePatientHeader {
+            first_name,
+            surname,
+            birth_date: birth_date_to_string(birth_date),
+            sex,
+            patient_id,
+        })
+    }
+}
+
+// The birth date is a Julian day number scaled by 64 and offset by 14558805
+fn birth_date_to_string(raw: u32) -> String {
+    let julian_day = (raw / 64) as i64 - 14558805;
+    if julian_day <= 0 {
+        return raw.to_string();
+    }
+    // Fliegel & Van Flandern conversion to the Gregorian calendar
+    let l = julian_day + 68569;
+    let n = 4 * l / 146097;
+    let l = l - (146097 * n + 3) / 4;
+    let i = 4000 * (l + 1) / 1461001;
+    let l = l - 1461 * i / 4 + 31;
+    let j = 80 * l / 2447;
+    let day = l - 2447 * j / 80;
+    let l = j / 11;
+    let month = j + 2 - 12 * l;
+    let year = 100 * (n - 49) + i + l;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[derive(Debug)]
+pub struct E2eLateralityHeader {
+    pub eye: String,
+}
+
+impl E2eLateralityHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut unknown = [0u8; 14];
+        reader.read_exact(&mut unknown)?;
+        let eye = match reader.read_u8()? {
+            b'L' => "LEFT".to_string(),
+            b'R' => "RIGHT".to_string(),
+            _ => "UNKNOWN".to_string(),
+        };
+        Ok(E2eLateralityHeader { eye })
+    }
+}
+
+#[derive(Debug)]
+pub struct E2eBscanMetaHeader {
+    pub height: u32,
+    pub width: u32,
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+    pub scale_z_mm: f32,
+    pub number_slices: u32,
+    pub scan_type: u32,
+    pub averages: u32,
+    pub quality: f32,
+}
+
+impl E2eBscanMetaHeader {
+    pub fn from_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let _unknown = reader.read_u32::<LittleEndian>()?;
+        let height = reader.read_u32::<LittleEndian>()?;
+        let width = reader.read_u32::<LittleEndian>()?;
+        let start = [reader.read_f32::<LittleEndian>()?, reader.read_f32::<LittleEndian>()?];
+        let end = [reader.read_f32::<LittleEndian>()?, reader.read_f32::<LittleEndian>()?];
+        let _zero = reader.read_u32::<LittleEndian>()?;
+        let _unknown2 = reader.read_f32::<LittleEndian>()?;
+        let scale_z_mm = reader.read_f32::<LittleEndian>()?;
+        let mut unknown = [0u8; 24];
+        reader.read_exact(&mut unknown)?;
+        let number_slices = reader.read_u32::<LittleEndian>()?;
+        let _current_slice = reader.read_u32::<LittleEndian>()?;
+        let scan_type = reader.read_u32::<LittleEndian>()?;
+        let mut unknown = [0u8; 12];
+        reader.read_exact(&mut unknown)?;
+        let _acquisition_time = reader.read_u64::<LittleEndian>()?;
+        let averages = reader.read_u32::<LittleEndian>()?;
+        let quality = reader.read_f32::<LittleEndian>()?;
+        Ok(E2eBscanMetaHeader {
+            height,
+            width,
+            start,
+            end,
+            scale_z_mm,
+            number_slices,
+            scan_type,
+            averages,
+            quality,
+        })
+    }
+}
@@ -0,0 +1,388 @@
+use image::{DynamicImage, GrayImage};
+use log::warn;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::e2e::headers::*;
+use crate::formats::{ImageEntry, ImageKind, Metadata, OctReader};
+use crate::volume::{Volume, VoxelSpacing};
+
+pub const E2E_MAGIC: &str = "CMDb";
+const DIRECTORY_MAGIC: &str = "MDbMDir";
+const CHUNK_MAGIC: &str = "MDbData";
+
+// A chunk found through the directory, with the offset of its payload
+#[derive(Debug, Clone)]
+pub struct E2eChunk {
+    pub header: ChunkHeader,
+    pub payload_offset: u64,
+}
+
+// All images of one patient/study/series triple, as chunks that are decoded on request
+#[derive(Debug, Default)]
+pub struct E2eSeries {
+    pub patient_id: u32,
+    pub study_id: u32,
+    pub series_id: u32,
+    // B-scans keyed by slice number
+    pub bscans: BTreeMap<i32, E2eChunk>,
+    // Infrared SLO fundus images
+    pub fundus: Vec<E2eChunk>,
+    pub scale_z_mm: Option<f32>,
+}
+
+pub struct E2eContents {
+    pub metadata: Metadata,
+    pub series: Vec<E2eSeries>,
+}
+
+// Walk the linked list of directory pages and collect every data chunk they point to
+pub fn list_chunks(filepath: &str) -> Result<(E2eHeader, Vec<E2eChunk>), Box<dyn Error>> {
+    let mut file = BufReader::new(File::open(filepath)?);
+    let header = E2eHeader::from_reader(&mut file)?;
+    if header.magic != E2E_MAGIC {
+        return Err(format!("Not a Heidelberg E2E file (magic '{}')", header.magic).into());
+    }
+    let main_directory = DirectoryHeader::from_reader(&mut file)?;
+    if main_directory.magic != DIRECTORY_MAGIC {
+        return Err(format!("Unexpected E2E directory magic '{}'", main_directory.magic).into());
+    }
+
+    let mut pages = Vec::new();
+    let mut visited = HashSet::new();
+    let mut current = main_directory.current;
+    while current != 0 && visited.insert(current) {
+        file.seek(SeekFrom::Start(current as u64))?;
+        let page = DirectoryHeader::from_reader(&mut file)?;
+        if page.magic != DIRECTORY_MAGIC {
+            warn!("Directory page at {} has magic '{}', stopping there", current, page.magic);
+            break;
+        }
+        pages.push((current as u64, page.num_entries));
+        current = page.prev;
+    }
+
+    let mut starts = Vec::new();
+    for (position, num_entries) in pages {
+        file.seek(SeekFrom::Start(position + DIRECTORY_HEADER_SIZE))?;
+        for _ in 0..num_entries {
+            let entry = DirectoryEntry::from_reader(&mut file)?;
+            // Entries that point back at the directory itself carry no data
+            if entry.start > entry.pos {
+                starts.push(entry.start as u64);
+            }
+        }
+    }
+    starts.sort_unstable();
+    starts.dedup();
+
+    let mut chunks = Vec::with_capacity(starts.len());
+    for start in starts {
+        file.seek(SeekFrom::Start(start))?;
+        let chunk = ChunkHeader::from_reader(&mut file)?;
+        if chunk.magic != CHUNK_MAGIC {
+            warn!("Chunk at {} has magic '{}', skipping", start, chunk.magic);
+            continue;
+        }
+        chunks.push(E2eChunk { header: chunk, payload_offset: start + CHUNK_HEADER_SIZE });
+    }
+    Ok((header, chunks))
+}
+
+// B-scans are stored as 16-bit floats with a 10-bit mantissa and a 6-bit exponent (bias 63)
+fn ufloat16_to_u8(raw: u16) -> u8 {
+    let mantissa = (raw & 0x3FF) as f64 / 1024.0;
+    let exponent = (raw >> 10) as i32;
+    let value = if exponent == 0 {
+        mantissa * 2f64.powi(1 - 63)
+    } else {
+        (1.0 + mantissa) * 2f64.powi(exponent - 63)
+    };
+    // Same display gamma as the Heidelberg viewer
+    (256.0 * value.powf(1.0 / 2.4)).clamp(0.0, 255.0) as u8
+}
+
+// `file_len` bounds the chunk size, which a corrupt file could set to anything
+pub fn read_image<R: Read + Seek>(reader: &mut R, chunk: &E2eChunk, file_len: u64, lut: &[u8]) -> Result<GrayImage, Box<dyn Error>> {
+    reader.seek(SeekFrom::Start(chunk.payload_offset))?;
+    let header = E2eImageHeader::from_reader(reader)?;
+    let bytes_per_pixel = if chunk.header.ind == 0 { 1 } else { 2 };
+    let remaining = file_len.saturating_sub(chunk.payload_offset + IMAGE_HEADER_SIZE);
+    let available = (chunk.header.size as u64).saturating_sub(IMAGE_HEADER_SIZE).min(remaining) as usize;
+    let size = (header.width as usize).checked_mul(header.height as usize).and_then(|pixels| pixels.checked_mul(bytes_per_pixel));
+    let Some(size) = size.filter(|size| *size > 0 && *size <= available) else {
+        return Err(format!(
+            "Image chunk of slice {} holds {} bytes, too few for {}x{}",
+            chunk.header.slice_id, available, header.width, header.height
+        ).into());
+    };
+
+    let mut raw = vec![0; size];
+    reader.read_exact(&mut raw)?;
+    let data = if bytes_per_pixel == 1 {
+        raw
+    } else {
+        raw.chunks_exact(2).map(|px| lut[u16::from_le_bytes([px[0], px[1]]) as usize]).collect()
+    };
+    GrayImage::from_raw(header.width, header.height, data).ok_or_else(|| "Failed to create E2E image".into())
+}
+
+// Metadata of the whole file and where the images of each series are, without decoding them
+pub fn read_e2e(filepath: &str) -> Result<E2eContents, Box<dyn Error>> {
+    let (header, chunks) = list_chunks(filepath)?;
+    let mut file = BufReader::new(File::open(filepath)?);
+
+    let mut metadata: Metadata = HashMap::new();
+    let mut file_info = HashMap::new();
+    file_info.insert("version".to_string(), header.version.to_string());
+    file_info.insert("chunks".to_string(), chunks.len().to_string());
+    metadata.insert("FILE_INFO".to_string(), file_info);
+
+    let mut series: BTreeMap<(u32, u32, u32), E2eSeries> = BTreeMap::new();
+    let mut scales = HashMap::new();
+    for chunk in &chunks {
+        let h = &chunk.header;
+        file.seek(SeekFrom::Start(chunk.payload_offset))?;
+        match h.chunk_type {
+            CHUNK_PATIENT => {
+                let patient = E2ePatientHeader::from_reader(&mut file)?;
+                metadata.entry("PATIENT_INFO".to_string()).or_insert_with(|| {
+                    let mut info = HashMap::new();
+                    info.insert("patient_id".to_string(), patient.patient_id);
+                    info.insert("given_name".to_string(), patient.first_name);
+                    info.insert("surname".to_string(), patient.surname);
+                    info.insert("sex".to_string(), patient.sex);
+                    info.insert("birth_date".to_string(), patient.birth_date);
+                    info
+                });
+            }
+            CHUNK_LATERALITY => {
+                let laterality = E2eLateralityHeader::from_reader(&mut file)?;
+                metadata
+                    .entry("LATERALITY".to_string())
+                    .or_default()
+                    .insert("eye".to_string(), laterality.eye);
+            }
+            CHUNK_BSCAN_META => {
+                let meta = E2eBscanMetaHeader::from_reader(&mut file)?;
+                scales.entry(h.series_id).or_insert(meta.scale_z_mm);
+                metadata.entry(format!("SERIES_{}", h.series_id)).or_insert_with(|| {
+                    let mut info = HashMap::new();
+                    info.insert("width".to_string(), meta.width.to_string());
+                    info.insert("height".to_string(), meta.height.to_string());
+                    info.insert("number_slices".to_string(), meta.number_slices.to_string());
+                    info.insert("scale_z_mm".to_string(), meta.scale_z_mm.to_string());
+                    info.insert("start".to_string(), format!("{:?}", meta.start));
+                    info.insert("end".to_string(), format!("{:?}", meta.end));
+                    info.insert("scan_type".to_string(), meta.scan_type.to_string());
+                    info.insert("averages".to_string(), meta.averages.to_string());
+                    info.insert("quality".to_string(), meta.quality.to_string());
+                    info
+                });
+            }
+            CHUNK_IMAGE => {
+                let entry = series.entry((h.patient_id, h.study_id, h.series_id)).or_insert_with(|| E2eSeries {
+                    patient_id: h.patient_id,
+                    study_id: h.study_id,
+                    series_id: h.series_id,
+                    ..Default::default()
+                });
+                if h.ind == 0 {
+                    entry.fundus.push(chunk.clone());
+                } else {
+                    // Slice ids count in steps of two
+                    entry.bscans.insert(h.slice_id / 2, chunk.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for s in series.values_mut() {
+        s.scale_z_mm = scales.get(&s.series_id).copied();
+        let info = metadata.entry(format!("SERIES_{}", s.series_id)).or_default();
+        info.insert("patient_id".to_string(), s.patient_id.to_string());
+        info.insert("study_id".to_string(), s.study_id.to_string());
+        info.insert("bscans".to_string(), s.bscans.len().to_string());
+        info.insert("fundus_images".to_string(), s.fundus.len().to_string());
+    }
+
+    Ok(E2eContents {
+        metadata,
+        series: series.into_values().collect(),
+    })
+}
+
+pub struct E2eReader {
+    filepath: String,
+    file_len: u64,
+    metadata: Metadata,
+    images: Vec<(ImageEntry, E2eChunk)>,
+    // One per series with B-scans, labelled when there are several
+    volumes: Vec<(String, E2eSeries)>,
+    lut: Vec<u8>,
+}
+
+impl E2eReader {
+    fn decode(&self, chunks: &[&E2eChunk]) -> Result<Vec<GrayImage>, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(&self.filepath)?);
+        chunks.iter().map(|chunk| read_image(&mut file, chunk, self.file_len, &self.lut)).collect()
+    }
+
+    fn read_series(&self, series: &E2eSeries) -> Result<Volume, Box<dyn Error>> {
+        let slices = self.decode(&series.bscans.values().collect::<Vec<_>>())?;
+        // Only the axial scale is decoded from E2E files, the lateral spacing stays unknown and
+        // resampling refuses to run
+        let spacing = VoxelSpacing { x_mm: 0.0, y_mm: 0.0, z_mm: series.scale_z_mm.unwrap_or(0.0) as f64 };
+        Volume::from_slices(&slices, spacing).map_err(|e| format!("Series {} is not a regular volume: {}", series.series_id, e).into())
+    }
+}
+
+impl OctReader for E2eReader {
+    fn open(filepath: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = read_e2e(filepath)?;
+        let file_len = std::fs::metadata(filepath)?.len();
+
+        // Keep the FDA names for the common single-volume case
+        let count = contents.series.iter().filter(|s| !s.bscans.is_empty()).count();
+        let mut images = Vec::new();
+        let mut volumes = Vec::new();
+        let mut fundus_count = 0;
+        for s in contents.series {
+            let prefix = if count > 1 { format!("series{}_bscan", s.series_id) } else { "bscan".to_string() };
+            for (index, chunk) in s.bscans.values().enumerate() {
+                let entry = ImageEntry { kind: ImageKind::Bscan, name: format!("{}_{}", prefix, index), index: images.len() };
+                images.push((entry, chunk.clone()));
+            }
+            for chunk in &s.fundus {
+                let entry = ImageEntry { kind: ImageKind::GrayscaleFundus, name: format!("grayscale_fundus_{}", fundus_count), index: images.len() };
+                images.push((entry, chunk.clone()));
+                fundus_count += 1;
+            }
+            if !s.bscans.is_empty() {
+                let label = if count > 1 { format!("series{}", s.series_id) } else { String::new() };
+                volumes.push((label, s));
+            }
+        }
+        let lut = (0..=u16::MAX).map(ufloat16_to_u8).collect();
+        Ok(E2eReader { filepath: filepath.to_string(), file_len, metadata: contents.metadata, images, volumes, lut })
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Heidelberg E2E"
+    }
+
+    fn metadata(&self) -> Result<Metadata, Box<dyn Error>> {
+        Ok(self.metadata.clone())
+    }
+
+    fn list_images(&self) -> Result<Vec<ImageEntry>, Box<dyn Error>> {
+        Ok(self.images.iter().map(|(entry, _)| entry.clone()).collect())
+    }
+
+    fn read_image(&self, entry: &ImageEntry) -> Result<DynamicImage, Box<dyn Error>> {
+        let (_, chunk) = self.images.get(entry.index).ok_or_else(|| format!("No image {} in this E2E file", entry.name))?;
+        let image = self.decode(&[chunk])?.remove(0);
+        Ok(DynamicImage::ImageLuma8(image))
+    }
+
+    fn read_volume(&self) -> Result<Volume, Box<dyn Error>> {
+        let (_, series) = self.volumes.first().ok_or("E2E file holds no B-scan volume")?;
+        self.read_series(series)
+    }
+
+    fn read_volumes(&self) -> Result<Vec<(String, Volume)>, Box<dyn Error>> {
+        if self.volumes.is_empty() {
+            return Err("E2E file holds no B-scan volume".into());
+        }
+        self.volumes.iter().map(|(label, series)| Ok((label.clone(), self.read_series(series)?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn padded(value: &str, len: usize) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn directory(num_entries: u32, current: u32) -> Vec<u8> {
+        [padded(DIRECTORY_MAGIC, 12), vec![0; 24], u32s(&[num_entries, current, 0, 0])].concat()
+    }
+
+    fn chunk_header(size: u32, slice_id: i32, ind: u16) -> Vec<u8> {
+        let ids = [u32s(&[0, 0, 0, size, 0, 1, 2, 3]), slice_id.to_le_bytes().to_vec(), ind.to_le_bytes().to_vec(), vec![0; 2]];
+        [padded(CHUNK_MAGIC, 12), ids.concat(), u32s(&[CHUNK_IMAGE, 0])].concat()
+    }
+
+    fn image(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+        [u32s(&[0, 0, 0, width, height]), pixels.to_vec()].concat()
+    }
+
+    fn chunk(size: u32, ind: u16) -> E2eChunk {
+        let header = ChunkHeader::from_reader(&mut chunk_header(size, 0, ind).as_slice()).unwrap();
+        E2eChunk { header, payload_offset: 0 }
+    }
+
+    #[test]
+    fn intensities_follow_the_viewer_gamma() {
+        assert_eq!(ufloat16_to_u8(0), 0);
+        // 2^0 = 1.0 is the top of the display range
+        assert_eq!(ufloat16_to_u8(63 << 10), 255);
+        assert!(ufloat16_to_u8(62 << 10) < 255);
+    }
+
+    #[test]
+    fn images_are_bounded_by_the_chunk_and_the_file() {
+        let bytes = image(2, 2, &[1, 2, 3, 4]);
+        let lut = [];
+        let read = read_image(&mut Cursor::new(&bytes), &chunk(24, 0), bytes.len() as u64, &lut).unwrap();
+        assert_eq!(read.into_raw(), vec![1, 2, 3, 4]);
+        // A chunk too small for its dimensions
+        assert!(read_image(&mut Cursor::new(&bytes), &chunk(22, 0), bytes.len() as u64, &lut).is_err());
+        // A corrupt size past the end of the file is not trusted
+        assert!(read_image(&mut Cursor::new(&bytes[..22]), &chunk(u32::MAX, 0), 22, &lut).is_err());
+        // Or dimensions that overflow
+        let huge = image(u32::MAX, u32::MAX, &[]);
+        assert!(read_image(&mut Cursor::new(&huge), &chunk(u32::MAX, 1), huge.len() as u64, &lut).is_err());
+    }
+
+    #[test]
+    fn bscans_are_found_through_the_directory() {
+        // File header, main directory, one directory page with one entry, one B-scan chunk
+        let page = 36 + 52;
+        let start = page + 52 + 44;
+        let pixels = u32s(&[(63 << 26) | (63 << 10), 0]);
+        let bytes = [
+            padded(E2E_MAGIC, 12),
+            vec![0; 24],
+            directory(1, page),
+            directory(1, page),
+            u32s(&[page + 52, start]),
+            vec![0; 36],
+            chunk_header(20 + pixels.len() as u32, 0, 1),
+            image(2, 2, &pixels),
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("octExtractor-e2e-test-{}.e2e", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+
+        let reader = E2eReader::open(path.to_str().unwrap()).unwrap();
+        let images = reader.list_images().unwrap();
+        assert_eq!(images.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), vec!["bscan_0"]);
+        let bscan = reader.read_image(&images[0]).unwrap().into_luma8();
+        assert_eq!(bscan.into_raw(), vec![255, 255, 0, 0]);
+        assert_eq!(reader.metadata().unwrap()["SERIES_3"]["bscans"], "1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use log::LevelFilter;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::outcome::Outcome;
+use crate::output::final_path;
+
+// Log target of progress events, so the JSON formatter can tell them from plain log lines
+pub const EVENT_TARGET: &str = "octExtractor::event";
+
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+// Files written so far, each with whether it is a B-scan
+type HeldFiles = Vec<(PathBuf, bool)>;
+
+// Folders whose files are moved once written, e.g. by --naming, and the files written there
+static HELD: Mutex<Vec<(PathBuf, HeldFiles)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Human,
+    // One JSON object per line on stderr
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format '{}'", other)),
+        }
+    }
+}
+
+// Progress of an extraction, for whatever drives the tool
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    FileStarted { file: &'a str },
+    ChunkParsed { file: &'a str, chunk: &'a str, fields: usize },
+    SliceWritten { path: &'a str },
+    ImageWritten { path: &'a str },
+    FileFinished { file: &'a str, outcome: Outcome, bscans: usize, errors: usize, duration_ms: u128 },
+    // Files per outcome and the exit code of the whole run
+    RunFinished { files: usize, outcomes: BTreeMap<Outcome, usize>, exit_code: i32 },
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::FileStarted { file } => write!(f, "Extracting {}", file),
+            Event::ChunkParsed { chunk, fields, .. } => write!(f, "Parsed {} ({} fields)", chunk, fields),
+            Event::SliceWritten { path } | Event::ImageWritten { path } => write!(f, "Wrote {}", path),
+            Event::FileFinished { file, outcome, duration_ms, .. } => write!(f, "{}: {} in {} ms", file, outcome.description(), duration_ms),
+            Event::RunFinished { files, outcomes, .. } => {
+                let counts: Vec<String> = outcomes.iter().map(|(outcome, count)| format!("{} {}", count, outcome.description())).collect();
+                write!(f, "{} file{}: {}", files, if *files == 1 { "" } else { "s" }, counts.join(", "))
+            }
+        }
+    }
+}
+
+pub fn emit(event: Event) {
+    if JSON_EVENTS.load(Ordering::Relaxed) {
+        log::info!(target: EVENT_TARGET, "{}", serde_json::to_string(&event).unwrap_or_default());
+    } else {
+        log::info!(target: EVENT_TARGET, "{}", event);
+    }
+}
+
+// Paths are reported where they end up, not in the staging directory
+pub fn slice_written(path: &str) {
+    written(path, true);
+}
+
+pub fn image_written(path: &str) {
+    written(path, false);
+}
+
+fn written(path: &str, slice: bool) {
+    if !log::log_enabled!(target: EVENT_TARGET, log::Level::Info) {
+        return;
+    }
+    let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, files)) = held.iter_mut().find(|(dir, _)| Path::new(path).starts_with(dir)) {
+        files.push((PathBuf::from(path), slice));
+        return;
+    }
+    drop(held);
+    emit_written(path, slice);
+}
+
+fn emit_written(path: &str, slice: bool) {
+    let path = final_path(path);
+    if slice {
+        emit(Event::SliceWritten { path: &path });
+    } else {
+        emit(Event::ImageWritten { path: &path });
+    }
+}
+
+// Keeps back the events of files written under a folder until they are released
+pub struct HeldEvents {
+    dir: PathBuf,
+}
+
+pub fn hold_events(dir: &str) -> HeldEvents {
+    HELD.lock().unwrap_or_else(|e| e.into_inner()).push((PathBuf::from(dir), Vec::new()));
+    HeldEvents { dir: PathBuf::from(dir) }
+}
+
+impl HeldEvents {
+    // Report the files at the paths they were moved to; files `moved` does not know kept theirs
+    pub fn release(self, moved: impl Fn(&Path) -> Option<PathBuf>) {
+        let files = {
+            let mut held = HELD.lock().unwrap_or_else(|e| e.into_inner());
+            held.iter_mut().find(|(dir, _)| *dir == self.dir).map(|(_, files)| std::mem::take(files)).unwrap_or_default()
+        };
+        for (path, slice) in files {
+            let path = moved(&path).unwrap_or(path);
+            emit_written(&path.to_string_lossy(), slice);
+        }
+    }
+}
+
+// Dropped without a release, e.g. when the extraction failed, the events are discarded
+impl Drop for HeldEvents {
+    fn drop(&mut self) {
+        HELD.lock().unwrap_or_else(|e| e.into_inner()).retain(|(dir, _)| *dir != self.dir);
+    }
+}
+
+fn json_line(record: &log::Record) -> serde_json::Value {
+    let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let level = record.level().to_string().to_lowercase();
+    let message = record.args().to_string();
+    if record.target() == EVENT_TARGET {
+        if let Ok(serde_json::Value::Object(mut event)) = serde_json::from_str(&message) {
+            event.insert("level".to_string(), level.into());
+            event.insert("time_ms".to_string(), time_ms.into());
+            return serde_json::Value::Object(event);
+        }
+    }
+    let event = match record.level() {
+        log::Level::Error => "error",
+        log::Level::Warn => "warning",
+        _ => "log",
+    };
+    serde_json::json!({ "event": event, "level": level, "time_ms": time_ms, "target": record.target(), "message": message })
+}
+
+// Human logs show warnings and errors unless RUST_LOG asks for more; JSON logs include the
+// progress events. Quiet keeps errors only and ignores RUST_LOG.
+pub fn init_logger(format: LogFormat, quiet: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(if quiet { LevelFilter::Error } else { LevelFilter::Warn });
+    if format == LogFormat::Json && !quiet {
+        // Our own progress only; the image codecs log every tile at info level
+        builder.filter_module("oct_extractor", LevelFilter::Info);
+        builder.filter_module(EVENT_TARGET, LevelFilter::Info);
+    }
+    if !quiet {
+        builder.parse_env("RUST_LOG");
+    }
+    if format == LogFormat::Json {
+        JSON_EVENTS.store(true, Ordering::Relaxed);
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    // Progress messages logged by any test, as the human formatter would show them
+    static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == EVENT_TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED.lock().unwrap_or_else(|e| e.into_inner()).push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured(dir: &str) -> Vec<String> {
+        CAPTURED.lock().unwrap_or_else(|e| e.into_inner()).iter().filter(|line| line.contains(dir)).cloned().collect()
+    }
+
+    fn capture() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(LevelFilter::Info);
+        });
+    }
+
+    #[test]
+    fn log_formats() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("human".parse::<LogFormat>(), Ok(LogFormat::Human));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn events_read_as_text_or_json() {
+        let finished = Event::FileFinished { file: "scan.fda", outcome: Outcome::Partial, bscans: 128, errors: 1, duration_ms: 40 };
+        assert_eq!(finished.to_string(), "scan.fda: partial in 40 ms");
+        let outcomes = BTreeMap::from([(Outcome::Success, 2), (Outcome::Failed, 1)]);
+        assert_eq!(Event::RunFinished { files: 3, outcomes, exit_code: 3 }.to_string(), "3 files: 2 ok, 1 failed");
+
+        let json = serde_json::to_value(Event::SliceWritten { path: "out/oct/0.png" }).unwrap();
+        assert_eq!(json, serde_json::json!({ "event": "slice_written", "path": "out/oct/0.png" }));
+    }
+
+    #[test]
+    fn json_lines_merge_events_and_wrap_log_lines() {
+        let line = json_line(&log::Record::builder().target(EVENT_TARGET).level(log::Level::Info).args(format_args!("{}", r#"{"event":"file_started","file":"scan.fda"}"#)).build());
+        assert_eq!((line["event"].as_str(), line["file"].as_str(), line["level"].as_str()), (Some("file_started"), Some("scan.fda"), Some("info")));
+
+        let line = json_line(&log::Record::builder().target("oct_extractor::extract").level(log::Level::Warn).args(format_args!("No fundus")).build());
+        assert_eq!((line["event"].as_str(), line["message"].as_str()), (Some("warning"), Some("No fundus")));
+        assert_eq!(line["target"], "oct_extractor::extract");
+    }
+
+    #[test]
+    fn held_files_are_reported_where_they_were_moved() {
+        capture();
+        let dir = format!("events-test-{}-held", std::process::id());
+        let held = hold_events(&dir);
+        image_written(&format!("{}/fundus/fundus.png", dir));
+        slice_written(&format!("{}/oct/0.png", dir));
+        image_written(&format!("{}-other/fundus.png", dir));
+        assert_eq!(captured(&dir), vec![format!("Wrote {}-other/fundus.png", dir)]);
+
+        let renamed = PathBuf::from(format!("{}/OD_fundus.png", dir));
+        held.release(|path| path.ends_with("fundus/fundus.png").then(|| renamed.clone()));
+        assert_eq!(captured(&dir)[1..], [format!("Wrote {}/OD_fundus.png", dir), format!("Wrote {}/oct/0.png", dir)]);
+    }
+
+    #[test]
+    fn dropped_holds_discard_their_files() {
+        capture();
+        let dir = format!("events-test-{}-dropped", std::process::id());
+        let held = hold_events(&dir);
+        slice_written(&format!("{}/oct/0.png", dir));
+        drop(held);
+        assert!(captured(&dir).is_empty());
+        // And later files are reported straight away
+        slice_written(&format!("{}/oct/1.png", dir));
+        assert_eq!(captured(&dir), vec![format!("Wrote {}/oct/1.png", dir)]);
+    }
+}
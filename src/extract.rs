@@ -0,0 +1,497 @@
+use image::{DynamicImage, ImageFormat};
+use serde::Serialize;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::color::ColorOptions;
+use crate::deidentify::Deidentifier;
+use crate::events::{emit, hold_events, image_written, slice_written, Event};
+use crate::fda::fds::{read_img_scan, read_obs_fundus_image};
+use crate::fda::image_processing::{save_image_to_file, read_fundus, read_fundus_image, read_grayscale_fundus, read_img_jpeg, read_grayscale_image, read_thumbnail, read_thumbnail_image, read_oct_volume, read_scan_region};
+use crate::fda::parser::ChunkParserRegistry;
+use crate::fda::reader::TopconReader;
+use crate::fda::utils::{get_list_of_file_chunks, read_all_metadata, ChunkDict, TopconFormat};
+use crate::formats::{detect_format, open_reader, ImageEntry, ImageKind, InputFormat, Metadata, OctReader};
+use crate::montage::{build_montage, MontageInputs};
+use crate::naming::{metadata_tokens, NamingTemplate};
+use crate::outcome::{Failure, Outcome};
+use crate::output::{create_output_dir, output_files, write_output};
+use crate::preview::{render_frames, write_preview, FundusOverlay, PreviewFormat};
+use crate::volume::{save_orthogonal_slices, save_volume_slices, Interpolation, SliceRange, TargetSpacing, Volume};
+
+type Task<'a> = Box<dyn FnOnce() -> Result<(), Box<dyn Error>> + Send + 'a>;
+
+pub struct VolumeOptions {
+    pub resample: Option<TargetSpacing>,
+    pub interpolation: Interpolation,
+    pub reslice: bool,
+}
+
+impl VolumeOptions {
+    pub fn is_active(&self) -> bool {
+        self.resample.is_some() || self.reslice
+    }
+}
+
+fn volume_json(volume: &Volume) -> serde_json::Value {
+    serde_json::json!({
+        "width": volume.width,
+        "height": volume.height,
+        "slices": volume.depth,
+        "spacing_mm": [volume.spacing.x_mm, volume.spacing.y_mm, volume.spacing.z_mm],
+    })
+}
+
+fn export_volume(filepath: &str, chunk_dict: &ChunkDict, format: ImageFormat, output_dir: &str, options: &VolumeOptions, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    write_volumes(vec![(String::new(), read_oct_volume(filepath, chunk_dict)?)], format, output_dir, options, slices)
+}
+
+// `slices` picks from the B-scans as written, i.e. after resampling
+fn write_volume(native: Volume, format: ImageFormat, output_dir: &str, options: &VolumeOptions, slices: SliceRange, label: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mut volume_info = serde_json::json!({ "native": volume_json(&native) });
+    let prefix = if label.is_empty() { String::new() } else { format!("{}_", label) };
+
+    let volume = match options.resample {
+        Some(target) => {
+            if !native.spacing.is_known() {
+                return Err(format!("The voxel spacing of {} is not known ({}), it cannot be resampled", if label.is_empty() { "the volume" } else { label }, native.spacing).into());
+            }
+            let resampled = native.resample(target.resolve(&native.spacing), options.interpolation)?;
+            log::info!("Resampled volume from {} to {}", native.spacing, resampled.spacing);
+            volume_info["interpolation"] = format!("{:?}", options.interpolation).to_lowercase().into();
+            volume_info["resampled"] = volume_json(&resampled);
+            resampled
+        }
+        None => native,
+    };
+
+    save_volume_slices(&volume, &format!("{}/oct", output_dir), &format!("{}bscan", prefix), format, slices)?;
+    if options.reslice {
+        save_orthogonal_slices(&volume, output_dir, &prefix, format)?;
+    }
+    Ok(volume_info)
+}
+
+// volume.json describes a lone volume directly and several keyed by their label
+fn write_volumes(volumes: Vec<(String, Volume)>, format: ImageFormat, output_dir: &str, options: &VolumeOptions, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    let mut lone = None;
+    let mut labelled = serde_json::Map::new();
+    for (label, volume) in volumes {
+        let info = write_volume(volume, format, output_dir, options, slices, &label)?;
+        if label.is_empty() {
+            lone = Some(info);
+        } else {
+            labelled.insert(label, info);
+        }
+    }
+    let volume_info = lone.unwrap_or(serde_json::Value::Object(labelled));
+    write_output(&format!("{}/metadata/volume.json", output_dir), serde_json::to_string_pretty(&volume_info)?.as_bytes())?;
+    Ok(())
+}
+
+pub struct PreviewOptions {
+    pub formats: Vec<PreviewFormat>,
+    pub with_fundus: bool,
+    pub delay_ms: u16,
+}
+
+fn export_preview(filepath: &str, chunk_dict: &ChunkDict, output_dir: &str, options: &PreviewOptions) -> Result<(), Box<dyn Error>> {
+    let volume = read_oct_volume(filepath, chunk_dict)?;
+    let fundus = if options.with_fundus {
+        let image = read_fundus(filepath, chunk_dict)?;
+        let scan_region = read_scan_region(filepath, chunk_dict).ok();
+        Some(FundusOverlay { image, scan_region })
+    } else {
+        None
+    };
+
+    let frames = render_frames(&volume, fundus.as_ref());
+    for format in &options.formats {
+        write_preview(&frames, &format!("{}/preview/{}", output_dir, format.file_name()), *format, options.delay_ms)?;
+    }
+    Ok(())
+}
+
+fn reader_preview(reader: &dyn OctReader, images: &[ImageEntry], output_dir: &str, options: &PreviewOptions) -> Result<(), Box<dyn Error>> {
+    let volume = reader.read_volume()?;
+    let fundus = if options.with_fundus {
+        images
+            .iter()
+            .find(|entry| matches!(entry.kind, ImageKind::Fundus | ImageKind::GrayscaleFundus))
+            .map(|entry| reader.read_image(entry))
+            .transpose()?
+            .map(|image| FundusOverlay { image: image.to_rgb8(), scan_region: None })
+    } else {
+        None
+    };
+    let frames = render_frames(&volume, fundus.as_ref());
+    for format in &options.formats {
+        write_preview(&frames, &format!("{}/preview/{}", output_dir, format.file_name()), *format, options.delay_ms)?;
+    }
+    Ok(())
+}
+
+// B-scans in oct/, i.e. those selected by --slices and written without error; read before --naming moves them
+fn written_bscans(output_dir: &str) -> usize {
+    output_files(&Path::new(output_dir).join("oct")).map_or(0, |files| files.len())
+}
+
+// Before anything is decoded, fail if the template sends two of the selected images to one path
+fn check_naming(naming: &NamingTemplate, images: &[ImageEntry], selection: &Selection, format: ImageFormat) -> Result<(), String> {
+    let planned: Vec<PathBuf> = images
+        .iter()
+        .filter(|entry| selection.includes(entry.kind))
+        .map(|entry| PathBuf::from(format!("{}/{}.{}", entry.kind.directory(), entry.name, format.extensions_str()[0])))
+        .collect();
+    naming.targets(planned.iter().map(PathBuf::as_path), &HashMap::new()).map(|_| ())
+}
+
+// Non-Topcon inputs: every selected image, the volume when resampling or reslicing, and the preview
+fn export_reader(reader: &dyn OctReader, filepath: &str, output_dir: &str, options: &ExtractOptions) -> Result<ExtractReport, Box<dyn Error>> {
+    let volume_options = &options.volume;
+    let preview_options = &options.preview;
+    let selection = &options.selection;
+    let format = options.output_format;
+    let images = reader.list_images().map_err(Failure::input)?;
+    let with_volume = volume_options.is_active() && selection.includes(ImageKind::Bscan);
+    if let Some(naming) = &options.naming {
+        // The B-scans of several E2E series only differ by name
+        check_naming(naming, &images, selection, format)?;
+    }
+    // Written files are reported once --naming has moved them
+    let held = options.naming.as_ref().map(|_| hold_events(output_dir));
+
+    let mut bscan_position = 0;
+    let selected: Vec<&ImageEntry> = images
+        .iter()
+        .filter(|entry| match entry.kind {
+            ImageKind::Bscan => {
+                bscan_position += 1;
+                !with_volume && selection.includes(ImageKind::Bscan) && selection.slices.contains(bscan_position - 1)
+            }
+            kind => selection.includes(kind),
+        })
+        .collect();
+
+    let mut subdirs: Vec<&str> = selected.iter().map(|entry| entry.kind.directory()).collect();
+    subdirs.push("metadata");
+    if with_volume {
+        subdirs.push("oct");
+    }
+    if with_volume && volume_options.reslice {
+        subdirs.extend(["sagittal", "cscan"]);
+    }
+    if !preview_options.formats.is_empty() {
+        subdirs.push("preview");
+    }
+    subdirs.sort_unstable();
+    subdirs.dedup();
+    for subdir in &subdirs {
+        create_output_dir(&format!("{}/{}", output_dir, subdir)).map_err(Failure::output)?;
+    }
+
+    let mut metadata = reader.metadata().map_err(Failure::input)?;
+    if let Some(deidentifier) = &options.deidentifier {
+        deidentifier.apply(&mut metadata)?;
+    }
+    write_output(&format!("{}/metadata/metadata.json", output_dir), serde_json::to_string_pretty(&metadata)?.as_bytes()).map_err(Failure::output)?;
+
+    let mut errors = Vec::new();
+    // The volume export writes the B-scans itself
+    if with_volume {
+        if let Err(e) = reader.read_volumes().and_then(|volumes| write_volumes(volumes, format, output_dir, volume_options, selection.slices)) {
+            errors.push(e.to_string());
+        }
+    }
+    let image_errors: Vec<String> = selected
+        .par_iter()
+        .filter_map(|entry| {
+            let path = format!("{}/{}/{}.{}", output_dir, entry.kind.directory(), entry.name, format.extensions_str()[0]);
+            let image = match reader.read_image(entry) {
+                Ok(image) => image,
+                Err(e) => return Some(format!("Failed to read {}: {}", entry.name, e)),
+            };
+            if let Err(e) = save_image_to_file(&image, &path, Some(format)) {
+                return Some(format!("Failed to save {}: {}", path, e));
+            }
+            match entry.kind {
+                ImageKind::Bscan => slice_written(&path),
+                _ => image_written(&path),
+            }
+            None
+        })
+        .collect();
+    errors.extend(image_errors);
+
+    if !preview_options.formats.is_empty() {
+        if let Err(e) = reader_preview(reader, &images, output_dir, preview_options) {
+            errors.push(e.to_string());
+        }
+    }
+    let bscans = written_bscans(output_dir);
+    if let (Some(naming), Some(held)) = (&options.naming, held) {
+        let moved = naming.apply(output_dir, &metadata_tokens(&metadata, &file_token(filepath, options))).map_err(Failure::output)?;
+        held.release(|path| moved.get(path).cloned());
+    }
+    Ok(ExtractReport {
+        format: reader.format_name().to_string(),
+        bscans,
+        errors,
+        ..Default::default()
+    })
+}
+
+// First non-empty value of `field` among the given metadata chunks
+pub fn metadata_value(metadata: &Metadata, chunks: &[&str], field: &str) -> String {
+    chunks
+        .iter()
+        .filter_map(|chunk| metadata.get(*chunk).and_then(|info| info.get(field)))
+        .map(|value| value.trim_matches('"').trim().to_string())
+        .find(|value| !value.is_empty())
+        .unwrap_or_else(|| "UNKNOWN".to_string())
+}
+
+// Value of the {file} naming token and of the per-input folder names; file names often carry
+// the patient name
+pub fn file_token(filepath: &str, options: &ExtractOptions) -> String {
+    match (&options.deidentifier, std::path::Path::new(filepath).file_stem()) {
+        (Some(deidentifier), _) => deidentifier.file_token(filepath),
+        (None, Some(stem)) => stem.to_string_lossy().to_string(),
+        (None, None) => filepath.to_string(),
+    }
+}
+
+fn export_montage(filepath: &str, chunk_dict: &ChunkDict, metadata: &Metadata, format: ImageFormat, output_dir: &str, deidentified: bool) -> Result<(), Box<dyn Error>> {
+    // File names often carry the patient name
+    let file_name = match std::path::Path::new(filepath).file_name() {
+        _ if deidentified => "DEIDENTIFIED".to_string(),
+        Some(name) => name.to_string_lossy().to_string(),
+        None => filepath.to_string(),
+    };
+
+    let inputs = MontageInputs {
+        fundus: read_fundus(filepath, chunk_dict).ok(),
+        grayscale: read_grayscale_fundus(filepath, chunk_dict).ok().map(|image| DynamicImage::ImageLuma8(image).to_rgb8()),
+        thumbnail: read_thumbnail_image(filepath, chunk_dict).ok().map(|image| image.to_rgb8()),
+        bscans: read_oct_volume(filepath, chunk_dict).map(|volume| (0..volume.depth).map(|y| volume.slice(y)).collect()).unwrap_or_default(),
+        annotations: vec![
+            ("FILE".to_string(), file_name),
+            ("PATIENT ID".to_string(), metadata_value(metadata, &["PATIENT_INFO_02", "PATIENT_INFO_03"], "patient_id")),
+            ("EYE".to_string(), metadata_value(metadata, &["CAPTURE_INFO_02"], "eye")),
+            ("SCAN MODE".to_string(), metadata_value(metadata, &["CAPTURE_INFO_02"], "scan_mode")),
+            ("CAPTURE DATE".to_string(), metadata_value(metadata, &["CAPTURE_INFO_02"], "cap_date")),
+        ],
+    };
+
+    let montage = DynamicImage::ImageRgb8(build_montage(&inputs));
+    let path = format!("{}/montage/montage.{}", output_dir, format.extensions_str()[0]);
+    save_image_to_file(&montage, &path, Some(format))?;
+    image_written(&path);
+    Ok(())
+}
+
+// Image chunks each Topcon variant is expected to hold
+const EXPECTED_FDA_CHUNKS: [&str; 4] = ["@IMG_JPEG", "@IMG_FUNDUS", "@IMG_TRC_02", "@THUMBNAIL"];
+const EXPECTED_FDS_CHUNKS: [&str; 4] = ["@IMG_SCAN_03", "@IMG_OBS", "@IMG_TRC_02", "@THUMBNAIL"];
+
+// Chunk each kind of image is read from
+fn image_chunk(format: TopconFormat, kind: ImageKind) -> &'static str {
+    let chunks = match format {
+        TopconFormat::Fda => EXPECTED_FDA_CHUNKS,
+        TopconFormat::Fds => EXPECTED_FDS_CHUNKS,
+    };
+    match kind {
+        ImageKind::Bscan => chunks[0],
+        ImageKind::Fundus => chunks[1],
+        ImageKind::GrayscaleFundus => chunks[2],
+        ImageKind::Thumbnail => chunks[3],
+    }
+}
+
+// Which image categories and B-scans to write; metadata.json is always written
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub kinds: Vec<ImageKind>,
+    pub slices: SliceRange,
+}
+
+impl Selection {
+    pub fn includes(&self, kind: ImageKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Selection {
+            kinds: vec![ImageKind::Bscan, ImageKind::Fundus, ImageKind::GrayscaleFundus, ImageKind::Thumbnail],
+            slices: SliceRange::ALL,
+        }
+    }
+}
+
+// Everything the extract command can be asked to produce
+pub struct ExtractOptions {
+    pub output_format: ImageFormat,
+    pub volume: VolumeOptions,
+    pub preview: PreviewOptions,
+    pub montage: bool,
+    pub color: ColorOptions,
+    pub deidentifier: Option<Deidentifier>,
+    pub selection: Selection,
+    // None keeps the category/name.ext layout
+    pub naming: Option<NamingTemplate>,
+    // Fail instead of skipping the images of a missing chunk
+    pub strict: bool,
+}
+
+// What one extraction produced; `errors` holds the outputs that failed without stopping the others
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractReport {
+    pub format: String,
+    pub bscans: usize,
+    pub missing_chunks: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl ExtractReport {
+    pub fn outcome(&self) -> Outcome {
+        if self.errors.is_empty() { Outcome::Success } else { Outcome::Partial }
+    }
+}
+
+pub fn extract(filepath: &str, output_dir: &str, options: &ExtractOptions) -> Result<ExtractReport, Box<dyn Error>> {
+    let start = Instant::now();
+    emit(Event::FileStarted { file: filepath });
+    let result = extract_file(filepath, output_dir, options).and_then(|report| {
+        // A partial extraction is a failure with --strict, and none of it is kept
+        if options.strict && !report.errors.is_empty() {
+            return Err(format!("{} of the outputs failed with --strict: {}", report.errors.len(), report.errors.join("; ")).into());
+        }
+        Ok(report)
+    });
+    let (outcome, bscans, errors) = match &result {
+        Ok(report) => (report.outcome(), report.bscans, report.errors.len()),
+        Err(e) => (Outcome::of_error(e.as_ref()), 0, 1),
+    };
+    emit(Event::FileFinished { file: filepath, outcome, bscans, errors, duration_ms: start.elapsed().as_millis() });
+    result
+}
+
+fn extract_file(filepath: &str, output_dir: &str, options: &ExtractOptions) -> Result<ExtractReport, Box<dyn Error>> {
+    let output_format = options.output_format;
+    let volume_options = &options.volume;
+    let preview_options = &options.preview;
+    let deidentifier = options.deidentifier.as_ref();
+    let selection = &options.selection;
+
+    if detect_format(filepath).map_err(Failure::input)? != InputFormat::Topcon {
+        let reader = open_reader(filepath).map_err(Failure::input)?;
+        if options.montage || options.color.white_balance || options.color.embed_profile {
+            log::warn!("Montage and colour options only apply to Topcon files and are ignored for {}", reader.format_name());
+        }
+        return export_reader(reader.as_ref(), filepath, output_dir, options);
+    }
+
+    let (chunk_dict, header) = get_list_of_file_chunks(filepath, false).map_err(Failure::input)?;
+    let topcon_format = TopconFormat::from_header(&header).map_err(Failure::input)?;
+    let expected_chunks = match topcon_format {
+        TopconFormat::Fda => EXPECTED_FDA_CHUNKS,
+        TopconFormat::Fds => EXPECTED_FDS_CHUNKS,
+    };
+    let missing_chunks: Vec<String> = expected_chunks.iter().filter(|name| !chunk_dict.contains_key(**name)).map(|name| name.to_string()).collect();
+    if options.strict && !missing_chunks.is_empty() {
+        return Err(Failure::input(format!("missing expected chunks {}", missing_chunks.join(", "))).into());
+    }
+
+    // Files without a fundus, grayscale or thumbnail chunk are common; missing B-scans stay an error
+    let kinds: Vec<ImageKind> = selection
+        .kinds
+        .iter()
+        .copied()
+        .filter(|kind| {
+            let chunk = image_chunk(topcon_format, *kind);
+            let skip = *kind != ImageKind::Bscan && !chunk_dict.contains_key(chunk);
+            if skip {
+                log::warn!("{} has no {} chunk, no {} images written", filepath, chunk, kind.directory());
+            }
+            !skip
+        })
+        .collect();
+    let with_volume = volume_options.is_active() && kinds.contains(&ImageKind::Bscan);
+    if let Some(naming) = &options.naming {
+        let reader = TopconReader { filepath: filepath.to_string(), format: topcon_format, chunk_dict: chunk_dict.clone(), registry: ChunkParserRegistry::default() };
+        check_naming(naming, &reader.list_images().map_err(Failure::input)?, selection, output_format)?;
+    }
+
+    // Crear las subcarpetas necesarias
+    let mut subdirs: Vec<&str> = kinds.iter().map(|kind| kind.directory()).collect();
+    subdirs.push("metadata");
+    if with_volume && volume_options.reslice {
+        subdirs.extend(["sagittal", "cscan"]);
+    }
+    if !preview_options.formats.is_empty() {
+        subdirs.push("preview");
+    }
+    if options.montage {
+        subdirs.push("montage");
+    }
+    for subdir in &subdirs {
+        create_output_dir(&format!("{}/{}", output_dir, subdir)).map_err(Failure::output)?;
+    }
+
+    let mut metadata = read_all_metadata(filepath, &chunk_dict, &ChunkParserRegistry::default(), true).map_err(Failure::input)?;
+    if let Some(deidentifier) = deidentifier {
+        deidentifier.apply(&mut metadata)?;
+    }
+
+    let metadata_json = serde_json::to_string_pretty(&metadata)?;
+    write_output(&format!("{}/metadata/metadata.json", output_dir), metadata_json.as_bytes()).map_err(Failure::output)?;
+
+    let color_options = &options.color;
+    let slices = selection.slices;
+    let held = options.naming.as_ref().map(|_| hold_events(output_dir));
+    let mut tasks: Vec<Task> = Vec::new();
+    if kinds.contains(&ImageKind::Bscan) {
+        tasks.push(match topcon_format {
+            _ if with_volume => Box::new(|| export_volume(filepath, &chunk_dict, output_format, output_dir, volume_options, slices)),
+            TopconFormat::Fda => Box::new(|| read_img_jpeg(filepath, &chunk_dict, Some(output_format), output_dir, slices)),
+            TopconFormat::Fds => Box::new(|| read_img_scan(filepath, &chunk_dict, output_format, output_dir, slices)),
+        });
+    }
+    if kinds.contains(&ImageKind::Fundus) {
+        tasks.push(match topcon_format {
+            TopconFormat::Fda => Box::new(|| read_fundus_image(filepath, &chunk_dict, Some(output_format), output_dir, color_options)),
+            TopconFormat::Fds => Box::new(|| read_obs_fundus_image(filepath, &chunk_dict, output_format, output_dir, color_options)),
+        });
+    }
+    if kinds.contains(&ImageKind::GrayscaleFundus) {
+        tasks.push(Box::new(|| read_grayscale_image(filepath, &chunk_dict, Some(output_format), output_dir)));
+    }
+    if kinds.contains(&ImageKind::Thumbnail) {
+        tasks.push(Box::new(|| read_thumbnail(filepath, &chunk_dict, output_dir)));
+    }
+    if !preview_options.formats.is_empty() {
+        tasks.push(Box::new(|| export_preview(filepath, &chunk_dict, output_dir, preview_options)));
+    }
+    if options.montage {
+        tasks.push(Box::new(|| export_montage(filepath, &chunk_dict, &metadata, output_format, output_dir, deidentifier.is_some())));
+    }
+
+    let errors = tasks.into_par_iter().filter_map(|task| task().err().map(|e| e.to_string())).collect();
+    let bscans = written_bscans(output_dir);
+    if let (Some(naming), Some(held)) = (&options.naming, held) {
+        let moved = naming.apply(output_dir, &metadata_tokens(&metadata, &file_token(filepath, options))).map_err(Failure::output)?;
+        held.release(|path| moved.get(path).cloned());
+    }
+
+    Ok(ExtractReport {
+        format: topcon_format.name().to_string(),
+        bscans,
+        missing_chunks,
+        errors,
+    })
+}
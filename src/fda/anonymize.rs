@@ -0,0 +1,209 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use serde::Serialize;
+use std::error::Error;
+use std::io::{self, Cursor};
+
+use crate::fda::headers::ThumbnailHeader;
+use crate::fda::image_processing::read_fundus;
+use crate::fda::parser::{HW_INFO_03_FIXED, PATIENT_INFO_02_FIXED, PATIENT_INFO_03_FIXED};
+use crate::fda::utils::get_list_of_file_chunks;
+use crate::fda::version::FdaVersion;
+use crate::fda::writer::FdaWriter;
+
+// Label field of @CAPTURE_INFO_02: after the eye, scan mode and session id
+const CAPTURE_LABEL_OFFSET: usize = 6;
+const CAPTURE_LABEL_LEN: usize = 100;
+
+pub struct AnonymizeOptions {
+    // Written in place of the patient id; names are blanked
+    pub pseudonym: String,
+    // Keep the birth year and set month and day to 1 instead of clearing the date
+    pub keep_birth_year: bool,
+    // Redraw @THUMBNAIL from the fundus photo, in case the original shows PHI
+    pub regenerate_thumbnail: bool,
+}
+
+// One rewritten field, as listed in the mapping file
+#[derive(Debug, Clone, Serialize)]
+pub struct PhiChange {
+    pub chunk: String,
+    pub field: String,
+    pub original: String,
+    pub replacement: String,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn patch_string(payload: &mut [u8], offset: usize, len: usize, value: &str) -> io::Result<String> {
+    let field = payload
+        .get_mut(offset..offset + len)
+        .ok_or_else(|| invalid(format!("Field at {}..{} is past the end of the chunk", offset, offset + len)))?;
+    if value.len() > len {
+        return Err(invalid(format!("'{}' does not fit in a {}-byte field", value, len)));
+    }
+    let original = String::from_utf8_lossy(field).replace('\u{0000}', "");
+    field.fill(0);
+    field[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(original)
+}
+
+fn patch_u16(payload: &mut [u8], offset: usize, value: u16) -> io::Result<u16> {
+    let field = payload
+        .get_mut(offset..offset + 2)
+        .ok_or_else(|| invalid(format!("Field at {} is past the end of the chunk", offset)))?;
+    let original = u16::from_le_bytes([field[0], field[1]]);
+    field.copy_from_slice(&value.to_le_bytes());
+    Ok(original)
+}
+
+// Year, month and day stored as consecutive u16 at `offset`
+fn patch_birth_date(payload: &mut [u8], offset: usize, keep_year: bool) -> io::Result<(String, String)> {
+    let year = u16::from_le_bytes([payload[offset], payload[offset + 1]]);
+    let replacement = if keep_year { [year, 1, 1] } else { [0, 0, 0] };
+    let mut original = [0u16; 3];
+    for (i, value) in replacement.iter().enumerate() {
+        original[i] = patch_u16(payload, offset + 2 * i, *value)?;
+    }
+    let format = |date: [u16; 3]| format!("{}-{}-{}", date[0], date[1], date[2]);
+    Ok((format(original), format(replacement)))
+}
+
+struct Patcher<'a> {
+    changes: &'a mut Vec<PhiChange>,
+    chunk: &'a str,
+}
+
+impl Patcher<'_> {
+    fn record(&mut self, field: &str, original: String, replacement: &str) {
+        self.changes.push(PhiChange {
+            chunk: self.chunk.trim_start_matches('@').to_string(),
+            field: field.to_string(),
+            original,
+            replacement: replacement.to_string(),
+        });
+    }
+
+    fn string(&mut self, payload: &mut [u8], field: &str, offset: usize, len: usize, value: &str) -> io::Result<()> {
+        let original = patch_string(payload, offset, len, value)?;
+        self.record(field, original, value);
+        Ok(())
+    }
+
+    fn birth_date(&mut self, payload: &mut [u8], offset: usize, keep_year: bool) -> io::Result<()> {
+        if payload.len() < offset + 6 {
+            return Err(invalid(format!("Birth date at {} is past the end of the chunk", offset)));
+        }
+        let (original, replacement) = patch_birth_date(payload, offset, keep_year)?;
+        self.record("birth_date", original, &replacement);
+        Ok(())
+    }
+}
+
+fn anonymize_patient_info(payload: &mut [u8], patcher: &mut Patcher, len: usize, date_offset: usize, options: &AnonymizeOptions) -> io::Result<()> {
+    patcher.string(payload, "patient_id", 0, len, &options.pseudonym)?;
+    patcher.string(payload, "given_name", len, len, "")?;
+    patcher.string(payload, "surname", 2 * len, len, "")?;
+    patcher.birth_date(payload, date_offset, options.keep_birth_year)
+}
+
+// Thumbnail payload: u32 size followed by a BMP, padded with zeros to `length`. A colour BMP
+// that does not fit is written as grayscale.
+fn thumbnail_payload(image: &DynamicImage, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    for image in [image.clone(), DynamicImage::ImageLuma8(image.to_luma8())] {
+        let mut bmp = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)?;
+        if bmp.len() + 4 <= length {
+            let mut payload = (bmp.len() as u32).to_le_bytes().to_vec();
+            payload.extend(bmp);
+            payload.resize(length, 0);
+            return Ok(payload);
+        }
+    }
+    Err(format!("A {}x{} thumbnail does not fit in the {} bytes of @THUMBNAIL", image.width(), image.height(), length).into())
+}
+
+// Same dimensions and payload length as the original thumbnail
+fn regenerate_thumbnail(filepath: &str, original: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let thumbnail = ThumbnailHeader::from_reader(&mut Cursor::new(original))?;
+    let (width, height) = image::load_from_memory_with_format(&thumbnail.img, ImageFormat::Bmp)
+        .map(|image| (image.width(), image.height()))
+        .unwrap_or((128, 128));
+    let (chunk_dict, _) = get_list_of_file_chunks(filepath, false)?;
+    let fundus = read_fundus(filepath, &chunk_dict)?;
+    let resized = image::imageops::resize(&fundus, width, height, FilterType::Triangle);
+    thumbnail_payload(&DynamicImage::ImageRgb8(resized), original.len())
+}
+
+// Rewrite the PHI fields of `file` (read from `filepath`) in place, keeping every
+// chunk size so the result still opens in the Topcon viewer
+pub fn anonymize(filepath: &str, file: &mut FdaWriter, options: &AnonymizeOptions) -> Result<Vec<PhiChange>, Box<dyn Error>> {
+    let version = FdaVersion::from_header(&file.header);
+    let patient_len = version.layout.patient_string_len;
+    let hw_len = version.layout.hw_string_len;
+    let mut changes = Vec::new();
+
+    for chunk in file.chunks.iter_mut() {
+        let payload_len = chunk.payload.len();
+        let mut patcher = Patcher { changes: &mut changes, chunk: &chunk.name };
+        match chunk.name.as_str() {
+            "@PATIENT_INFO_02" => {
+                let (len, _) = version.string_len(patient_len, 3, PATIENT_INFO_02_FIXED, payload_len);
+                // 8 reserved bytes and the validity flag precede the date
+                anonymize_patient_info(&mut chunk.payload, &mut patcher, len, 3 * len + 9, options)?;
+                if !options.keep_birth_year {
+                    chunk.payload[3 * len + 8] = 0;
+                }
+            }
+            "@PATIENT_INFO_03" => {
+                let (len, _) = version.string_len(patient_len, 3, PATIENT_INFO_03_FIXED, payload_len);
+                // Sex byte precedes the date
+                anonymize_patient_info(&mut chunk.payload, &mut patcher, len, 3 * len + 1, options)?;
+            }
+            "@CAPTURE_INFO_02" => {
+                patcher.string(&mut chunk.payload, "label", CAPTURE_LABEL_OFFSET, CAPTURE_LABEL_LEN, "")?;
+            }
+            "@HW_INFO_03" => {
+                let (len, _) = version.string_len(hw_len, 5, HW_INFO_03_FIXED, payload_len);
+                patcher.string(&mut chunk.payload, "serial_number", len, len, "")?;
+                patcher.string(&mut chunk.payload, "spect_sn", 2 * len, len, "")?;
+            }
+            "@THUMBNAIL" if options.regenerate_thumbnail => {
+                chunk.payload = regenerate_thumbnail(filepath, &chunk.payload)?;
+                patcher.record("image", "original thumbnail".to_string(), "regenerated from fundus");
+            }
+            _ => {}
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 7])))
+    }
+
+    #[test]
+    fn thumbnail_keeps_the_payload_length() {
+        let payload = thumbnail_payload(&gradient(16, 16), 2000).unwrap();
+        assert_eq!(payload.len(), 2000);
+        let thumbnail = ThumbnailHeader::from_reader(&mut Cursor::new(&payload)).unwrap();
+        let image = image::load_from_memory_with_format(&thumbnail.img, ImageFormat::Bmp).unwrap();
+        assert_eq!((image.width(), image.height()), (16, 16));
+    }
+
+    #[test]
+    fn thumbnail_falls_back_to_grayscale_then_fails() {
+        // 32x32 takes 3126 bytes as a 24-bit BMP and 2102 as an 8-bit one
+        let payload = thumbnail_payload(&gradient(32, 32), 3000).unwrap();
+        let thumbnail = ThumbnailHeader::from_reader(&mut Cursor::new(&payload)).unwrap();
+        // Bits per pixel of the BMP info header
+        assert_eq!(u16::from_le_bytes([thumbnail.img[28], thumbnail.img[29]]), 8);
+        assert!(thumbnail_payload(&gradient(32, 32), 100).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use image::{DynamicImage, GrayImage, ImageFormat, RgbImage};
+use log::{info, warn};
+use std::error::Error;
+use std::io;
+
+use crate::color::ColorOptions;
+use crate::fda::headers::{ImgObsHeader, ImgScan03Header};
+use crate::fda::image_processing::{decode_j2k_image, read_camera_info, read_chunk_bytes, read_voxel_spacing, save_fundus_images, split_j2k_codestreams};
+use crate::fda::utils::ChunkDict;
+use crate::volume::{save_volume_slices, SliceRange, Volume};
+
+// Size of ImgScan03Header on disk
+const IMG_SCAN_HEADER_SIZE: usize = 22;
+// Size of ImgObsHeader on disk
+const IMG_OBS_HEADER_SIZE: usize = 24;
+
+// Bytes per B-scan pixel: one up to 8 bits, two (little-endian) up to 16
+fn bytes_per_pixel(header: &ImgScan03Header) -> Result<usize, String> {
+    match header.bits_per_pixel {
+        1..=8 => Ok(1),
+        9..=16 => Ok(2),
+        bits => Err(format!("@IMG_SCAN_03 has {} bits per pixel, expected 1 to 16", bits)),
+    }
+}
+
+// Size of one B-scan, refusing header values that do not fit in memory
+pub fn slice_bytes(header: &ImgScan03Header) -> Result<usize, String> {
+    (header.width as usize)
+        .checked_mul(header.height as usize)
+        .and_then(|pixels| pixels.checked_mul(bytes_per_pixel(header).ok()?))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("@IMG_SCAN_03 has an invalid geometry {}x{} at {} bits", header.width, header.height, header.bits_per_pixel))
+}
+
+// @IMG_SCAN_03 stores the B-scans raw, row by row, slice after slice. Returns the header, the
+// pixels of every B-scan and the size of one.
+fn scan_pixels(raw: &[u8]) -> Result<(ImgScan03Header, &[u8], usize), Box<dyn Error>> {
+    let header = ImgScan03Header::from_reader(&mut io::Cursor::new(raw))?;
+    let slice_size = slice_bytes(&header)?;
+    let number_slices = header.number_slices as usize;
+    let expected = slice_size
+        .checked_mul(number_slices)
+        .and_then(|pixels| pixels.checked_add(IMG_SCAN_HEADER_SIZE))
+        .filter(|_| number_slices > 0);
+    match expected {
+        Some(expected) if raw.len() >= expected => Ok((header, &raw[IMG_SCAN_HEADER_SIZE..expected], slice_size)),
+        _ => Err(format!(
+            "@IMG_SCAN_03 holds {} bytes, too few for {}x{}x{} at {} bits",
+            raw.len(), header.width, header.height, number_slices, header.bits_per_pixel
+        ).into()),
+    }
+}
+
+// Samples wider than 8 bits are reduced to their top 8 bits
+fn scan_slice(header: &ImgScan03Header, slice: &[u8]) -> GrayImage {
+    let shift = header.bits_per_pixel.saturating_sub(8);
+    let data = if slice.len() == header.width as usize * header.height as usize {
+        slice.to_vec()
+    } else {
+        slice.chunks_exact(2).map(|px| (u16::from_le_bytes([px[0], px[1]]) >> shift) as u8).collect()
+    };
+    GrayImage::from_raw(header.width, header.height, data).expect("slice buffer matches dimensions")
+}
+
+pub fn decode_scan_slices(raw: &[u8]) -> Result<(ImgScan03Header, Vec<GrayImage>), Box<dyn Error>> {
+    let (header, pixels, slice_size) = scan_pixels(raw)?;
+    let slices = pixels.chunks_exact(slice_size).map(|slice| scan_slice(&header, slice)).collect();
+    Ok((header, slices))
+}
+
+pub fn read_scan_slice(filepath: &str, chunk_dict: &ChunkDict, index: usize) -> Result<GrayImage, Box<dyn Error>> {
+    let raw = read_chunk_bytes(filepath, chunk_dict, "@IMG_SCAN_03")?;
+    let (header, pixels, slice_size) = scan_pixels(&raw)?;
+    let slice = pixels
+        .chunks_exact(slice_size)
+        .nth(index)
+        .ok_or_else(|| format!("B-scan {} out of range, the volume has {}", index, header.number_slices))?;
+    Ok(scan_slice(&header, slice))
+}
+
+pub fn read_scan_volume(filepath: &str, chunk_dict: &ChunkDict) -> Result<Volume, Box<dyn Error>> {
+    warn!("FDS support is experimental: the @IMG_SCAN_03 layout has not been verified against device files, check the B-scans of {}", filepath);
+    let raw = read_chunk_bytes(filepath, chunk_dict, "@IMG_SCAN_03")?;
+    let (header, slices) = decode_scan_slices(&raw)?;
+    let spacing = read_voxel_spacing(filepath, chunk_dict, header.width as usize, header.number_slices as usize)?;
+    Volume::from_slices(&slices, spacing)
+}
+
+// @IMG_OBS holds the fundus photo either as a J2K codestream or as raw BGR pixels
+pub fn read_obs_image(filepath: &str, chunk_dict: &ChunkDict) -> Result<RgbImage, Box<dyn Error>> {
+    let raw = read_chunk_bytes(filepath, chunk_dict, "@IMG_OBS")?;
+    let header = ImgObsHeader::from_reader(&mut io::Cursor::new(&raw))?;
+    let payload = &raw[IMG_OBS_HEADER_SIZE.min(raw.len())..];
+
+    if let Some(codestream) = split_j2k_codestreams(payload).into_iter().next() {
+        return Ok(decode_j2k_image(codestream, true, false)?.to_rgb8());
+    }
+
+    let pixels = header.width as usize * header.height as usize;
+    if payload.len() >= pixels * 3 {
+        let mut data = payload[..pixels * 3].to_vec();
+        for px in data.chunks_exact_mut(3) {
+            px.swap(0, 2); // Swap B and R
+        }
+        return RgbImage::from_raw(header.width, header.height, data).ok_or_else(|| "Failed to create fundus image".into());
+    }
+    if payload.len() >= pixels {
+        let gray = GrayImage::from_raw(header.width, header.height, payload[..pixels].to_vec()).ok_or("Failed to create fundus image")?;
+        return Ok(DynamicImage::ImageLuma8(gray).to_rgb8());
+    }
+    Err(format!("@IMG_OBS holds {} bytes, too few for a {}x{} image", payload.len(), header.width, header.height).into())
+}
+
+pub fn read_img_scan(filepath: &str, chunk_dict: &ChunkDict, format: ImageFormat, output_dir: &str, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    if !chunk_dict.contains_key("@IMG_SCAN_03") {
+        info!("@IMG_SCAN_03 is not in chunk list, skipping.");
+        return Err("Chunk @IMG_SCAN_03 not found".into());
+    }
+    let volume = read_scan_volume(filepath, chunk_dict)?;
+    save_volume_slices(&volume, &format!("{}/oct", output_dir), "bscan", format, slices)
+}
+
+pub fn read_obs_fundus_image(filepath: &str, chunk_dict: &ChunkDict, format: ImageFormat, output_dir: &str, color: &ColorOptions) -> Result<(), Box<dyn Error>> {
+    if !chunk_dict.contains_key("@IMG_OBS") {
+        info!("@IMG_OBS is not in chunk list, skipping.");
+        return Err("Chunk @IMG_OBS not found".into());
+    }
+    let image = read_obs_image(filepath, chunk_dict)?;
+    let camera = read_camera_info(filepath, chunk_dict).ok();
+    save_fundus_images(vec![image], &format!("{}/fundus", output_dir), format, camera.as_ref(), color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_chunk(width: u32, height: u32, bits_per_pixel: u32, number_slices: u32, pixels: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0];
+        for value in [width, height, bits_per_pixel, number_slices] {
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+        raw.push(0);
+        raw.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+        raw.extend_from_slice(pixels);
+        raw
+    }
+
+    #[test]
+    fn header_size_matches_layout() {
+        assert_eq!(scan_chunk(1, 1, 8, 1, &[]).len(), IMG_SCAN_HEADER_SIZE);
+    }
+
+    #[test]
+    fn decodes_16_bit_slices_to_their_top_bits() {
+        let pixels: Vec<u8> = [0x0100u16, 0xFF00, 0x8000, 0x00FF, 0x1234, 0xABCD].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let (header, slices) = decode_scan_slices(&scan_chunk(3, 1, 16, 2, &pixels)).unwrap();
+        assert_eq!(header.number_slices, 2);
+        assert_eq!(slices[0].as_raw(), &vec![0x01, 0xFF, 0x80]);
+        assert_eq!(slices[1].as_raw(), &vec![0x00, 0x12, 0xAB]);
+    }
+
+    #[test]
+    fn decodes_12_bit_slices_over_their_range() {
+        let pixels: Vec<u8> = [0x0FFFu16, 0x0800].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let (_, slices) = decode_scan_slices(&scan_chunk(2, 1, 12, 1, &pixels)).unwrap();
+        assert_eq!(slices[0].as_raw(), &vec![0xFF, 0x80]);
+    }
+
+    #[test]
+    fn decodes_8_bit_slices_as_they_are() {
+        let (_, slices) = decode_scan_slices(&scan_chunk(2, 2, 8, 1, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(slices[0].as_raw(), &vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_impossible_headers() {
+        assert!(decode_scan_slices(&scan_chunk(4, 4, 16, 2, &[0; 40])).is_err());
+        assert!(decode_scan_slices(&scan_chunk(4, 4, 16, 0, &[])).is_err());
+        assert!(decode_scan_slices(&scan_chunk(0, 4, 16, 1, &[])).is_err());
+        assert!(decode_scan_slices(&scan_chunk(4, 4, 32, 1, &[0; 64])).is_err());
+        assert!(decode_scan_slices(&scan_chunk(u32::MAX, u32::MAX, 16, u32::MAX, &[0; 8])).is_err());
+        assert!(decode_scan_slices(&[0; 10]).is_err());
+    }
+}
@@ -1,200 +1,358 @@
-use image::{DynamicImage, ImageBuffer, ImageFormat, Luma, Rgba};
-use jpeg2k::Image as Jpeg2kImage;
-use log::{error, info};
-use rayon::prelude::*;
-use std::error::Error;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::collections::HashMap;
-use crate::fda::headers::ThumbnailHeader;
-
-const J2K_SOI: &[u8] = &[0xFF, 0x4F, 0xFF, 0x51];
-
-fn save_image_to_file(image: &DynamicImage, path: &str, format: Option<ImageFormat>) -> Result<(), Box<dyn Error>> {
-    match format {
-        Some(format) => {
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(path)?;
-            if format == ImageFormat::Jpeg && image.color().has_alpha() {
-                // Convert Rgba8 to Rgb8 for JPEG
-                let rgb_image = image.to_rgb8();
-                DynamicImage::ImageRgb8(rgb_image).write_to(&mut file, format)?;
-            } else {
-                image.write_to(&mut file, format)?;
-            }
-        }
-        None => {
-            // Save as .j2k
-            let image_data = image.clone().into_bytes();
-            save_j2k_file(&image_data, path)?;
-        }
-    }
-    Ok(())
-}
-
-fn convert_bgr_to_rgb(image_data: &mut [u8]) {
-    for chunk in image_data.chunks_exact_mut(4) {
-        chunk.swap(0, 2); // Swap B and R
-    }
-}
-
-fn convert_rgb_to_bgr(image_data: &mut [u8]) {
-    for chunk in image_data.chunks_exact_mut(3) {
-        chunk.swap(0, 2); // Swap R and B
-    }
-}
-
-fn scale_16bit_to_8bit(data: &[u8]) -> Vec<u8> {
-    data.chunks_exact(2)
-        .map(|chunk| (u16::from_be_bytes([chunk[0], chunk[1]]) >> 8) as u8)
-        .collect()
-}
-
-fn save_j2k_to_format(j2k_data: &[u8], base_path: &str, format: Option<ImageFormat>, is_bgr: bool, is_greyscale_16bit: bool) -> Result<(), Box<dyn Error>> {
-    if format.is_none() {
-        // Convert RGB to BGR if needed
-        let mut image_data = j2k_data.to_vec();
-        if is_bgr {
-            convert_rgb_to_bgr(&mut image_data);
-        }
-
-        // Directly save the raw J2K data
-        let j2k_path = format!("{}.j2k", base_path);
-        return Ok(save_j2k_file(&image_data, &j2k_path)?);
-    }
-
-    let jp2_image = Jpeg2kImage::from_bytes(j2k_data)?;
-    let width = jp2_image.width();
-    let height = jp2_image.height();
-
-    let mut image_data = jp2_image.get_pixels(Some(255))?;
-    if is_bgr {
-        convert_bgr_to_rgb(&mut image_data.data);
-    }
-
-    let img = if jp2_image.num_components() == 1 {
-        if is_greyscale_16bit && image_data.data.len() == width as usize * height as usize * 2 {
-            let luma_data_8bit = scale_16bit_to_8bit(&image_data.data);
-            let luma_buffer: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, luma_data_8bit)
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to create Luma ImageBuffer"))?;
-            DynamicImage::ImageLuma8(luma_buffer)
-        } else {
-            let luma_buffer: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, image_data.data.clone())
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to create Luma ImageBuffer"))?;
-            DynamicImage::ImageLuma8(luma_buffer)
-        }
-    } else {
-        let buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, image_data.data.clone())
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Failed to create ImageBuffer"))?;
-        DynamicImage::ImageRgba8(buffer)
-    };
-
-    let extension = if let Some(format) = format {
-        format.extensions_str()[0]
-    } else {
-        "j2k"
-    };
-
-    save_image_to_file(&img, &format!("{}.{}", base_path, extension), format)?;
-    Ok(())
-}
-
-fn extract_images_from_chunk_parallel(data: &[u8], output_dir: &str, prefix: &str, format: Option<ImageFormat>, is_bgr: bool, is_greyscale_16bit: bool) -> Result<(), Box<dyn Error>> {
-    let mut start = 0;
-    let mut positions = vec![];
-
-    while let Some(pos) = data[start..].windows(4).position(|window| window == J2K_SOI) {
-        positions.push(start + pos);
-        start += pos + J2K_SOI.len();
-    }
-
-    positions.push(data.len()); // Add the end of the data as the final position
-
-    positions.par_windows(2).enumerate().for_each(|(image_count, window)| {
-        let image_start = window[0];
-        let image_end = window[1];
-        let image_data = &data[image_start..image_end];
-        let base_path = format!("{}/{}_{}", output_dir, prefix, image_count);
-
-        if let Err(e) = save_j2k_to_format(image_data, &base_path, format, is_bgr, is_greyscale_16bit) {
-            error!("Failed to save image formats for {}_{}: {}", prefix, image_count, e);
-        }
-    });
-
-    Ok(())
-}
-
-pub fn read_fundus_image(filepath: &str, chunk_dict: &HashMap<String, (u64, u32)>, format: Option<ImageFormat>, output_dir: &str) -> Result<(), Box<dyn Error>> {
-    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_FUNDUS") {
-        let mut raw_image = vec![0; chunk_size as usize];
-        let mut file = File::open(filepath)?;
-        file.seek(SeekFrom::Start(chunk_location))?;
-        file.read_exact(&mut raw_image)?;
-
-        extract_images_from_chunk_parallel(&raw_image, &format!("{}/fundus", output_dir), "fundus", format,  true, false)?;
-        Ok(())
-    } else {
-        info!("@IMG_FUNDUS is not in chunk list, skipping.");
-        Err("Chunk @IMG_FUNDUS not found".into())
-    }
-}
-
-pub fn read_img_jpeg(filepath: &str, chunk_dict: &HashMap<String, (u64, u32)>, format: Option<ImageFormat>, output_dir: &str) -> Result<(), Box<dyn Error>> {
-    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_JPEG") {
-        let mut raw_image = vec![0; chunk_size as usize];
-        let mut file = File::open(filepath)?;
-        file.seek(SeekFrom::Start(chunk_location))?;
-        file.read_exact(&mut raw_image)?;
-
-        extract_images_from_chunk_parallel(&raw_image, &format!("{}/oct", output_dir), "bscan", format, false, true)?;
-        Ok(())
-    } else {
-        info!("@IMG_JPEG is not in chunk list, skipping.");
-        Err("Chunk @IMG_JPEG not found".into())
-    }
-}
-
-pub fn read_grayscale_image(filepath: &str, chunk_dict: &HashMap<String, (u64, u32)>, format: Option<ImageFormat>, output_dir: &str) -> Result<(), Box<dyn Error>> {
-    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_TRC_02") {
-        let mut raw_image = vec![0; chunk_size as usize];
-        let mut file = File::open(filepath)?;
-        file.seek(SeekFrom::Start(chunk_location))?;
-        file.read_exact(&mut raw_image)?;
-
-        extract_images_from_chunk_parallel(&raw_image, &format!("{}/grayscale", output_dir), "grayscale_fundus", format, false, false)?;
-        Ok(())
-    } else {
-        info!("@IMG_TRC_02 is not in chunk list, skipping.");
-        Err("Chunk @IMG_TRC_02 not found".into())
-    }
-}
-
-pub fn read_thumbnail(filepath: &str, chunk_dict: &HashMap<String, (u64, u32)>, output_dir: &str) -> Result<(), Box<dyn Error>> {
-    if let Some(&(chunk_location, _chunk_size)) = chunk_dict.get("@THUMBNAIL") {
-        let mut file = File::open(filepath)?;
-        file.seek(SeekFrom::Start(chunk_location))?;
-
-        let header = ThumbnailHeader::from_reader(&mut file)?;
-        let image = image::load_from_memory_with_format(&header.img, ImageFormat::Bmp)?;
-        let thumbnail_path = format!("{}/thumbnail/thumbnail.bmp", output_dir);
-        image.save(&thumbnail_path)?;
-
-        Ok(())
-    } else {
-        info!("@THUMBNAIL is not in chunk list, skipping.");
-        Err("Chunk @THUMBNAIL not found".into())
-    }
-}
-
-fn save_j2k_file(j2k_data: &[u8], path: &str) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path)?;
-    file.write_all(j2k_data)?;
-    Ok(())
-}
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageFormat, Luma, RgbImage, Rgba};
+use jpeg2k::Image as Jpeg2kImage;
+use log::{info, warn};
+use rayon::prelude::*;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use crate::color::{encode_with_color_metadata, white_balance, CameraInfo, ColorOptions};
+use crate::fda::fds::{read_obs_image, read_scan_slice, read_scan_volume};
+use crate::fda::headers::{ImgJpegHeader, ParamObs02Header, ParamScan04Header, RegistInfoHeader, ThumbnailHeader};
+use crate::events::{image_written, slice_written};
+use crate::fda::utils::ChunkDict;
+use crate::output::write_output;
+use crate::volume::{SliceRange, Volume, VoxelSpacing};
+
+const J2K_SOI: &[u8] = &[0xFF, 0x4F, 0xFF, 0x51];
+
+pub fn save_image_to_file(image: &DynamicImage, path: &str, format: Option<ImageFormat>) -> Result<(), Box<dyn Error>> {
+    match format {
+        Some(format) => {
+            let mut encoded = Cursor::new(Vec::new());
+            if format == ImageFormat::Jpeg && image.color().has_alpha() {
+                // Convert Rgba8 to Rgb8 for JPEG
+                let rgb_image = image.to_rgb8();
+                DynamicImage::ImageRgb8(rgb_image).write_to(&mut encoded, format)?;
+            } else {
+                image.write_to(&mut encoded, format)?;
+            }
+            write_output(path, encoded.get_ref())?;
+        }
+        None => {
+            // Save as .j2k
+            let image_data = image.clone().into_bytes();
+            save_j2k_file(&image_data, path)?;
+        }
+    }
+    Ok(())
+}
+
+fn convert_bgr_to_rgb(image_data: &mut [u8]) {
+    for chunk in image_data.chunks_exact_mut(4) {
+        chunk.swap(0, 2); // Swap B and R
+    }
+}
+
+fn convert_rgb_to_bgr(image_data: &mut [u8]) {
+    for chunk in image_data.chunks_exact_mut(3) {
+        chunk.swap(0, 2); // Swap R and B
+    }
+}
+
+fn scale_16bit_to_8bit(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2)
+        .map(|chunk| (u16::from_be_bytes([chunk[0], chunk[1]]) >> 8) as u8)
+        .collect()
+}
+
+pub fn decode_j2k_image(j2k_data: &[u8], is_bgr: bool, is_greyscale_16bit: bool) -> Result<DynamicImage, Box<dyn Error>> {
+    let jp2_image = Jpeg2kImage::from_bytes(j2k_data)?;
+    let width = jp2_image.width();
+    let height = jp2_image.height();
+
+    let mut image_data = jp2_image.get_pixels(Some(255))?;
+    if is_bgr {
+        convert_bgr_to_rgb(&mut image_data.data);
+    }
+
+    let img = if jp2_image.num_components() == 1 {
+        if is_greyscale_16bit && image_data.data.len() == width as usize * height as usize * 2 {
+            let luma_data_8bit = scale_16bit_to_8bit(&image_data.data);
+            let luma_buffer: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, luma_data_8bit)
+                .ok_or_else(|| io::Error::other("Failed to create Luma ImageBuffer"))?;
+            DynamicImage::ImageLuma8(luma_buffer)
+        } else {
+            // get_pixels returns luma + alpha pairs for single component images
+            let luma_data = if image_data.data.len() == width as usize * height as usize * 2 {
+                image_data.data.iter().step_by(2).copied().collect()
+            } else {
+                image_data.data
+            };
+            let luma_buffer: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, luma_data)
+                .ok_or_else(|| io::Error::other("Failed to create Luma ImageBuffer"))?;
+            DynamicImage::ImageLuma8(luma_buffer)
+        }
+    } else {
+        let buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, image_data.data)
+            .ok_or_else(|| io::Error::other("Failed to create ImageBuffer"))?;
+        DynamicImage::ImageRgba8(buffer)
+    };
+    Ok(img)
+}
+
+// Returns the path written, base_path plus the extension
+fn save_j2k_to_format(j2k_data: &[u8], base_path: &str, format: Option<ImageFormat>, is_bgr: bool, is_greyscale_16bit: bool) -> Result<String, Box<dyn Error>> {
+    if format.is_none() {
+        // Convert RGB to BGR if needed
+        let mut image_data = j2k_data.to_vec();
+        if is_bgr {
+            convert_rgb_to_bgr(&mut image_data);
+        }
+
+        // Directly save the raw J2K data
+        let j2k_path = format!("{}.j2k", base_path);
+        save_j2k_file(&image_data, &j2k_path)?;
+        return Ok(j2k_path);
+    }
+
+    let img = decode_j2k_image(j2k_data, is_bgr, is_greyscale_16bit)?;
+
+    let extension = if let Some(format) = format {
+        format.extensions_str()[0]
+    } else {
+        "j2k"
+    };
+
+    let path = format!("{}.{}", base_path, extension);
+    save_image_to_file(&img, &path, format)?;
+    Ok(path)
+}
+
+// Split a chunk payload into its J2K codestreams
+pub fn split_j2k_codestreams(data: &[u8]) -> Vec<&[u8]> {
+    let mut start = 0;
+    let mut positions = vec![];
+
+    while let Some(pos) = data[start..].windows(4).position(|window| window == J2K_SOI) {
+        positions.push(start + pos);
+        start += pos + J2K_SOI.len();
+    }
+
+    positions.push(data.len()); // Add the end of the data as the final position
+
+    positions.windows(2).map(|window| &data[window[0]..window[1]]).collect()
+}
+
+// Every selected codestream is tried; the ones that fail are listed in the error
+fn extract_images_from_chunk_parallel(data: &[u8], output_dir: &str, prefix: &str, format: Option<ImageFormat>, is_bgr: bool, is_greyscale_16bit: bool, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    let codestreams = split_j2k_codestreams(data);
+
+    // Skipped codestreams are never decoded
+    let failures: Vec<String> = codestreams.par_iter().enumerate().filter(|(image_count, _)| slices.contains(*image_count)).filter_map(|(image_count, image_data)| {
+        let base_path = format!("{}/{}_{}", output_dir, prefix, image_count);
+
+        match save_j2k_to_format(image_data, &base_path, format, is_bgr, is_greyscale_16bit) {
+            Ok(path) if prefix == "bscan" => slice_written(&path),
+            Ok(path) => image_written(&path),
+            Err(e) => return Some(format!("{}_{}: {}", prefix, image_count, e)),
+        }
+        None
+    }).collect();
+
+    if !failures.is_empty() {
+        return Err(format!("Failed to save {} {} images: {}", failures.len(), prefix, failures.join("; ")).into());
+    }
+    Ok(())
+}
+
+pub fn read_chunk_bytes(filepath: &str, chunk_dict: &ChunkDict, chunk_name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let &(chunk_location, chunk_size) = chunk_dict
+        .get(chunk_name)
+        .ok_or_else(|| format!("Chunk {} not found", chunk_name))?;
+    let mut raw = vec![0; chunk_size as usize];
+    let mut file = File::open(filepath)?;
+    file.seek(SeekFrom::Start(chunk_location))?;
+    file.read_exact(&mut raw)?;
+    Ok(raw)
+}
+
+pub fn read_camera_info(filepath: &str, chunk_dict: &ChunkDict) -> Result<CameraInfo, Box<dyn Error>> {
+    let raw = read_chunk_bytes(filepath, chunk_dict, "@PARAM_OBS_02")?;
+    let header = ParamObs02Header::parse(&mut io::Cursor::new(raw))?;
+    Ok(CameraInfo {
+        camera_model: header.camera_model,
+        color_temperature: header.color_temperature,
+        color_temperature_value: header.color_temperature_value,
+    })
+}
+
+pub fn save_fundus_images(images: Vec<RgbImage>, output_dir: &str, format: ImageFormat, camera: Option<&CameraInfo>, color: &ColorOptions) -> Result<(), Box<dyn Error>> {
+    let kelvin = if color.white_balance { camera.and_then(CameraInfo::kelvin) } else { None };
+    if color.white_balance && kelvin.is_none() {
+        warn!("No usable colour temperature in @PARAM_OBS_02, fundus is not white balanced");
+    }
+    let embedded = if color.embed_profile { Some(camera.cloned().unwrap_or_default()) } else { None };
+
+    images.into_par_iter().enumerate().try_for_each(|(image_count, mut image)| {
+        if let Some(kelvin) = kelvin {
+            white_balance(&mut image, kelvin);
+        }
+        let path = format!("{}/fundus_{}.{}", output_dir, image_count, format.extensions_str()[0]);
+        let encoded = encode_with_color_metadata(&DynamicImage::ImageRgb8(image), format, embedded.as_ref(), kelvin.is_some())
+            .map_err(|e| e.to_string())?;
+        write_output(&path, &encoded).map_err(|e| format!("Failed to save {}: {}", path, e))?;
+        image_written(&path);
+        Ok::<(), String>(())
+    })?;
+    Ok(())
+}
+
+pub fn read_fundus_image(filepath: &str, chunk_dict: &ChunkDict, format: Option<ImageFormat>, output_dir: &str, color: &ColorOptions) -> Result<(), Box<dyn Error>> {
+    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_FUNDUS") {
+        let mut raw_image = vec![0; chunk_size as usize];
+        let mut file = File::open(filepath)?;
+        file.seek(SeekFrom::Start(chunk_location))?;
+        file.read_exact(&mut raw_image)?;
+
+        match format {
+            Some(format) if color.is_active() => {
+                let camera = read_camera_info(filepath, chunk_dict).ok();
+                let images = split_j2k_codestreams(&raw_image)
+                    .par_iter()
+                    .map(|codestream| decode_j2k_image(codestream, true, false).map(|img| img.to_rgb8()).map_err(|e| e.to_string()))
+                    .collect::<Result<Vec<_>, String>>()?;
+                save_fundus_images(images, &format!("{}/fundus", output_dir), format, camera.as_ref(), color)?;
+            }
+            _ => extract_images_from_chunk_parallel(&raw_image, &format!("{}/fundus", output_dir), "fundus", format,  true, false, SliceRange::ALL)?,
+        }
+        Ok(())
+    } else {
+        info!("@IMG_FUNDUS is not in chunk list, skipping.");
+        Err("Chunk @IMG_FUNDUS not found".into())
+    }
+}
+
+// Decode the colour fundus photo into memory
+pub fn read_fundus(filepath: &str, chunk_dict: &ChunkDict) -> Result<RgbImage, Box<dyn Error>> {
+    if !chunk_dict.contains_key("@IMG_FUNDUS") && chunk_dict.contains_key("@IMG_OBS") {
+        return read_obs_image(filepath, chunk_dict);
+    }
+    let raw_image = read_chunk_bytes(filepath, chunk_dict, "@IMG_FUNDUS")?;
+    let codestream = split_j2k_codestreams(&raw_image).into_iter().next().ok_or("No image in @IMG_FUNDUS")?;
+    Ok(decode_j2k_image(codestream, true, false)?.to_rgb8())
+}
+
+// Decode the grayscale (IR) fundus image into memory
+pub fn read_grayscale_fundus(filepath: &str, chunk_dict: &ChunkDict) -> Result<GrayImage, Box<dyn Error>> {
+    let raw_image = read_chunk_bytes(filepath, chunk_dict, "@IMG_TRC_02")?;
+    let codestream = split_j2k_codestreams(&raw_image).into_iter().next().ok_or("No image in @IMG_TRC_02")?;
+    Ok(decode_j2k_image(codestream, false, false)?.to_luma8())
+}
+
+// Scanned region on the fundus photo as [x_start, y_start, x_end, y_end], from @REGIST_INFO
+pub fn read_scan_region(filepath: &str, chunk_dict: &ChunkDict) -> Result<[u32; 4], Box<dyn Error>> {
+    let raw = read_chunk_bytes(filepath, chunk_dict, "@REGIST_INFO")?;
+    let header = RegistInfoHeader::parse(&mut io::Cursor::new(raw))?;
+    Ok(header.bounding_box_fundus)
+}
+
+pub fn read_img_jpeg(filepath: &str, chunk_dict: &ChunkDict, format: Option<ImageFormat>, output_dir: &str, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_JPEG") {
+        let mut raw_image = vec![0; chunk_size as usize];
+        let mut file = File::open(filepath)?;
+        file.seek(SeekFrom::Start(chunk_location))?;
+        file.read_exact(&mut raw_image)?;
+
+        extract_images_from_chunk_parallel(&raw_image, &format!("{}/oct", output_dir), "bscan", format, false, true, slices)?;
+        Ok(())
+    } else {
+        info!("@IMG_JPEG is not in chunk list, skipping.");
+        Err("Chunk @IMG_JPEG not found".into())
+    }
+}
+
+// Decode every B-scan of @IMG_JPEG (or @IMG_SCAN_03 for FDS) into an 8-bit volume with the spacing from @PARAM_SCAN_04
+pub fn read_oct_volume(filepath: &str, chunk_dict: &ChunkDict) -> Result<Volume, Box<dyn Error>> {
+    if !chunk_dict.contains_key("@IMG_JPEG") && chunk_dict.contains_key("@IMG_SCAN_03") {
+        return read_scan_volume(filepath, chunk_dict);
+    }
+    let raw_image = read_chunk_bytes(filepath, chunk_dict, "@IMG_JPEG")?;
+    let slices = split_j2k_codestreams(&raw_image)
+        .par_iter()
+        .map(|codestream| decode_j2k_image(codestream, false, true).map(|img| img.to_luma8()).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let header = ImgJpegHeader::from_reader(&mut io::Cursor::new(&raw_image))?;
+    let width = if header.width > 0 { header.width as usize } else { slices.first().map_or(0, |s| s.width() as usize) };
+    let number_slices = if header.number_slices > 0 { header.number_slices as usize } else { slices.len() };
+    let spacing = read_voxel_spacing(filepath, chunk_dict, width, number_slices)?;
+    Volume::from_slices(&slices, spacing)
+}
+
+// One B-scan, decoding only its own codestream
+pub fn read_oct_slice(filepath: &str, chunk_dict: &ChunkDict, index: usize) -> Result<GrayImage, Box<dyn Error>> {
+    if !chunk_dict.contains_key("@IMG_JPEG") && chunk_dict.contains_key("@IMG_SCAN_03") {
+        return read_scan_slice(filepath, chunk_dict, index);
+    }
+    let raw_image = read_chunk_bytes(filepath, chunk_dict, "@IMG_JPEG")?;
+    let codestreams = split_j2k_codestreams(&raw_image);
+    let codestream = codestreams.get(index).ok_or_else(|| format!("B-scan {} out of range, the volume has {}", index, codestreams.len()))?;
+    Ok(decode_j2k_image(codestream, false, true)?.to_luma8())
+}
+
+pub fn read_voxel_spacing(filepath: &str, chunk_dict: &ChunkDict, width: usize, number_slices: usize) -> Result<VoxelSpacing, Box<dyn Error>> {
+    let raw_param = read_chunk_bytes(filepath, chunk_dict, "@PARAM_SCAN_04")?;
+    let param = ParamScan04Header::from_reader(&mut io::Cursor::new(raw_param))?;
+
+    Ok(VoxelSpacing {
+        x_mm: param.x_dimension_mm / width.max(1) as f64,
+        y_mm: param.y_dimension_mm / number_slices.max(1) as f64,
+        z_mm: param.z_resolution_um / 1000.0,
+    })
+}
+
+pub fn read_grayscale_image(filepath: &str, chunk_dict: &ChunkDict, format: Option<ImageFormat>, output_dir: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(&(chunk_location, chunk_size)) = chunk_dict.get("@IMG_TRC_02") {
+        let mut raw_image = vec![0; chunk_size as usize];
+        let mut file = File::open(filepath)?;
+        file.seek(SeekFrom::Start(chunk_location))?;
+        file.read_exact(&mut raw_image)?;
+
+        extract_images_from_chunk_parallel(&raw_image, &format!("{}/grayscale", output_dir), "grayscale_fundus", format, false, false, SliceRange::ALL)?;
+        Ok(())
+    } else {
+        info!("@IMG_TRC_02 is not in chunk list, skipping.");
+        Err("Chunk @IMG_TRC_02 not found".into())
+    }
+}
+
+pub fn read_thumbnail_image(filepath: &str, chunk_dict: &ChunkDict) -> Result<DynamicImage, Box<dyn Error>> {
+    let &(chunk_location, _chunk_size) = chunk_dict.get("@THUMBNAIL").ok_or("Chunk @THUMBNAIL not found")?;
+    let mut file = File::open(filepath)?;
+    file.seek(SeekFrom::Start(chunk_location))?;
+
+    let header = ThumbnailHeader::from_reader(&mut file)?;
+    Ok(image::load_from_memory_with_format(&header.img, ImageFormat::Bmp)?)
+}
+
+pub fn read_thumbnail(filepath: &str, chunk_dict: &ChunkDict, output_dir: &str) -> Result<(), Box<dyn Error>> {
+    if !chunk_dict.contains_key("@THUMBNAIL") {
+        info!("@THUMBNAIL is not in chunk list, skipping.");
+        return Err("Chunk @THUMBNAIL not found".into());
+    }
+    let image = read_thumbnail_image(filepath, chunk_dict)?;
+    let thumbnail_path = format!("{}/thumbnail/thumbnail.bmp", output_dir);
+    save_image_to_file(&image, &thumbnail_path, Some(ImageFormat::Bmp))?;
+    image_written(&thumbnail_path);
+
+    Ok(())
+}
+
+fn save_j2k_file(j2k_data: &[u8], path: &str) -> io::Result<()> {
+    write_output(path, j2k_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_codestreams_are_reported() {
+        let data: Vec<u8> = (0..3).flat_map(|_| J2K_SOI.iter().copied().chain([0u8; 16])).collect();
+        let output_dir = std::env::temp_dir();
+        let slices = SliceRange { start: 1, end: None };
+        let error = extract_images_from_chunk_parallel(&data, &output_dir.to_string_lossy(), "bscan", Some(ImageFormat::Png), false, false, slices).unwrap_err().to_string();
+        assert!(error.starts_with("Failed to save 2 bscan images"), "{}", error);
+        assert!(error.contains("bscan_1: ") && error.contains("bscan_2: ") && !error.contains("bscan_0"), "{}", error);
+    }
+}
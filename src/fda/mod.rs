@@ -1,4 +1,9 @@
-pub mod headers;
-pub mod image_processing;
-pub mod parser;
-pub mod utils;
\ No newline at end of file
+pub mod anonymize;
+pub mod fds;
+pub mod headers;
+pub mod image_processing;
+pub mod parser;
+pub mod reader;
+pub mod utils;
+pub mod version;
+pub mod writer;
@@ -1,214 +1,492 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::io::{Read, Seek};
-
-use crate::fda::headers::*;
-
-pub fn parse_chunk<R: Read + Seek>(reader: &mut R, chunk_name: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
-    let mut chunk_info = HashMap::new();
-    let chunk_name_clean = chunk_name.trim_start_matches('@').to_lowercase();
-
-    match chunk_name_clean.as_str() {
-        "patient_info_02" => {
-            let patient_info = PatientInfo02Header::parse(reader)?;
-            chunk_info.insert("patient_id".to_string(), patient_info.patient_id);
-            chunk_info.insert("given_name".to_string(), patient_info.given_name);
-            chunk_info.insert("surname".to_string(), patient_info.surname);
-            chunk_info.insert("birth_date_valid".to_string(), patient_info.birth_date_valid.to_string());
-            chunk_info.insert("birth_year".to_string(), patient_info.birth_year.to_string());
-            chunk_info.insert("birth_month".to_string(), patient_info.birth_month.to_string());
-            chunk_info.insert("birth_day".to_string(), patient_info.birth_day.to_string());
-            chunk_info.insert("extra_data".to_string(), format!("{:?}", patient_info.extra_data));
-        }
-        "header" => {
-            let header = Header::parse(reader)?;
-            chunk_info.insert("file_code".to_string(), header.file_code);
-            chunk_info.insert("file_type".to_string(), header.file_type);
-            chunk_info.insert("major_ver".to_string(), header.major_ver.to_string());
-            chunk_info.insert("minor_ver".to_string(), header.minor_ver.to_string());
-        }
-        "capture_info_02" => {
-            let capture_info = CaptureInfo02Header::parse(reader)?;
-            chunk_info.insert("eye".to_string(), capture_info.eye.to_string());
-            chunk_info.insert("scan_mode".to_string(), capture_info.scan_mode.to_string());
-            chunk_info.insert("session_id".to_string(), capture_info.session_id.to_string());
-            chunk_info.insert("label".to_string(), capture_info.label);
-            chunk_info.insert("cap_date".to_string(), format!("{:?}", capture_info.cap_date));
-        }
-        "hw_info_03" => {
-            let hw_info = HwInfo03Header::parse(reader)?;
-            chunk_info.insert("model_name".to_string(), hw_info.model_name);
-            chunk_info.insert("serial_number".to_string(), hw_info.serial_number);
-            chunk_info.insert("spect_sn".to_string(), hw_info.spect_sn);
-            chunk_info.insert("rom_ver".to_string(), hw_info.rom_ver);
-            chunk_info.insert("unknown".to_string(), hw_info.unknown);
-            chunk_info.insert("eq_calib_year".to_string(), hw_info.eq_calib_year.to_string());
-            chunk_info.insert("eq_calib_month".to_string(), hw_info.eq_calib_month.to_string());
-            chunk_info.insert("eq_calib_day".to_string(), hw_info.eq_calib_day.to_string());
-            chunk_info.insert("eq_calib_hour".to_string(), hw_info.eq_calib_hour.to_string());
-            chunk_info.insert("eq_calib_minute".to_string(), hw_info.eq_calib_minute.to_string());
-            chunk_info.insert("spect_calib_year".to_string(), hw_info.spect_calib_year.to_string());
-            chunk_info.insert("spect_calib_month".to_string(), hw_info.spect_calib_month.to_string());
-            chunk_info.insert("spect_calib_day".to_string(), hw_info.spect_calib_day.to_string());
-            chunk_info.insert("spect_calib_hour".to_string(), hw_info.spect_calib_hour.to_string());
-            chunk_info.insert("spect_calib_minute".to_string(), hw_info.spect_calib_minute.to_string());
-        }
-        "patient_info_03" => {
-            let patient_info = PatientInfo03Header::from_reader(reader)?;
-            chunk_info.insert("patient_id".to_string(), patient_info.patient_id);
-            chunk_info.insert("surname".to_string(), patient_info.surname);
-            chunk_info.insert("given_name".to_string(), patient_info.given_name);
-            chunk_info.insert("sex".to_string(), patient_info.sex);
-            chunk_info.insert("birth_date".to_string(), patient_info.birth_date);
-        }
-        "img_jpeg" => {
-            let header = ImgJpegHeader::from_reader(reader)?;
-            chunk_info.insert("scan_mode".to_string(), header.scan_mode.to_string());
-            chunk_info.insert("unknown1".to_string(), header.unknown1.to_string());
-            chunk_info.insert("unknown2".to_string(), header.unknown2.to_string());
-            chunk_info.insert("width".to_string(), header.width.to_string());
-            chunk_info.insert("height".to_string(), header.height.to_string());
-            chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
-            chunk_info.insert("unknown3".to_string(), header.unknown3.to_string());
-        }
-        "img_mot_comp_03" => {
-            let header = ImgMotComp03Header::from_reader(reader)?;
-            chunk_info.insert("scan_mode".to_string(), header.scan_mode.to_string());
-            chunk_info.insert("width".to_string(), header.width.to_string());
-            chunk_info.insert("height".to_string(), header.height.to_string());
-            chunk_info.insert("bits_per_pixel".to_string(), header.bits_per_pixel.to_string());
-            chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
-            chunk_info.insert("format".to_string(), header.format.to_string());
-            chunk_info.insert("size".to_string(), header.size.to_string());
-        }
-        "fda_file_info" => {
-            let header = FdaFileInfoHeader::from_reader(reader)?;
-            chunk_info.insert("0x2".to_string(), header.field_0x2.to_string());
-            chunk_info.insert("0x3e8".to_string(), header.field_0x3e8.to_string());
-            chunk_info.insert("version".to_string(), header.version);
-        }
-        "contour_info" => {
-            let header = ContourInfoHeader::from_reader(reader)?;
-            chunk_info.insert("id".to_string(), header.id);
-            chunk_info.insert("method".to_string(), header.method.to_string());
-            chunk_info.insert("format".to_string(), header.format.to_string());
-            chunk_info.insert("width".to_string(), header.width.to_string());
-            chunk_info.insert("height".to_string(), header.height.to_string());
-            chunk_info.insert("size".to_string(), header.size.to_string());
-        }
-        "align_info" => {
-            println!("Parsing align_info");
-            let header = AlignInfoHeader::from_reader(reader)?;
-            println!("Parsed align_info header: {:?}", header);
-
-            chunk_info.insert("unlabeled_1".to_string(), header.unlabeled_1.to_string());
-            chunk_info.insert("unlabeled_2".to_string(), header.unlabeled_2.to_string());
-            chunk_info.insert("w".to_string(), header.w.to_string());
-            chunk_info.insert("n_size".to_string(), header.n_size.to_string());
-
-            if let Some(aligndata) = header.aligndata {
-                chunk_info.insert("aligndata".to_string(), format!("{:?}", aligndata));
-            } else {
-                chunk_info.insert("aligndata".to_string(), "None".to_string());
-            }
-
-            chunk_info.insert("keyframe_1".to_string(), header.keyframe_1.to_string());
-            chunk_info.insert("keyframe_2".to_string(), header.keyframe_2.to_string());
-            chunk_info.insert("unlabeled_3".to_string(), header.unlabeled_3.to_string());
-            chunk_info.insert("unlabeled_4".to_string(), header.unlabeled_4.to_string());
-        }
-        "param_scan_04" => {
-            let header = ParamScan04Header::from_reader(reader)?;
-            chunk_info.insert("fixation".to_string(), header.fixation.to_string());
-            chunk_info.insert("mirror_pos".to_string(), header.mirror_pos.to_string());
-            chunk_info.insert("polar".to_string(), header.polar.to_string());
-            chunk_info.insert("x_dimension_mm".to_string(), header.x_dimension_mm.to_string());
-            chunk_info.insert("y_dimension_mm".to_string(), header.y_dimension_mm.to_string());
-            chunk_info.insert("z_resolution_um".to_string(), header.z_resolution_um.to_string());
-            chunk_info.insert("comp_eff_2".to_string(), header.comp_eff_2.to_string());
-            chunk_info.insert("comp_eff_3".to_string(), header.comp_eff_3.to_string());
-            chunk_info.insert("base_pos".to_string(), header.base_pos.to_string());
-            chunk_info.insert("used_calib_data".to_string(), header.used_calib_data.to_string());
-        }
-        "result_cornea_curve" => {
-            let header = ResultCorneaCurveHeader::from_reader(reader)?;
-            chunk_info.insert("id".to_string(), format!("{:?}", header.id));
-            chunk_info.insert("width".to_string(), header.width.to_string());
-            chunk_info.insert("height".to_string(), header.height.to_string());
-            chunk_info.insert("version".to_string(), format!("{:?}", header.version));
-        }
-        "result_cornea_thickness" => {
-            let header = ResultCorneaThicknessHeader::from_reader(reader)?;
-            chunk_info.insert("version".to_string(), format!("{:?}", header.version));
-            chunk_info.insert("id".to_string(), format!("{:?}", header.id));
-            chunk_info.insert("width".to_string(), header.width.to_string());
-            chunk_info.insert("height".to_string(), header.height.to_string());
-        }
-        "main_module_info" => {
-            let header = MainModuleInfoHeader::from_reader(reader)?;
-            chunk_info.insert("software_name".to_string(), header.software_name);
-            chunk_info.insert("file_version_1".to_string(), header.file_version_1.to_string());
-            chunk_info.insert("file_version_2".to_string(), header.file_version_2.to_string());
-            chunk_info.insert("file_version_3".to_string(), header.file_version_3.to_string());
-            chunk_info.insert("file_version_4".to_string(), header.file_version_4.to_string());
-            chunk_info.insert("string".to_string(), header.string);
-        }
-        "contour_mask_info" => {
-            let header = ContourMaskInfoHeader::parse(reader)?;
-            chunk_info.insert("empty".to_string(), header.empty.to_string());
-        }
-        "topqext_info" => {
-            let header = TopQExtInfoHeader::parse(reader)?;
-            chunk_info.insert("empty".to_string(), header.empty.to_string());
-        }
-        "effective_scan_range" => {
-            let header = EffectiveScanRangeHeader::parse(reader)?;
-            chunk_info.insert("fundus_bounding_box".to_string(), format!("{:?}", header.fundus_bounding_box));
-            chunk_info.insert("trc_bounding_box".to_string(), format!("{:?}", header.trc_bounding_box));
-        }
-        "fast_q2_info" => {
-            let header = FastQ2InfoHeader::parse(reader)?;
-            chunk_info.insert("various_quality_statistics".to_string(), format!("{:?}", header.various_quality_statistics));
-        }
-        "param_obs_02" => {
-            let header = ParamObs02Header::parse(reader)?;
-            chunk_info.insert("values".to_string(), format!("{:?}", header.values));
-            chunk_info.insert("camera_model".to_string(), header.camera_model);
-            chunk_info.insert("jpeg_quality".to_string(), header.jpeg_quality);
-            chunk_info.insert("color_temperature".to_string(), header.color_temperature);
-            chunk_info.insert("color_temperature_value".to_string(), header.color_temperature_value.to_string());
-        }
-        "regist_info" => {
-            let header = RegistInfoHeader::parse(reader)?;
-            chunk_info.insert("u8_value".to_string(), header.u8_value.to_string());
-            chunk_info.insert("u32_values_1".to_string(), format!("{:?}", header.u32_values_1));
-            chunk_info.insert("bounding_box_fundus".to_string(), format!("{:?}", header.bounding_box_fundus));
-            chunk_info.insert("u8_string".to_string(), header.u8_string);
-            chunk_info.insert("bounding_box_trc".to_string(), format!("{:?}", header.bounding_box_trc));
-            chunk_info.insert("f64_values".to_string(), format!("{:?}", header.f64_values));
-            chunk_info.insert("zeros".to_string(), format!("{:?}", header.zeros));
-        }
-        "gla_littmann_01" => {
-            let header = GlaLittmann01Header::parse(reader)?;
-            chunk_info.insert("u32_values".to_string(), format!("{:?}", header.u32_values));
-            chunk_info.insert("u32_value_1".to_string(), header.u32_value_1.to_string());
-            chunk_info.insert("u32_value_2".to_string(), header.u32_value_2.to_string());
-        }
-        "img_en_face" => {
-            let header = ImgEnFaceHeader::parse(reader)?;
-            chunk_info.insert("empty".to_string(), header.empty.to_string());
-        }
-        "report_info" => {
-            let header = ReportInfoHeader::parse(reader)?;
-            chunk_info.insert("zeros".to_string(), format!("{:?}", header.zeros));
-        }
-        "thumbnail" =>{
-            let header = ThumbnailHeader::from_reader(reader)?;
-            chunk_info.insert("size".to_string(), format!("{:?}", header.size));
-        }
-        _ => {
-            println!("Unhandled chunk: {}", chunk_name_clean);
-        }
-    }
-    Ok(chunk_info)
-}
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+
+use crate::fda::headers::*;
+use crate::fda::version::FdaVersion;
+
+// Field name -> value for one parsed chunk
+pub type ChunkInfo = HashMap<String, String>;
+pub type ChunkParseFn = fn(&[u8], &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>>;
+
+// Bytes besides the strings in the chunks whose string widths depend on the version
+pub const PATIENT_INFO_02_FIXED: usize = 519;
+pub const PATIENT_INFO_03_FIXED: usize = 7;
+pub const HW_INFO_03_FIXED: usize = 20;
+
+pub trait ChunkParser: Send + Sync {
+    fn parse(&self, payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>>;
+}
+
+impl<F> ChunkParser for F
+where
+    F: Fn(&[u8], &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> + Send + Sync,
+{
+    fn parse(&self, payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+        self(payload, version)
+    }
+}
+
+// Chunk names are matched without the leading '@' and ignoring case
+fn normalize(chunk_name: &str) -> String {
+    chunk_name.trim_start_matches('@').to_lowercase()
+}
+
+// Chunk name -> parser. `default()` holds the built-in parsers; `register` adds or replaces one.
+#[derive(Clone)]
+pub struct ChunkParserRegistry {
+    parsers: HashMap<String, Arc<dyn ChunkParser>>,
+}
+
+impl ChunkParserRegistry {
+    pub fn empty() -> Self {
+        ChunkParserRegistry { parsers: HashMap::new() }
+    }
+
+    // Returns the parser previously registered for this chunk, if any
+    pub fn register<P: ChunkParser + 'static>(&mut self, chunk_name: &str, parser: P) -> Option<Arc<dyn ChunkParser>> {
+        self.parsers.insert(normalize(chunk_name), Arc::new(parser))
+    }
+
+    pub fn contains(&self, chunk_name: &str) -> bool {
+        self.parsers.contains_key(&normalize(chunk_name))
+    }
+
+    pub fn chunk_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.parsers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn parse(&self, chunk_name: &str, payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+        match self.parsers.get(&normalize(chunk_name)) {
+            Some(parser) => parser.parse(payload, version),
+            None => Err(format!("No parser registered for chunk {}", chunk_name).into()),
+        }
+    }
+}
+
+impl Default for ChunkParserRegistry {
+    fn default() -> Self {
+        let mut registry = ChunkParserRegistry::empty();
+        for (chunk_name, parser) in BUILTIN_PARSERS {
+            registry.register(chunk_name, parser);
+        }
+        registry
+    }
+}
+
+// String width for a version-dependent chunk, noting in the chunk info when it had to be inferred
+fn string_len(chunk_info: &mut ChunkInfo, version: &FdaVersion, payload_len: usize, layout_len: usize, count: usize, fixed: usize) -> usize {
+    let (len, inferred) = version.string_len(layout_len, count, fixed, payload_len);
+    if inferred {
+        chunk_info.insert("inferred_string_len".to_string(), len.to_string());
+    }
+    len
+}
+
+fn parse_patient_info_02(payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let len = string_len(&mut chunk_info, version, payload.len(), version.layout.patient_string_len, 3, PATIENT_INFO_02_FIXED);
+    let patient_info = PatientInfo02Header::parse(reader, len)?;
+    chunk_info.insert("patient_id".to_string(), patient_info.patient_id);
+    chunk_info.insert("given_name".to_string(), patient_info.given_name);
+    chunk_info.insert("surname".to_string(), patient_info.surname);
+    chunk_info.insert("birth_date_valid".to_string(), patient_info.birth_date_valid.to_string());
+    chunk_info.insert("birth_year".to_string(), patient_info.birth_year.to_string());
+    chunk_info.insert("birth_month".to_string(), patient_info.birth_month.to_string());
+    chunk_info.insert("birth_day".to_string(), patient_info.birth_day.to_string());
+    chunk_info.insert("extra_data".to_string(), format!("{:?}", patient_info.extra_data));
+    Ok(chunk_info)
+}
+
+fn parse_header(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = Header::parse(reader)?;
+    chunk_info.insert("file_code".to_string(), header.file_code);
+    chunk_info.insert("file_type".to_string(), header.file_type);
+    chunk_info.insert("major_ver".to_string(), header.major_ver.to_string());
+    chunk_info.insert("minor_ver".to_string(), header.minor_ver.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_capture_info_02(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let capture_info = CaptureInfo02Header::parse(reader)?;
+    chunk_info.insert("eye".to_string(), capture_info.eye.to_string());
+    chunk_info.insert("scan_mode".to_string(), capture_info.scan_mode.to_string());
+    chunk_info.insert("session_id".to_string(), capture_info.session_id.to_string());
+    chunk_info.insert("label".to_string(), capture_info.label);
+    chunk_info.insert("cap_date".to_string(), format!("{:?}", capture_info.cap_date));
+    Ok(chunk_info)
+}
+
+fn parse_hw_info_03(payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let len = string_len(&mut chunk_info, version, payload.len(), version.layout.hw_string_len, 5, HW_INFO_03_FIXED);
+    let hw_info = HwInfo03Header::parse(reader, len)?;
+    chunk_info.insert("model_name".to_string(), hw_info.model_name);
+    chunk_info.insert("serial_number".to_string(), hw_info.serial_number);
+    chunk_info.insert("spect_sn".to_string(), hw_info.spect_sn);
+    chunk_info.insert("rom_ver".to_string(), hw_info.rom_ver);
+    chunk_info.insert("unknown".to_string(), hw_info.unknown);
+    chunk_info.insert("eq_calib_year".to_string(), hw_info.eq_calib_year.to_string());
+    chunk_info.insert("eq_calib_month".to_string(), hw_info.eq_calib_month.to_string());
+    chunk_info.insert("eq_calib_day".to_string(), hw_info.eq_calib_day.to_string());
+    chunk_info.insert("eq_calib_hour".to_string(), hw_info.eq_calib_hour.to_string());
+    chunk_info.insert("eq_calib_minute".to_string(), hw_info.eq_calib_minute.to_string());
+    chunk_info.insert("spect_calib_year".to_string(), hw_info.spect_calib_year.to_string());
+    chunk_info.insert("spect_calib_month".to_string(), hw_info.spect_calib_month.to_string());
+    chunk_info.insert("spect_calib_day".to_string(), hw_info.spect_calib_day.to_string());
+    chunk_info.insert("spect_calib_hour".to_string(), hw_info.spect_calib_hour.to_string());
+    chunk_info.insert("spect_calib_minute".to_string(), hw_info.spect_calib_minute.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_patient_info_03(payload: &[u8], version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let len = string_len(&mut chunk_info, version, payload.len(), version.layout.patient_string_len, 3, PATIENT_INFO_03_FIXED);
+    let patient_info = PatientInfo03Header::from_reader(reader, len)?;
+    chunk_info.insert("patient_id".to_string(), patient_info.patient_id);
+    chunk_info.insert("surname".to_string(), patient_info.surname);
+    chunk_info.insert("given_name".to_string(), patient_info.given_name);
+    chunk_info.insert("sex".to_string(), patient_info.sex);
+    chunk_info.insert("birth_date".to_string(), patient_info.birth_date);
+    Ok(chunk_info)
+}
+
+fn parse_img_jpeg(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ImgJpegHeader::from_reader(reader)?;
+    chunk_info.insert("scan_mode".to_string(), header.scan_mode.to_string());
+    chunk_info.insert("unknown1".to_string(), header.unknown1.to_string());
+    chunk_info.insert("unknown2".to_string(), header.unknown2.to_string());
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
+    chunk_info.insert("unknown3".to_string(), header.unknown3.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_img_scan_03(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ImgScan03Header::from_reader(reader)?;
+    chunk_info.insert("unknown1".to_string(), header.unknown1.to_string());
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("bits_per_pixel".to_string(), header.bits_per_pixel.to_string());
+    chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
+    chunk_info.insert("unknown2".to_string(), header.unknown2.to_string());
+    chunk_info.insert("size".to_string(), header.size.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_img_obs(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ImgObsHeader::from_reader(reader)?;
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("bits_per_pixel".to_string(), header.bits_per_pixel.to_string());
+    chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
+    chunk_info.insert("unknown".to_string(), header.unknown);
+    chunk_info.insert("size".to_string(), header.size.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_img_mot_comp_03(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ImgMotComp03Header::from_reader(reader)?;
+    chunk_info.insert("scan_mode".to_string(), header.scan_mode.to_string());
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("bits_per_pixel".to_string(), header.bits_per_pixel.to_string());
+    chunk_info.insert("number_slices".to_string(), header.number_slices.to_string());
+    chunk_info.insert("format".to_string(), header.format.to_string());
+    chunk_info.insert("size".to_string(), header.size.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_fda_file_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = FdaFileInfoHeader::from_reader(reader)?;
+    chunk_info.insert("0x2".to_string(), header.field_0x2.to_string());
+    chunk_info.insert("0x3e8".to_string(), header.field_0x3e8.to_string());
+    chunk_info.insert("version".to_string(), header.version);
+    Ok(chunk_info)
+}
+
+fn parse_contour_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ContourInfoHeader::from_reader(reader)?;
+    chunk_info.insert("id".to_string(), header.id);
+    chunk_info.insert("method".to_string(), header.method.to_string());
+    chunk_info.insert("format".to_string(), header.format.to_string());
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("size".to_string(), header.size.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_align_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = AlignInfoHeader::from_reader(reader)?;
+    log::debug!("Parsed align_info header: {:?}", header);
+
+    chunk_info.insert("unlabeled_1".to_string(), header.unlabeled_1.to_string());
+    chunk_info.insert("unlabeled_2".to_string(), header.unlabeled_2.to_string());
+    chunk_info.insert("w".to_string(), header.w.to_string());
+    chunk_info.insert("n_size".to_string(), header.n_size.to_string());
+
+    if let Some(aligndata) = header.aligndata {
+        chunk_info.insert("aligndata".to_string(), format!("{:?}", aligndata));
+    } else {
+        chunk_info.insert("aligndata".to_string(), "None".to_string());
+    }
+
+    chunk_info.insert("keyframe_1".to_string(), header.keyframe_1.to_string());
+    chunk_info.insert("keyframe_2".to_string(), header.keyframe_2.to_string());
+    chunk_info.insert("unlabeled_3".to_string(), header.unlabeled_3.to_string());
+    chunk_info.insert("unlabeled_4".to_string(), header.unlabeled_4.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_param_scan_04(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ParamScan04Header::from_reader(reader)?;
+    chunk_info.insert("fixation".to_string(), header.fixation.to_string());
+    chunk_info.insert("mirror_pos".to_string(), header.mirror_pos.to_string());
+    chunk_info.insert("polar".to_string(), header.polar.to_string());
+    chunk_info.insert("x_dimension_mm".to_string(), header.x_dimension_mm.to_string());
+    chunk_info.insert("y_dimension_mm".to_string(), header.y_dimension_mm.to_string());
+    chunk_info.insert("z_resolution_um".to_string(), header.z_resolution_um.to_string());
+    chunk_info.insert("comp_eff_2".to_string(), header.comp_eff_2.to_string());
+    chunk_info.insert("comp_eff_3".to_string(), header.comp_eff_3.to_string());
+    chunk_info.insert("base_pos".to_string(), header.base_pos.to_string());
+    chunk_info.insert("used_calib_data".to_string(), header.used_calib_data.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_result_cornea_curve(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ResultCorneaCurveHeader::from_reader(reader)?;
+    chunk_info.insert("id".to_string(), format!("{:?}", header.id));
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    chunk_info.insert("version".to_string(), format!("{:?}", header.version));
+    Ok(chunk_info)
+}
+
+fn parse_result_cornea_thickness(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ResultCorneaThicknessHeader::from_reader(reader)?;
+    chunk_info.insert("version".to_string(), format!("{:?}", header.version));
+    chunk_info.insert("id".to_string(), format!("{:?}", header.id));
+    chunk_info.insert("width".to_string(), header.width.to_string());
+    chunk_info.insert("height".to_string(), header.height.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_main_module_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = MainModuleInfoHeader::from_reader(reader)?;
+    chunk_info.insert("software_name".to_string(), header.software_name);
+    chunk_info.insert("file_version_1".to_string(), header.file_version_1.to_string());
+    chunk_info.insert("file_version_2".to_string(), header.file_version_2.to_string());
+    chunk_info.insert("file_version_3".to_string(), header.file_version_3.to_string());
+    chunk_info.insert("file_version_4".to_string(), header.file_version_4.to_string());
+    chunk_info.insert("string".to_string(), header.string);
+    Ok(chunk_info)
+}
+
+fn parse_contour_mask_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ContourMaskInfoHeader::parse(reader)?;
+    chunk_info.insert("empty".to_string(), header.empty.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_topqext_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = TopQExtInfoHeader::parse(reader)?;
+    chunk_info.insert("empty".to_string(), header.empty.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_effective_scan_range(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = EffectiveScanRangeHeader::parse(reader)?;
+    chunk_info.insert("fundus_bounding_box".to_string(), format!("{:?}", header.fundus_bounding_box));
+    chunk_info.insert("trc_bounding_box".to_string(), format!("{:?}", header.trc_bounding_box));
+    Ok(chunk_info)
+}
+
+fn parse_fast_q2_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = FastQ2InfoHeader::parse(reader)?;
+    chunk_info.insert("various_quality_statistics".to_string(), format!("{:?}", header.various_quality_statistics));
+    Ok(chunk_info)
+}
+
+fn parse_param_obs_02(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ParamObs02Header::parse(reader)?;
+    chunk_info.insert("values".to_string(), format!("{:?}", header.values));
+    chunk_info.insert("camera_model".to_string(), header.camera_model);
+    chunk_info.insert("jpeg_quality".to_string(), header.jpeg_quality);
+    chunk_info.insert("color_temperature".to_string(), header.color_temperature);
+    chunk_info.insert("color_temperature_value".to_string(), header.color_temperature_value.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_regist_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = RegistInfoHeader::parse(reader)?;
+    chunk_info.insert("u8_value".to_string(), header.u8_value.to_string());
+    chunk_info.insert("u32_values_1".to_string(), format!("{:?}", header.u32_values_1));
+    chunk_info.insert("bounding_box_fundus".to_string(), format!("{:?}", header.bounding_box_fundus));
+    chunk_info.insert("u8_string".to_string(), header.u8_string);
+    chunk_info.insert("bounding_box_trc".to_string(), format!("{:?}", header.bounding_box_trc));
+    chunk_info.insert("f64_values".to_string(), format!("{:?}", header.f64_values));
+    chunk_info.insert("zeros".to_string(), format!("{:?}", header.zeros));
+    Ok(chunk_info)
+}
+
+fn parse_gla_littmann_01(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = GlaLittmann01Header::parse(reader)?;
+    chunk_info.insert("u32_values".to_string(), format!("{:?}", header.u32_values));
+    chunk_info.insert("u32_value_1".to_string(), header.u32_value_1.to_string());
+    chunk_info.insert("u32_value_2".to_string(), header.u32_value_2.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_img_en_face(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ImgEnFaceHeader::parse(reader)?;
+    chunk_info.insert("empty".to_string(), header.empty.to_string());
+    Ok(chunk_info)
+}
+
+fn parse_report_info(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ReportInfoHeader::parse(reader)?;
+    chunk_info.insert("zeros".to_string(), format!("{:?}", header.zeros));
+    Ok(chunk_info)
+}
+
+fn parse_thumbnail(payload: &[u8], _version: &FdaVersion) -> Result<ChunkInfo, Box<dyn Error>> {
+    let reader = &mut io::Cursor::new(payload);
+    let mut chunk_info = HashMap::new();
+    let header = ThumbnailHeader::from_reader(reader)?;
+    chunk_info.insert("size".to_string(), format!("{:?}", header.size));
+    Ok(chunk_info)
+}
+
+const BUILTIN_PARSERS: [(&str, ChunkParseFn); 26] = [
+    ("patient_info_02", parse_patient_info_02),
+    ("header", parse_header),
+    ("capture_info_02", parse_capture_info_02),
+    ("hw_info_03", parse_hw_info_03),
+    ("patient_info_03", parse_patient_info_03),
+    ("img_jpeg", parse_img_jpeg),
+    ("img_scan_03", parse_img_scan_03),
+    ("img_obs", parse_img_obs),
+    ("img_mot_comp_03", parse_img_mot_comp_03),
+    ("fda_file_info", parse_fda_file_info),
+    ("contour_info", parse_contour_info),
+    ("align_info", parse_align_info),
+    ("param_scan_04", parse_param_scan_04),
+    ("result_cornea_curve", parse_result_cornea_curve),
+    ("result_cornea_thickness", parse_result_cornea_thickness),
+    ("main_module_info", parse_main_module_info),
+    ("contour_mask_info", parse_contour_mask_info),
+    ("topqext_info", parse_topqext_info),
+    ("effective_scan_range", parse_effective_scan_range),
+    ("fast_q2_info", parse_fast_q2_info),
+    ("param_obs_02", parse_param_obs_02),
+    ("regist_info", parse_regist_info),
+    ("gla_littmann_01", parse_gla_littmann_01),
+    ("img_en_face", parse_img_en_face),
+    ("report_info", parse_report_info),
+    ("thumbnail", parse_thumbnail),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fda::version::STANDARD_LAYOUT;
+
+    fn version() -> FdaVersion {
+        FdaVersion { major: 2, minor: 1, layout: STANDARD_LAYOUT, known: true }
+    }
+
+    fn padded(value: &str, len: usize) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    #[test]
+    fn chunk_names_ignore_the_prefix_and_case() {
+        let registry = ChunkParserRegistry::default();
+        assert_eq!(registry.chunk_names().len(), BUILTIN_PARSERS.len());
+        assert!(registry.contains("@PATIENT_INFO_03"));
+        assert!(registry.contains("patient_info_03"));
+        assert!(!registry.contains("@IMG_TRC_02"));
+        assert!(registry.parse("@IMG_TRC_02", &[], &version()).is_err());
+    }
+
+    #[test]
+    fn registered_parsers_replace_the_builtin_ones() {
+        let mut registry = ChunkParserRegistry::default();
+        let previous = registry.register("@HEADER", |payload: &[u8], _: &FdaVersion| -> Result<ChunkInfo, Box<dyn Error>> {
+            Ok(ChunkInfo::from([("length".to_string(), payload.len().to_string())]))
+        });
+        assert!(previous.is_some());
+        let info = registry.parse("@HEADER", &[0; 15], &version()).unwrap();
+        assert_eq!(info, ChunkInfo::from([("length".to_string(), "15".to_string())]));
+
+        // New chunks can be added alongside the built-in ones
+        assert!(registry.register("@IMG_TRC_02", |_: &[u8], _: &FdaVersion| -> Result<ChunkInfo, Box<dyn Error>> { Ok(ChunkInfo::new()) }).is_none());
+        assert!(registry.contains("@IMG_TRC_02"));
+        assert!(ChunkParserRegistry::empty().chunk_names().is_empty());
+    }
+
+    #[test]
+    fn string_width_is_inferred_from_the_payload() {
+        let registry = ChunkParserRegistry::default();
+        let mut payload = [padded("ID1", 16), padded("Jane", 16), padded("Doe", 16)].concat();
+        payload.extend([2, 0xB2, 0x07, 5, 0, 17, 0]);
+        let info = registry.parse("@PATIENT_INFO_03", &payload, &version()).unwrap();
+        assert_eq!(info["patient_id"], "ID1");
+        assert_eq!(info["sex"], "F");
+        assert_eq!(info["birth_date"], "1970, 5, 17");
+        assert_eq!(info["inferred_string_len"], "16");
+    }
+}
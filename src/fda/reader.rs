@@ -0,0 +1,95 @@
+use image::DynamicImage;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+
+use crate::fda::headers::{ImgJpegHeader, ImgScan03Header};
+use crate::fda::image_processing::{read_fundus, read_grayscale_fundus, read_oct_slice, read_oct_volume, read_thumbnail_image};
+use crate::fda::parser::ChunkParserRegistry;
+use crate::fda::utils::{get_list_of_file_chunks, read_all_metadata, ChunkDict, TopconFormat};
+use crate::formats::{bscan_entries, ImageEntry, ImageKind, Metadata, OctReader};
+use crate::volume::Volume;
+
+// Number of B-scans announced by the image chunk header, without decoding them
+pub fn number_slices(filepath: &str, chunk_dict: &ChunkDict, format: TopconFormat) -> Result<usize, Box<dyn Error>> {
+    let chunk_name = match format {
+        TopconFormat::Fda => "@IMG_JPEG",
+        TopconFormat::Fds => "@IMG_SCAN_03",
+    };
+    let Some(&(chunk_location, _)) = chunk_dict.get(chunk_name) else {
+        return Ok(0);
+    };
+    let mut file = File::open(filepath)?;
+    file.seek(SeekFrom::Start(chunk_location))?;
+    let number_slices = match format {
+        TopconFormat::Fda => ImgJpegHeader::from_reader(&mut file)?.number_slices,
+        TopconFormat::Fds => ImgScan03Header::from_reader(&mut file)?.number_slices,
+    };
+    Ok(number_slices as usize)
+}
+
+pub struct TopconReader {
+    pub filepath: String,
+    pub format: TopconFormat,
+    pub chunk_dict: ChunkDict,
+    pub registry: ChunkParserRegistry,
+}
+
+impl TopconReader {
+    // Like `open`, with extra or replacement chunk parsers for the metadata
+    pub fn with_registry(filepath: &str, registry: ChunkParserRegistry) -> Result<Self, Box<dyn Error>> {
+        let (chunk_dict, header) = get_list_of_file_chunks(filepath, false)?;
+        let format = TopconFormat::from_header(&header)?;
+        Ok(TopconReader { filepath: filepath.to_string(), format, chunk_dict, registry })
+    }
+
+    fn number_slices(&self) -> Result<usize, Box<dyn Error>> {
+        number_slices(&self.filepath, &self.chunk_dict, self.format)
+    }
+
+    fn has_any(&self, chunk_names: &[&str]) -> bool {
+        chunk_names.iter().any(|name| self.chunk_dict.contains_key(*name))
+    }
+}
+
+impl OctReader for TopconReader {
+    fn open(filepath: &str) -> Result<Self, Box<dyn Error>> {
+        TopconReader::with_registry(filepath, ChunkParserRegistry::default())
+    }
+
+    fn format_name(&self) -> &'static str {
+        self.format.name()
+    }
+
+    fn metadata(&self) -> Result<Metadata, Box<dyn Error>> {
+        Ok(read_all_metadata(&self.filepath, &self.chunk_dict, &self.registry, false)?)
+    }
+
+    fn list_images(&self) -> Result<Vec<ImageEntry>, Box<dyn Error>> {
+        let mut images = bscan_entries(self.number_slices()?);
+        let single_images = [
+            (ImageKind::Fundus, "fundus_0", &["@IMG_FUNDUS", "@IMG_OBS"][..]),
+            (ImageKind::GrayscaleFundus, "grayscale_fundus_0", &["@IMG_TRC_02"][..]),
+            (ImageKind::Thumbnail, "thumbnail", &["@THUMBNAIL"][..]),
+        ];
+        for (kind, name, chunk_names) in single_images {
+            if self.has_any(chunk_names) {
+                images.push(ImageEntry { kind, name: name.to_string(), index: 0 });
+            }
+        }
+        Ok(images)
+    }
+
+    fn read_image(&self, entry: &ImageEntry) -> Result<DynamicImage, Box<dyn Error>> {
+        match entry.kind {
+            ImageKind::Bscan => Ok(DynamicImage::ImageLuma8(read_oct_slice(&self.filepath, &self.chunk_dict, entry.index)?)),
+            ImageKind::Fundus => Ok(DynamicImage::ImageRgb8(read_fundus(&self.filepath, &self.chunk_dict)?)),
+            ImageKind::GrayscaleFundus => Ok(DynamicImage::ImageLuma8(read_grayscale_fundus(&self.filepath, &self.chunk_dict)?)),
+            ImageKind::Thumbnail => read_thumbnail_image(&self.filepath, &self.chunk_dict),
+        }
+    }
+
+    fn read_volume(&self) -> Result<Volume, Box<dyn Error>> {
+        read_oct_volume(&self.filepath, &self.chunk_dict)
+    }
+}
@@ -1,98 +1,141 @@
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::error::Error;
-use crate::fda::headers::Header;
-use crate::fda::parser::parse_chunk; 
-
-pub fn get_list_of_file_chunks(filepath: &str, printing: bool) -> io::Result<(HashMap<String, (u64, u32)>, Header)> {
-    let mut chunk_dict: HashMap<String, (u64, u32)> = HashMap::new();
-    let mut file = File::open(filepath)?;
-
-    let header = Header::parse(&mut file)?;
-
-    let mut eof = false;
-    while !eof {
-        let chunk_name_size = file.read_u8()? as usize;
-        if chunk_name_size == 0 {
-            eof = true;
-        } else {
-            let mut chunk_name = vec![0; chunk_name_size];
-            file.read_exact(&mut chunk_name)?;
-            let chunk_name = String::from_utf8(chunk_name).unwrap();
-            let chunk_size = file.read_u32::<LittleEndian>()?;
-            let chunk_location = file.seek(SeekFrom::Current(0))?;
-            file.seek(SeekFrom::Current(chunk_size as i64))?;
-
-            if !chunk_dict.contains_key(&chunk_name) {
-                chunk_dict.insert(chunk_name, (chunk_location, chunk_size));
-            }
-        }
-    }
-
-    if printing {
-        println!("File {} contains the following chunks:", filepath);
-        for key in chunk_dict.keys() {
-            println!("{}", key);
-        }
-        println!("");
-    }
-
-    Ok((chunk_dict, header))
-}
-
-pub fn read_any_info_and_make_dict(filepath: &str, chunk_name: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
-    let mut file = File::open(filepath)?;
-    let (chunk_location, chunk_size) = match get_list_of_file_chunks(filepath, false)?.0.get(chunk_name) {
-        Some((location, size)) => (*location, *size),  
-        None => return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Chunk not found"))),
-    };
-    file.seek(SeekFrom::Start(chunk_location))?;
-    let mut raw = vec![0; chunk_size as usize];
-    file.read_exact(&mut raw)?;
-
-    let chunk_name_lowercase = chunk_name.to_lowercase();
-    let mut cursor = io::Cursor::new(raw);
-    let chunk_info = parse_chunk(&mut cursor, &chunk_name_lowercase)?;
-
-    Ok(chunk_info)
-}
-
-pub fn read_all_metadata(filepath: &str, chunk_dict: &HashMap<String, (u64, u32)>, verbose: bool) -> io::Result<HashMap<String, HashMap<String, String>>> {
-    let mut metadata = HashMap::new();
-    for key in chunk_dict.keys() {
-        if key == "@IMG_JPEG" || key == "@IMG_FUNDUS" || key == "@IMG_TRC_02" {
-            continue;
-        } 
-        let json_key = key.split('@').last().unwrap_or("").to_uppercase();
-        match read_any_info_and_make_dict(filepath, key) {
-            Ok(info) => {
-                metadata.insert(json_key, info);
-            }
-            Err(_) => {
-                if verbose {
-                    println!("{} there is no method for getting info from this chunk.", key);
-                }
-            }
-        }
-    }
-    Ok(metadata)
-}
-
-pub fn empty_directory(dir: &str) -> Result<(), Box<dyn Error>> {
-    if std::path::Path::new(dir).exists() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                fs::remove_dir_all(&path)?;
-            } else {
-                fs::remove_file(path)?;
-            }
-        }
-    } else {
-        fs::create_dir_all(dir)?;
-    }
-    Ok(())
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::error::Error;
+use crate::events::{emit, Event};
+use crate::fda::headers::Header;
+use crate::fda::parser::ChunkParserRegistry;
+use crate::fda::version::FdaVersion;
+
+// Chunk name -> (payload offset, payload size)
+pub type ChunkDict = HashMap<String, (u64, u32)>;
+
+// Topcon container variants sharing the FOCT chunk layout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopconFormat {
+    Fda,
+    Fds,
+}
+
+impl TopconFormat {
+    pub fn from_header(header: &Header) -> io::Result<Self> {
+        if header.file_code != "FOCT" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Not a Topcon file (file code '{}')", header.file_code)));
+        }
+        match header.file_type.as_str() {
+            "FDA" => Ok(TopconFormat::Fda),
+            "FDS" => Ok(TopconFormat::Fds),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported Topcon file type '{}'", other))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopconFormat::Fda => "Topcon FDA",
+            TopconFormat::Fds => "Topcon FDS",
+        }
+    }
+}
+
+pub fn get_list_of_file_chunks(filepath: &str, printing: bool) -> io::Result<(ChunkDict, Header)> {
+    let mut chunk_dict: ChunkDict = HashMap::new();
+    let mut file = File::open(filepath)?;
+
+    let header = Header::parse(&mut file)?;
+
+    let mut eof = false;
+    while !eof {
+        let chunk_name_size = file.read_u8()? as usize;
+        if chunk_name_size == 0 {
+            eof = true;
+        } else {
+            let mut chunk_name = vec![0; chunk_name_size];
+            file.read_exact(&mut chunk_name)?;
+            let chunk_name = String::from_utf8(chunk_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk name: {}", e)))?;
+            let chunk_size = file.read_u32::<LittleEndian>()?;
+            let chunk_location = file.stream_position()?;
+            file.seek(SeekFrom::Current(chunk_size as i64))?;
+
+            chunk_dict.entry(chunk_name).or_insert((chunk_location, chunk_size));
+        }
+    }
+
+    if printing {
+        let names: Vec<&str> = chunk_dict.keys().map(String::as_str).collect();
+        log::info!("File {} contains the following chunks: {}", filepath, names.join(", "));
+    }
+
+    Ok((chunk_dict, header))
+}
+
+pub fn read_any_info_and_make_dict(filepath: &str, chunk_name: &str, registry: &ChunkParserRegistry) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut file = File::open(filepath)?;
+    let (chunk_dict, header) = get_list_of_file_chunks(filepath, false)?;
+    let (chunk_location, chunk_size) = match chunk_dict.get(chunk_name) {
+        Some((location, size)) => (*location, *size),  
+        None => return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "Chunk not found"))),
+    };
+    file.seek(SeekFrom::Start(chunk_location))?;
+    let mut raw = vec![0; chunk_size as usize];
+    file.read_exact(&mut raw)?;
+
+    let chunk_info = registry.parse(chunk_name, &raw, &FdaVersion::from_header(&header))?;
+
+    Ok(chunk_info)
+}
+
+pub fn read_all_metadata(filepath: &str, chunk_dict: &ChunkDict, registry: &ChunkParserRegistry, verbose: bool) -> io::Result<HashMap<String, HashMap<String, String>>> {
+    let mut metadata = HashMap::new();
+    let header = Header::parse(&mut File::open(filepath)?)?;
+    let version = FdaVersion::from_header(&header);
+    if !version.known {
+        log::warn!("{}: unknown FDA version {}.{}, parsing it with the standard layout", filepath, version.major, version.minor);
+    }
+    metadata.insert("FDA_VERSION".to_string(), version.metadata());
+    for key in chunk_dict.keys() {
+        if key == "@IMG_JPEG" || key == "@IMG_FUNDUS" || key == "@IMG_TRC_02" || key == "@IMG_SCAN_03" || key == "@IMG_OBS" {
+            continue;
+        } 
+        let json_key = key.split('@').next_back().unwrap_or("").to_uppercase();
+        if !registry.contains(key) {
+            // Listed with no fields so the chunk still shows up in metadata.json
+            if verbose {
+                log::debug!("Unhandled chunk: {}", key);
+            }
+            metadata.insert(json_key, HashMap::new());
+            continue;
+        }
+        match read_any_info_and_make_dict(filepath, key, registry) {
+            Ok(info) => {
+                if verbose {
+                    emit(Event::ChunkParsed { file: filepath, chunk: key, fields: info.len() });
+                }
+                metadata.insert(json_key, info);
+            }
+            Err(e) => {
+                if verbose {
+                    log::warn!("{} could not be parsed: {}", key, e);
+                }
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+pub fn empty_directory(dir: &str) -> Result<(), Box<dyn Error>> {
+    if std::path::Path::new(dir).exists() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+        }
+    } else {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(())
 }
\ No newline at end of file
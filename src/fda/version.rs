@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use crate::fda::headers::Header;
+
+// Widths of the fixed-length strings whose size changes between software generations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FdaLayout {
+    // patient id, given name and surname in @PATIENT_INFO_02/03
+    pub patient_string_len: usize,
+    // model, serial numbers and ROM version in @HW_INFO_03
+    pub hw_string_len: usize,
+}
+
+pub const STANDARD_LAYOUT: FdaLayout = FdaLayout {
+    patient_string_len: 32,
+    hw_string_len: 16,
+};
+
+// (major, minor) header versions the chunk layouts were checked against. Other versions are
+// parsed best-effort with the standard layout and flagged as unknown.
+const KNOWN_VERSIONS: [((u32, u32), FdaLayout); 1] = [((2, 1), STANDARD_LAYOUT)];
+
+// String widths found in the layouts above; a chunk whose size fits none of them is read with
+// the width of its layout
+const STRING_LENS: [usize; 2] = [16, 32];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FdaVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub layout: FdaLayout,
+    // False when the version is not in KNOWN_VERSIONS
+    pub known: bool,
+}
+
+impl FdaVersion {
+    pub fn from_header(header: &Header) -> Self {
+        let known = KNOWN_VERSIONS.iter().find(|(version, _)| *version == (header.major_ver, header.minor_ver));
+        FdaVersion {
+            major: header.major_ver,
+            minor: header.minor_ver,
+            layout: known.map_or(STANDARD_LAYOUT, |(_, layout)| *layout),
+            known: known.is_some(),
+        }
+    }
+
+    // Width of each of `count` strings in a payload of `payload_len` bytes that also holds
+    // `fixed` bytes of other fields. The layout width wins when the size agrees with it;
+    // otherwise another known width that gives exactly that size is used, and the second
+    // value is true.
+    pub fn string_len(&self, layout_len: usize, count: usize, fixed: usize, payload_len: usize) -> (usize, bool) {
+        if payload_len == layout_len * count + fixed {
+            return (layout_len, false);
+        }
+        match STRING_LENS.iter().find(|len| **len * count + fixed == payload_len) {
+            Some(&len) => {
+                log::warn!("Payload of {} bytes does not match the layout of FDA version {}.{}, using {}-byte strings", payload_len, self.major, self.minor, len);
+                (len, true)
+            }
+            None => (layout_len, false),
+        }
+    }
+
+    pub fn metadata(&self) -> HashMap<String, String> {
+        let mut info = HashMap::new();
+        info.insert("major_ver".to_string(), self.major.to_string());
+        info.insert("minor_ver".to_string(), self.minor.to_string());
+        info.insert("known".to_string(), self.known.to_string());
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u32, minor: u32) -> FdaVersion {
+        FdaVersion::from_header(&Header { file_code: "FOCT".to_string(), file_type: "FDA".to_string(), major_ver: major, minor_ver: minor })
+    }
+
+    #[test]
+    fn versions_are_looked_up_in_the_table() {
+        assert_eq!(version(2, 1), FdaVersion { major: 2, minor: 1, layout: STANDARD_LAYOUT, known: true });
+        // Unknown versions fall back to the standard layout
+        assert_eq!(version(9, 0), FdaVersion { major: 9, minor: 0, layout: STANDARD_LAYOUT, known: false });
+    }
+
+    #[test]
+    fn layout_width_when_the_size_agrees() {
+        assert_eq!(version(2, 1).string_len(32, 3, 10, 3 * 32 + 10), (32, false));
+    }
+
+    #[test]
+    fn width_inferred_from_the_size() {
+        assert_eq!(version(2, 1).string_len(32, 3, 10, 3 * 16 + 10), (16, true));
+        // Sizes that fit no known width keep the layout, even when they divide evenly
+        assert_eq!(version(2, 1).string_len(32, 3, 10, 3 * 40 + 10), (32, false));
+        assert_eq!(version(2, 1).string_len(32, 3, 10, 4), (32, false));
+    }
+
+    #[test]
+    fn records_the_version() {
+        let info = version(9, 0).metadata();
+        assert_eq!(info["major_ver"], "9");
+        assert_eq!(info["minor_ver"], "0");
+        assert_eq!(info["known"], "false");
+    }
+}
@@ -0,0 +1,148 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::fda::headers::Header;
+use crate::fda::utils::TopconFormat;
+
+// One chunk as stored in the file: u8 name length, name, u32 payload size, payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct FdaChunk {
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+// A whole FDA/FDS file held in memory. Unlike ChunkDict it keeps every chunk,
+// duplicates included, in file order, so writing an unmodified file gives back
+// the same bytes.
+#[derive(Debug, Clone)]
+pub struct FdaWriter {
+    pub header: Header,
+    pub chunks: Vec<FdaChunk>,
+    // Whatever follows the zero byte closing the chunk list
+    pub trailer: Vec<u8>,
+}
+
+impl FdaWriter {
+    pub fn from_file(filepath: &str) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(filepath)?);
+        let header = Header::parse(&mut file)?;
+        TopconFormat::from_header(&header)?;
+
+        let mut chunks = Vec::new();
+        loop {
+            let chunk_name_size = file.read_u8()? as usize;
+            if chunk_name_size == 0 {
+                break;
+            }
+            let mut chunk_name = vec![0; chunk_name_size];
+            file.read_exact(&mut chunk_name)?;
+            let name = String::from_utf8(chunk_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let chunk_size = file.read_u32::<LittleEndian>()? as usize;
+            let mut payload = vec![0; chunk_size];
+            file.read_exact(&mut payload)?;
+            chunks.push(FdaChunk { name, payload });
+        }
+
+        let mut trailer = Vec::new();
+        file.read_to_end(&mut trailer)?;
+        Ok(FdaWriter { header, chunks, trailer })
+    }
+
+    pub fn chunk(&self, name: &str) -> Option<&FdaChunk> {
+        self.chunks.iter().find(|chunk| chunk.name == name)
+    }
+
+    // Replace the payload of every chunk called `name`; returns how many were replaced
+    pub fn replace_payload(&mut self, name: &str, payload: &[u8]) -> usize {
+        let mut replaced = 0;
+        for chunk in self.chunks.iter_mut().filter(|chunk| chunk.name == name) {
+            chunk.payload = payload.to_vec();
+            replaced += 1;
+        }
+        replaced
+    }
+
+    pub fn remove_chunk(&mut self, name: &str) -> usize {
+        let before = self.chunks.len();
+        self.chunks.retain(|chunk| chunk.name != name);
+        before - self.chunks.len()
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.header.write(writer)?;
+        for chunk in &self.chunks {
+            let name_size = u8::try_from(chunk.name.len())
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid chunk name '{}'", chunk.name)))?;
+            let chunk_size = u32::try_from(chunk.payload.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Chunk {} is too large", chunk.name)))?;
+            writer.write_u8(name_size)?;
+            writer.write_all(chunk.name.as_bytes())?;
+            writer.write_u32::<LittleEndian>(chunk_size)?;
+            writer.write_all(&chunk.payload)?;
+        }
+        writer.write_u8(0)?;
+        writer.write_all(&self.trailer)
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn save(&self, filepath: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(filepath)?);
+        self.write(&mut file)?;
+        file.flush()
+    }
+}
+
+// True when reading and re-writing the file reproduces it byte for byte
+pub fn round_trip_matches(filepath: &str) -> io::Result<bool> {
+    Ok(FdaWriter::from_file(filepath)?.to_bytes()? == fs::read(filepath)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(name: &str, payload: &[u8]) -> FdaChunk {
+        FdaChunk { name: name.to_string(), payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn edits_survive_a_round_trip() {
+        let path = std::env::temp_dir().join(format!("octExtractor-writer-test-{}.fda", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let header = Header { file_code: "FOCT".to_string(), file_type: "FDA".to_string(), major_ver: 8, minor_ver: 1 };
+        let original = FdaWriter {
+            header,
+            chunks: vec![chunk("@CAPTURE_INFO_02", &[1, 2, 3]), chunk("@THUMBNAIL", &[4; 10]), chunk("@CAPTURE_INFO_02", &[5])],
+            trailer: vec![0xAB, 0xCD],
+        };
+        original.save(&path).unwrap();
+        assert!(round_trip_matches(&path).unwrap());
+
+        // Duplicates are kept, in file order
+        let mut file = FdaWriter::from_file(&path).unwrap();
+        assert_eq!((file.chunks.clone(), file.trailer.clone()), (original.chunks.clone(), original.trailer.clone()));
+        assert_eq!(file.replace_payload("@CAPTURE_INFO_02", &[9, 9]), 2);
+        assert_eq!(file.remove_chunk("@THUMBNAIL"), 1);
+        file.save(&path).unwrap();
+
+        let edited = FdaWriter::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(edited.chunks, vec![chunk("@CAPTURE_INFO_02", &[9, 9]), chunk("@CAPTURE_INFO_02", &[9, 9])]);
+        assert_eq!((edited.header.major_ver, edited.trailer), (8, vec![0xAB, 0xCD]));
+    }
+
+    #[test]
+    fn empty_chunk_names_are_refused() {
+        let header = Header { file_code: "FOCT".to_string(), file_type: "FDA".to_string(), major_ver: 8, minor_ver: 1 };
+        let file = FdaWriter { header, chunks: vec![chunk("", &[1])], trailer: Vec::new() };
+        assert_eq!(file.to_bytes().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
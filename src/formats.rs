@@ -0,0 +1,124 @@
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use crate::bioptigen::boct::{has_bioptigen_signature, BioptigenReader};
+use crate::e2e::reader::{E2eReader, E2E_MAGIC};
+use crate::fda::reader::TopconReader;
+use crate::volume::Volume;
+use crate::zeiss::cirrus::{is_cirrus_img, CirrusReader};
+
+// Chunk or section name -> field -> value, as written to metadata.json
+pub type Metadata = HashMap<String, HashMap<String, String>>;
+
+// Bytes read from the start of a file to recognise its format
+const SIGNATURE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Topcon,
+    E2e,
+    Bioptigen,
+    Cirrus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ImageKind {
+    Bscan,
+    Fundus,
+    GrayscaleFundus,
+    Thumbnail,
+}
+
+impl ImageKind {
+    // Output subdirectory for this kind of image
+    pub fn directory(&self) -> &'static str {
+        match self {
+            ImageKind::Bscan => "oct",
+            ImageKind::Fundus => "fundus",
+            ImageKind::GrayscaleFundus => "grayscale",
+            ImageKind::Thumbnail => "thumbnail",
+        }
+    }
+}
+
+// Categories are named after their output subdirectory
+impl FromStr for ImageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oct" | "bscan" => Ok(ImageKind::Bscan),
+            "fundus" => Ok(ImageKind::Fundus),
+            "grayscale" => Ok(ImageKind::GrayscaleFundus),
+            "thumbnail" => Ok(ImageKind::Thumbnail),
+            other => Err(format!("Unknown image category '{}', expected oct, fundus, grayscale or thumbnail", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageEntry {
+    pub kind: ImageKind,
+    // File name without extension, e.g. bscan_12
+    pub name: String,
+    // Position of the image within the reader, only meaningful to the reader that listed it
+    pub index: usize,
+}
+
+pub trait OctReader: Send + Sync {
+    fn open(filepath: &str) -> Result<Self, Box<dyn Error>>
+    where
+        Self: Sized;
+    fn format_name(&self) -> &'static str;
+    fn metadata(&self) -> Result<Metadata, Box<dyn Error>>;
+    fn list_images(&self) -> Result<Vec<ImageEntry>, Box<dyn Error>>;
+    fn read_image(&self, entry: &ImageEntry) -> Result<DynamicImage, Box<dyn Error>>;
+    fn read_volume(&self) -> Result<Volume, Box<dyn Error>>;
+    // Every B-scan volume with the label its outputs are prefixed with, empty for a lone volume
+    fn read_volumes(&self) -> Result<Vec<(String, Volume)>, Box<dyn Error>> {
+        Ok(vec![(String::new(), self.read_volume()?)])
+    }
+}
+
+// bscan_0 .. bscan_{count - 1}, for readers whose B-scans are the slices of one volume
+pub fn bscan_entries(count: usize) -> Vec<ImageEntry> {
+    (0..count)
+        .map(|index| ImageEntry { kind: ImageKind::Bscan, name: format!("bscan_{}", index), index })
+        .collect()
+}
+
+pub fn detect_format(filepath: &str) -> Result<InputFormat, Box<dyn Error>> {
+    let mut signature = Vec::with_capacity(SIGNATURE_SIZE);
+    File::open(filepath)?.take(SIGNATURE_SIZE as u64).read_to_end(&mut signature)?;
+
+    if signature.starts_with(b"FOCT") {
+        Ok(InputFormat::Topcon)
+    } else if signature.starts_with(E2E_MAGIC.as_bytes()) {
+        Ok(InputFormat::E2e)
+    } else if has_bioptigen_signature(&signature) {
+        Ok(InputFormat::Bioptigen)
+    } else if is_cirrus_img(filepath) {
+        // Cirrus cubes are headerless, the extension is all there is
+        Ok(InputFormat::Cirrus)
+    } else {
+        let hex: Vec<String> = signature.iter().take(8).map(|b| format!("{:02X}", b)).collect();
+        Err(format!(
+            "Unrecognised input format for {} (starts with {}). Supported: Topcon .fda/.fds, Heidelberg .e2e, Bioptigen .OCT, Zeiss Cirrus .img",
+            filepath,
+            hex.join(" ")
+        ).into())
+    }
+}
+
+pub fn open_reader(filepath: &str) -> Result<Box<dyn OctReader>, Box<dyn Error>> {
+    Ok(match detect_format(filepath)? {
+        InputFormat::Topcon => Box::new(TopconReader::open(filepath)?),
+        InputFormat::E2e => Box::new(E2eReader::open(filepath)?),
+        InputFormat::Bioptigen => Box::new(BioptigenReader::open(filepath)?),
+        InputFormat::Cirrus => Box::new(CirrusReader::open(filepath)?),
+    })
+}
@@ -0,0 +1,300 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{self, Cursor};
+
+use crate::extract::metadata_value;
+use crate::fda::headers::{ImgJpegHeader, ImgScan03Header};
+use crate::fda::fds::slice_bytes;
+use crate::fda::image_processing::split_j2k_codestreams;
+use crate::fda::parser::ChunkParserRegistry;
+use crate::fda::utils::{get_list_of_file_chunks, TopconFormat};
+use crate::fda::version::FdaVersion;
+use crate::fda::writer::FdaWriter;
+use crate::formats::{detect_format, open_reader, ImageKind, InputFormat, OctReader};
+
+// Chunks a Topcon file is expected to hold besides its image chunk
+const EXPECTED_CHUNKS: [&str; 2] = ["@PATIENT_INFO_03", "@CAPTURE_INFO_02"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkEntry {
+    pub name: String,
+    // Offset of the payload, after the chunk name and size
+    pub offset: u64,
+    pub size: u32,
+}
+
+// Topcon chunks in file order
+pub fn chunk_table(filepath: &str) -> io::Result<Vec<ChunkEntry>> {
+    let (chunk_dict, _) = get_list_of_file_chunks(filepath, false)?;
+    let mut chunks: Vec<ChunkEntry> = chunk_dict
+        .into_iter()
+        .map(|(name, (offset, size))| ChunkEntry { name, offset, size })
+        .collect();
+    chunks.sort_by_key(|chunk| chunk.offset);
+    Ok(chunks)
+}
+
+// Patient, eye, scan and device fields for a quick look at a file, in display order
+pub fn summary(reader: &dyn OctReader) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let metadata = reader.metadata()?;
+    let value = |chunks: &[&str], field: &str| metadata_value(&metadata, chunks, field);
+    let patient = ["PATIENT_INFO_03", "PATIENT_INFO_02", "PATIENT_INFO", "CIRRUS_INFO"];
+
+    let mut lines = vec![
+        ("format".to_string(), reader.format_name().to_string()),
+        ("patient_id".to_string(), value(&patient, "patient_id")),
+        ("given_name".to_string(), value(&patient, "given_name")),
+        ("surname".to_string(), value(&patient, "surname")),
+        ("sex".to_string(), value(&patient, "sex")),
+        ("birth_date".to_string(), value(&patient, "birth_date")),
+        ("eye".to_string(), value(&["CAPTURE_INFO_02", "LATERALITY", "CIRRUS_INFO"], "eye")),
+        ("scan_mode".to_string(), value(&["CAPTURE_INFO_02"], "scan_mode")),
+        ("scan_type".to_string(), value(&["CIRRUS_INFO"], "scan_type")),
+        ("capture_date".to_string(), value(&["CAPTURE_INFO_02"], "cap_date")),
+        ("scan_date".to_string(), value(&["CIRRUS_INFO"], "scan_date")),
+        ("device".to_string(), value(&["HW_INFO_03"], "model_name")),
+        ("device_serial".to_string(), value(&["HW_INFO_03", "CIRRUS_INFO"], "serial_number")),
+    ];
+    if let Some(version) = metadata.get("FDA_VERSION") {
+        let field = |name: &str| version.get(name).cloned().unwrap_or_default();
+        lines.insert(1, ("version".to_string(), format!("{}.{}", field("major_ver"), field("minor_ver"))));
+    }
+    lines.retain(|(_, value)| value != "UNKNOWN");
+
+    let images = reader.list_images()?;
+    for kind in [ImageKind::Bscan, ImageKind::Fundus, ImageKind::GrayscaleFundus, ImageKind::Thumbnail] {
+        let count = images.iter().filter(|entry| entry.kind == kind).count();
+        lines.push((format!("{}_images", kind.directory()), count.to_string()));
+    }
+    Ok(lines)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Check { name: name.to_string(), status, detail: detail.into() }
+    }
+}
+
+// Structural checks of a file; stops early when the container itself is unreadable
+pub fn validate(filepath: &str) -> Vec<Check> {
+    match detect_format(filepath) {
+        Ok(InputFormat::Topcon) => validate_topcon(filepath),
+        Ok(_) => validate_reader(filepath),
+        Err(e) => vec![Check::new("format", CheckStatus::Error, e.to_string())],
+    }
+}
+
+fn validate_reader(filepath: &str) -> Vec<Check> {
+    let reader = match open_reader(filepath) {
+        Ok(reader) => reader,
+        Err(e) => return vec![Check::new("open", CheckStatus::Error, e.to_string())],
+    };
+    let mut checks = vec![Check::new("open", CheckStatus::Ok, reader.format_name())];
+    checks.push(match reader.list_images() {
+        Ok(images) if images.is_empty() => Check::new("images", CheckStatus::Error, "no images"),
+        Ok(images) => Check::new("images", CheckStatus::Ok, format!("{} images", images.len())),
+        Err(e) => Check::new("images", CheckStatus::Error, e.to_string()),
+    });
+    checks.push(match reader.read_volume() {
+        Ok(volume) => Check::new("volume", CheckStatus::Ok, format!("{}x{}x{}", volume.width, volume.height, volume.depth)),
+        Err(e) => Check::new("volume", CheckStatus::Warning, e.to_string()),
+    });
+    checks
+}
+
+fn validate_topcon(filepath: &str) -> Vec<Check> {
+    // Reading the whole file catches chunk sizes running past its end
+    let file = match FdaWriter::from_file(filepath) {
+        Ok(file) => file,
+        Err(e) => return vec![Check::new("chunk table", CheckStatus::Error, format!("unreadable, the file may be truncated: {}", e))],
+    };
+    let format = match TopconFormat::from_header(&file.header) {
+        Ok(format) => format,
+        Err(e) => return vec![Check::new("header", CheckStatus::Error, e.to_string())],
+    };
+    let version = FdaVersion::from_header(&file.header);
+    let mut checks = vec![
+        Check::new("header", CheckStatus::Ok, format!("{} {}.{}", file.header.file_type, version.major, version.minor)),
+        Check::new("chunk table", CheckStatus::Ok, format!("{} chunks", file.chunks.len())),
+    ];
+    if !version.known {
+        checks.push(Check::new("version", CheckStatus::Warning, "not a known FDA version, parsed with the standard layout"));
+    }
+    if !file.trailer.is_empty() {
+        checks.push(Check::new("trailer", CheckStatus::Warning, format!("{} bytes after the last chunk", file.trailer.len())));
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for chunk in &file.chunks {
+        *counts.entry(chunk.name.as_str()).or_default() += 1;
+    }
+    let mut duplicates: Vec<&str> = counts.iter().filter(|(_, count)| **count > 1).map(|(name, _)| *name).collect();
+    duplicates.sort_unstable();
+    if !duplicates.is_empty() {
+        checks.push(Check::new("duplicates", CheckStatus::Warning, format!("only the first of each is read: {}", duplicates.join(", "))));
+    }
+
+    let image_chunk = match format {
+        TopconFormat::Fda => "@IMG_JPEG",
+        TopconFormat::Fds => "@IMG_SCAN_03",
+    };
+    checks.push(match file.chunk(image_chunk) {
+        Some(chunk) => check_bscans(format, &chunk.payload),
+        None => Check::new("bscans", CheckStatus::Error, format!("{} is missing", image_chunk)),
+    });
+    if file.chunk("@IMG_FUNDUS").is_none() && file.chunk("@IMG_OBS").is_none() {
+        checks.push(Check::new("fundus", CheckStatus::Warning, "no fundus image"));
+    }
+    for name in EXPECTED_CHUNKS {
+        if file.chunk(name).is_none() {
+            checks.push(Check::new(name, CheckStatus::Warning, "missing"));
+        }
+    }
+
+    let registry = ChunkParserRegistry::default();
+    let mut parsed = HashSet::new();
+    for chunk in file.chunks.iter().filter(|chunk| registry.contains(&chunk.name)) {
+        if !parsed.insert(chunk.name.as_str()) {
+            continue;
+        }
+        match registry.parse(&chunk.name, &chunk.payload, &version) {
+            Ok(info) if info.contains_key("inferred_string_len") => {
+                checks.push(Check::new(&chunk.name, CheckStatus::Warning, "size does not match the layout, string widths inferred"));
+            }
+            Ok(_) => {}
+            Err(e) => checks.push(Check::new(&chunk.name, CheckStatus::Error, format!("could not be parsed: {}", e))),
+        }
+    }
+    checks
+}
+
+// The B-scan count in the image chunk header against what the payload actually holds
+fn check_bscans(format: TopconFormat, payload: &[u8]) -> Check {
+    let mut reader = Cursor::new(payload);
+    let (announced, found) = match format {
+        TopconFormat::Fda => match ImgJpegHeader::from_reader(&mut reader) {
+            Ok(header) => (header.number_slices as usize, split_j2k_codestreams(&payload[reader.position() as usize..]).len()),
+            Err(e) => return Check::new("bscans", CheckStatus::Error, e.to_string()),
+        },
+        TopconFormat::Fds => match ImgScan03Header::from_reader(&mut reader) {
+            Ok(header) => match slice_bytes(&header) {
+                Ok(slice_size) => (header.number_slices as usize, (payload.len() - reader.position() as usize) / slice_size),
+                Err(e) => return Check::new("bscans", CheckStatus::Error, e),
+            },
+            Err(e) => return Check::new("bscans", CheckStatus::Error, e.to_string()),
+        },
+    };
+    if found < announced {
+        Check::new("bscans", CheckStatus::Error, format!("header announces {} B-scans, found {}", announced, found))
+    } else {
+        Check::new("bscans", CheckStatus::Ok, format!("{} B-scans", announced))
+    }
+}
+
+// Classic 16 bytes per line dump: offset, hex bytes, printable ASCII
+pub fn hex_dump(bytes: &[u8], base_offset: u64) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        out.push_str(&format!("{:08x}  {:<47}  |{}|\n", base_offset + 16 * i as u64, hex.join(" "), ascii));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fda::headers::Header;
+    use crate::fda::writer::FdaChunk;
+    use std::fs;
+
+    // @IMG_SCAN_03 header for 2x2 8-bit B-scans followed by `slices` of them
+    fn scan_payload(number_slices: u32, slices: usize) -> Vec<u8> {
+        let mut payload = vec![0];
+        for value in [2u32, 2, 8, number_slices] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.push(0);
+        payload.extend_from_slice(&(4 * slices as u32).to_le_bytes());
+        payload.extend(vec![128; 4 * slices]);
+        payload
+    }
+
+    fn fds(path: &str, major_ver: u32, chunks: Vec<FdaChunk>) {
+        let header = Header { file_code: "FOCT".to_string(), file_type: "FDS".to_string(), major_ver, minor_ver: 1 };
+        FdaWriter { header, chunks, trailer: Vec::new() }.save(path).unwrap();
+    }
+
+    fn status<'a>(checks: &'a [Check], name: &str) -> Option<(CheckStatus, &'a str)> {
+        checks.iter().find(|check| check.name == name).map(|check| (check.status, check.detail.as_str()))
+    }
+
+    #[test]
+    fn bscans_are_counted_in_the_payload() {
+        assert_eq!(check_bscans(TopconFormat::Fds, &scan_payload(3, 3)).status, CheckStatus::Ok);
+        let short = check_bscans(TopconFormat::Fds, &scan_payload(3, 2));
+        assert_eq!((short.status, short.detail.as_str()), (CheckStatus::Error, "header announces 3 B-scans, found 2"));
+        assert_eq!(check_bscans(TopconFormat::Fds, &[0; 4]).status, CheckStatus::Error);
+    }
+
+    #[test]
+    fn problems_in_a_topcon_file_are_reported() {
+        let path = std::env::temp_dir().join(format!("octExtractor-inspect-test-{}.fds", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let chunk = |name: &str, payload: Vec<u8>| FdaChunk { name: name.to_string(), payload };
+        fds(&path, 9, vec![chunk("@IMG_SCAN_03", scan_payload(3, 2)), chunk("@IMG_OBS", Vec::new()), chunk("@IMG_OBS", Vec::new())]);
+
+        let checks = validate(&path);
+        assert_eq!(status(&checks, "header"), Some((CheckStatus::Ok, "FDS 9.1")));
+        assert_eq!(status(&checks, "version").map(|(status, _)| status), Some(CheckStatus::Warning));
+        assert_eq!(status(&checks, "duplicates"), Some((CheckStatus::Warning, "only the first of each is read: @IMG_OBS")));
+        assert_eq!(status(&checks, "bscans").map(|(status, _)| status), Some(CheckStatus::Error));
+        assert_eq!(status(&checks, "@PATIENT_INFO_03"), Some((CheckStatus::Warning, "missing")));
+        assert_eq!(status(&checks, "fundus"), None);
+        // A known version holding every B-scan but no fundus
+        fds(&path, 2, vec![chunk("@IMG_SCAN_03", scan_payload(3, 3))]);
+        let checks = validate(&path);
+        assert_eq!(status(&checks, "version"), None);
+        assert_eq!(status(&checks, "bscans"), Some((CheckStatus::Ok, "3 B-scans")));
+        assert_eq!(status(&checks, "fundus").map(|(status, _)| status), Some(CheckStatus::Warning));
+
+        // Chunks running past the end of the file
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert_eq!(validate(&path)[0].status, CheckStatus::Error);
+        // Chunk names that are not UTF-8
+        let mut bytes = bytes[..15].to_vec();
+        bytes.extend_from_slice(&[2, 0xFF, 0xFE, 0, 0, 0, 0, 0]);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(chunk_table(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dump_lines_hold_sixteen_bytes() {
+        let bytes: Vec<u8> = (0x3E..0x50).collect();
+        assert_eq!(
+            hex_dump(&bytes, 0x100),
+            "00000100  3e 3f 40 41 42 43 44 45 46 47 48 49 4a 4b 4c 4d  |>?@ABCDEFGHIJKLM|\n\
+             00000110  4e 4f                                            |NO|\n"
+        );
+        assert_eq!(hex_dump(&[0, b' '], 0), format!("00000000  00 20{}  |. |\n", " ".repeat(42)));
+    }
+}
@@ -0,0 +1,20 @@
+pub mod archive;
+pub mod batch;
+pub mod bioptigen;
+pub mod color;
+pub mod config;
+pub mod deidentify;
+pub mod e2e;
+pub mod events;
+pub mod extract;
+pub mod fda;
+pub mod formats;
+pub mod inspect;
+pub mod montage;
+pub mod naming;
+pub mod outcome;
+pub mod output;
+pub mod preview;
+pub mod volume;
+pub mod watch;
+pub mod zeiss;
@@ -1,82 +1,626 @@
-mod fda;
-
-use clap::{Arg, Command};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::{self, OpenOptions};
+use std::ffi::OsString;
+use std::fs;
 use std::io::Write;
-use rayon::prelude::*;
-use fda::image_processing::{read_fundus_image, read_img_jpeg, read_grayscale_image, read_thumbnail};
-use fda::utils::{get_list_of_file_chunks, read_all_metadata, empty_directory};
-use env_logger;
-use image::ImageFormat;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use oct_extractor::archive::Archive;
+use oct_extractor::batch::{expand_inputs, run_batch, write_summary, FileReport};
+use oct_extractor::config::{ExtractConfig, Profile};
+use oct_extractor::events::{emit, init_logger, Event, LogFormat};
+use oct_extractor::fda::anonymize::{anonymize, AnonymizeOptions};
+use oct_extractor::fda::image_processing::read_chunk_bytes;
+use oct_extractor::fda::utils::get_list_of_file_chunks;
+use oct_extractor::fda::writer::FdaWriter;
+use oct_extractor::formats::{detect_format, open_reader, ImageKind, InputFormat};
+use oct_extractor::outcome::{Failure, Outcome};
+use oct_extractor::output::{final_path, ExistingOutput, StagedOutput};
+use oct_extractor::naming::NamingTemplate;
+use oct_extractor::inspect::{chunk_table, hex_dump, summary, validate, CheckStatus};
+use oct_extractor::volume::{SliceRange, TargetSpacing};
+use oct_extractor::watch::Watcher;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let matches = Command::new("OCT Extractor")
-        .version("1.0")
-        .author("Jesus Blanco - witeDev")
-        .about("Extracts images from OCT files")
-        .arg(Arg::new("filepath")
-            .help("The path to the input file")
-            .required(true)
-            .index(1))
-        .arg(Arg::new("output_format")
+const SUBCOMMANDS: [&str; 8] = ["extract", "watch", "info", "chunks", "dump", "validate", "anonymize", "help"];
+
+fn filepath_arg() -> Arg {
+    Arg::new("filepath")
+        .help("The path to the input file")
+        .required(true)
+        .index(1)
+}
+
+// Human summaries on stdout, left out with --quiet or JSON logs
+fn is_chatty(matches: &ArgMatches) -> bool {
+    !matches.get_flag("quiet") && matches.get_one::<LogFormat>("log_format") == Some(&LogFormat::Human)
+}
+
+fn json_arg() -> Arg {
+    Arg::new("json")
+        .long("json")
+        .help("Print JSON instead of text")
+        .action(ArgAction::SetTrue)
+}
+
+// Options shared by extract and watch, read back by cli_config
+fn profile_args() -> Vec<Arg> {
+    vec![
+        Arg::new("output_format")
             .short('e')
             .long("extension")
-            .help("The output image format")
-            .required(true)
-            .value_parser(["bmp", "jpg", "png", "tiff"]))
-        .arg(Arg::new("output_dir")
+            .help("The output image format, required unless the config sets it")
+            .value_parser(["bmp", "jpg", "png", "tiff"]),
+        Arg::new("output_dir")
             .short('o')
             .long("output")
-            .help("The output directory")
-            .default_value("extraction"))
-        .get_matches();
+            .help("The output directory [default: extraction]"),
+        Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .help("Extraction profile (TOML, or JSON for .json files); options given on the command line override it"),
+        Arg::new("resample")
+            .long("resample")
+            .value_name("SPACING")
+            .help("Resample the B-scan volume before export: 'isotropic', a spacing in mm, or 'x,y,z' in mm")
+            .value_parser(|s: &str| s.parse::<TargetSpacing>()),
+        Arg::new("interpolation")
+            .long("interpolation")
+            .help("Interpolation used when resampling [default: trilinear]")
+            .value_parser(["nearest", "trilinear", "lanczos"]),
+        Arg::new("reslice")
+            .long("reslice")
+            .help("Also export sagittal (slow-axis) and C-scan (depth-constant) planes")
+            .action(ArgAction::SetTrue),
+        Arg::new("preview")
+            .long("preview")
+            .value_name("FORMATS")
+            .help("Write an animated preview of the B-scan stack (gif, apng or both, comma separated)")
+            .value_delimiter(',')
+            .value_parser(["gif", "apng"]),
+        Arg::new("preview_fundus")
+            .long("preview-fundus")
+            .help("Show the fundus photo with a moving scan-line marker next to each B-scan")
+            .action(ArgAction::SetTrue),
+        Arg::new("preview_delay")
+            .long("preview-delay")
+            .value_name("MS")
+            .help("Delay between preview frames in milliseconds [default: 100]")
+            .value_parser(clap::value_parser!(u16).range(1..)),
+        Arg::new("montage")
+            .long("montage")
+            .help("Build a contact sheet with the fundus images, thumbnail and B-scans")
+            .action(ArgAction::SetTrue),
+        Arg::new("white_balance")
+            .long("white-balance")
+            .help("White balance fundus photos from the colour temperature in @PARAM_OBS_02")
+            .action(ArgAction::SetTrue),
+        Arg::new("embed_profile")
+            .long("embed-profile")
+            .help("Embed an sRGB ICC profile and the camera fields in fundus photos (png, jpg)")
+            .action(ArgAction::SetTrue),
+        Arg::new("only")
+            .long("only")
+            .value_name("CATEGORIES")
+            .help("Only write these image categories (oct, fundus, grayscale, thumbnail; comma separated)")
+            .value_delimiter(',')
+            .value_parser(|s: &str| s.parse::<ImageKind>())
+            .conflicts_with("skip"),
+        Arg::new("skip")
+            .long("skip")
+            .value_name("CATEGORIES")
+            .help("Write every image category except these (comma separated)")
+            .value_delimiter(',')
+            .value_parser(|s: &str| s.parse::<ImageKind>()),
+        Arg::new("slices")
+            .long("slices")
+            .value_name("RANGE")
+            .help("Only write these B-scans: '10..20' (end excluded), '10..=20', '10..' or a single index")
+            .value_parser(|s: &str| s.parse::<SliceRange>()),
+        Arg::new("metadata_only")
+            .long("metadata-only")
+            .help("Only write metadata.json, without decoding any image")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["only", "skip", "slices", "preview", "montage", "resample", "reslice"]),
+        Arg::new("naming")
+            .long("naming")
+            .value_name("TEMPLATE")
+            .help("Output path template, e.g. '{patient_id}/{cap_date}_{eye}/{category}_{index:03}.{ext}'")
+            .value_parser(|s: &str| s.parse::<NamingTemplate>()),
+        Arg::new("deidentify")
+            .long("deidentify")
+            .value_name("POLICY")
+            .help("Remove PHI from metadata.json: drop identifying fields, hash them with --salt, or replace the patient id from --pseudonyms")
+            .value_parser(["drop", "hash", "pseudonym"]),
+        Arg::new("salt")
+            .long("salt")
+            .help("Secret salt for the hash policy and for per-patient date shifts"),
+        Arg::new("pseudonyms")
+            .long("pseudonyms")
+            .value_name("CSV")
+            .help("Lookup CSV with patient_id,pseudonym and an optional shift_days column"),
+        Arg::new("shift_dates")
+            .long("shift-dates")
+            .help("Shift dates by a per-patient offset instead of dropping them")
+            .action(ArgAction::SetTrue),
+        Arg::new("strict")
+            .long("strict")
+            .help("Fail on a file that lacks any expected image chunk or any of whose images cannot be written, instead of skipping those images")
+            .action(ArgAction::SetTrue),
+        Arg::new("overwrite")
+            .long("overwrite")
+            .help("Write into a non-empty output directory, replacing files with the same name")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("clean"),
+        Arg::new("clean")
+            .long("clean")
+            .help("Delete the contents of a non-empty output directory before writing")
+            .action(ArgAction::SetTrue),
+        Arg::new("jobs")
+            .short('j')
+            .long("jobs")
+            .help("Files extracted at the same time in batch and watch mode [default: number of CPUs]")
+            .value_parser(clap::value_parser!(u16).range(1..)),
+    ]
+}
 
-    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
-    let output_format_str = matches.get_one::<String>("output_format").expect("output format is required");
-    let binding = "extraction".to_string();
-    let output_dir = matches.get_one::<String>("output_dir").unwrap_or(&binding);
-
-    let output_format = match output_format_str.as_str() {
-        "bmp" => Some(ImageFormat::Bmp),
-        "jpg" => Some(ImageFormat::Jpeg),
-        "png" => Some(ImageFormat::Png),
-        "tiff" => Some(ImageFormat::Tiff),
-        "j2k" => None,
-        _ => unreachable!(), // Clap ensures only valid values
+fn extract_command_args() -> Command {
+    Command::new("extract")
+        .about("Extract images and metadata (the default when no subcommand is given)")
+        .arg(Arg::new("filepath")
+            .value_name("INPUTS")
+            .help("Input files, directories (searched recursively) or glob patterns")
+            .required(true)
+            .num_args(1..)
+            .index(1))
+        .args(profile_args())
+        .arg(Arg::new("archive")
+            .long("archive")
+            .value_name("FILE")
+            .help("Write all outputs into this .zip, .tar.gz or .tar archive, with a manifest, instead of an output directory")
+            .conflicts_with("output_dir"))
+        .arg(Arg::new("summary")
+            .long("summary")
+            .value_name("PATH")
+            .help("Summary with one row per input, JSON if the path ends in .json and CSV otherwise [default: <output>/summary.csv]"))
+}
+
+fn watch_command_args() -> Command {
+    Command::new("watch")
+        .about("Extract the files dropped into a folder, moving them to processed/ or failed/ when done")
+        .arg(Arg::new("dir")
+            .value_name("DIR")
+            .help("The folder to watch")
+            .required(true)
+            .index(1))
+        .args(profile_args())
+        .arg(Arg::new("interval")
+            .long("interval")
+            .value_name("SECS")
+            .help("Seconds between scans of the folder")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("5"))
+        .arg(Arg::new("settle")
+            .long("settle")
+            .value_name("SECS")
+            .help("Seconds a file's size must stay the same before it is extracted")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("10"))
+        .arg(Arg::new("ledger")
+            .long("ledger")
+            .value_name("PATH")
+            .help("Record of the files already extracted [default: <DIR>/.octExtractor-ledger.jsonl]"))
+        .arg(Arg::new("once")
+            .long("once")
+            .help("Extract the files in the folder once they are stable, then exit")
+            .action(ArgAction::SetTrue))
+}
+
+fn log_errors(report: &FileReport) {
+    for error in &report.errors {
+        log::error!("{}: {}", report.input, error);
+    }
+}
+
+// Errors per file, then counts per outcome; the worst outcome decides the exit code
+fn finish_run(reports: &[FileReport], matches: &ArgMatches) -> Outcome {
+    reports.iter().for_each(log_errors);
+    summarise_run(reports, matches)
+}
+
+fn summarise_run(reports: &[FileReport], matches: &ArgMatches) -> Outcome {
+    let mut outcomes = BTreeMap::new();
+    for report in reports {
+        *outcomes.entry(report.outcome).or_insert(0) += 1;
+    }
+    let worst = outcomes.keys().next_back().copied().unwrap_or(Outcome::Success);
+    let event = Event::RunFinished { files: reports.len(), outcomes, exit_code: worst.exit_code() };
+    if worst != Outcome::Success && is_chatty(matches) {
+        eprintln!("{}", event);
+    }
+    emit(event);
+    worst
+}
+
+// The options given on the command line, as a profile to lay over the config file
+fn cli_config(matches: &ArgMatches) -> ExtractConfig {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let strings = |id: &str| -> Option<Vec<String>> {
+        given(id).then(|| matches.get_raw(id).map(|values| values.map(|v| v.to_string_lossy().to_string()).collect())).flatten()
     };
+    let string = |id: &str| strings(id).and_then(|values| values.into_iter().next());
+    let flag = |id: &str| given(id).then_some(true);
+    ExtractConfig {
+        extension: string("output_format"),
+        output: string("output_dir"),
+        overwrite: flag("overwrite"),
+        clean: flag("clean"),
+        only: strings("only"),
+        skip: strings("skip"),
+        slices: string("slices"),
+        metadata_only: flag("metadata_only"),
+        naming: string("naming"),
+        resample: string("resample"),
+        interpolation: string("interpolation"),
+        reslice: flag("reslice"),
+        preview: strings("preview"),
+        preview_fundus: flag("preview_fundus"),
+        preview_delay: matches.get_one::<u16>("preview_delay").copied(),
+        montage: flag("montage"),
+        white_balance: flag("white_balance"),
+        embed_profile: flag("embed_profile"),
+        deidentify: string("deidentify"),
+        salt: string("salt"),
+        pseudonyms: string("pseudonyms"),
+        shift_dates: flag("shift_dates"),
+        strict: flag("strict"),
+        jobs: matches.get_one::<u16>("jobs").copied(),
+        summary: None,
+        archive: None,
+    }
+}
 
-    let (chunk_dict, _header) = get_list_of_file_chunks(filepath, true)?;
-    // Inicializa el registrador
-    env_logger::init();
+// The config file, if any, with the command line laid over it
+fn load_config(matches: &ArgMatches) -> Result<ExtractConfig, Box<dyn Error>> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => ExtractConfig::load(path)?,
+        None => ExtractConfig::default(),
+    };
+    config.merge(cli_config(matches));
+    Ok(config)
+}
 
-    // Crear y vaciar el directorio de salida
-    empty_directory(output_dir)?;
+fn extract_command(matches: &ArgMatches) -> Result<Outcome, Box<dyn Error>> {
+    let inputs: Vec<String> = matches.get_many::<String>("filepath").expect("filepath is required").cloned().collect();
+    let mut config = load_config(matches)?;
+    if let Some(summary) = matches.get_one::<String>("summary") {
+        config.summary = Some(summary.clone());
+    }
+    if let Some(archive) = matches.get_one::<String>("archive") {
+        config.merge(ExtractConfig { archive: Some(archive.clone()), ..Default::default() });
+    }
+    let Profile { options, output_dir, mut existing, jobs, summary, archive } = config.build()?;
 
-    // Crear las subcarpetas necesarias
-    let subdirs = ["oct", "fundus", "grayscale", "thumbnail", "metadata"];
-    for subdir in &subdirs {
-        fs::create_dir_all(format!("{}/{}", output_dir, subdir))?;
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err(Failure::input("No readable files found in the inputs").into());
+    }
+    // The summary follows the existing-output rule too, checked before anything is extracted.
+    // Without --summary an archived run keeps it inside the archive.
+    let summary_on_disk = summary.clone().or_else(|| archive.is_none().then(|| format!("{}/summary.csv", output_dir)));
+    if let Some(path) = summary_on_disk.filter(|path| existing == ExistingOutput::Refuse && Path::new(path).exists()) {
+        return Err(Failure::output(format!("{} already exists, use --overwrite or --clean to replace it", path)).into());
     }
+    // The existing-output rule applies to the archive, or to the output directory as a whole,
+    // which is staged like the subfolder of a single file. Either way the subfolders are new.
+    let archive = archive.map(|path| Archive::create(&path, existing)).transpose().map_err(Failure::output)?;
+    let staged = match &archive {
+        Some(_) => None,
+        None => Some(StagedOutput::prepare(&output_dir, existing).map_err(Failure::output)?),
+    };
+    let output_dir = match (&archive, &staged) {
+        (Some(archive), _) => archive.root(),
+        (None, Some(staged)) => staged.path(),
+        (None, None) => output_dir,
+    };
+    existing = ExistingOutput::Refuse;
 
-    let metadata = read_all_metadata(filepath, &chunk_dict, true)?;
+    let mut reports = run_batch(&files, &output_dir, existing, &options, jobs, archive.as_ref())?;
+    for report in &mut reports {
+        report.output_dir = final_path(&report.output_dir);
+    }
+    let summary_path = summary.clone().unwrap_or_else(|| format!("{}/summary.csv", output_dir));
+    write_summary(&reports, &summary_path).map_err(Failure::output)?;
+    let summary_path = final_path(&summary_path);
+    if let Some(archive) = archive {
+        // Unless it was asked for somewhere else, the summary goes into the archive too
+        if summary.is_none() {
+            archive.add(Path::new(&output_dir).join("summary.csv").as_path(), None).map_err(Failure::output)?;
+        }
+        archive.finish(&reports)?;
+    }
+    if let Some(staged) = staged {
+        staged.commit().map_err(Failure::output)?;
+    }
 
-    let metadata_json = serde_json::to_string_pretty(&metadata)?;
-    let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(format!("{}/metadata/metadata.json", output_dir))?;
-    file.write_all(metadata_json.as_bytes())?;
+    let ok = reports.iter().filter(|report| report.outcome == Outcome::Success).count();
+    if is_chatty(matches) {
+        println!("{} of {} files extracted without errors, summary in {}", ok, reports.len(), summary_path);
+    }
+    Ok(finish_run(&reports, matches))
+}
 
-    let tasks: Vec<Box<dyn FnOnce() -> Result<(), Box<dyn Error>> + Send>> = vec![
-        Box::new(|| read_img_jpeg(filepath, &chunk_dict, output_format.clone(), output_dir)),
-        Box::new(|| read_fundus_image(filepath, &chunk_dict, output_format.clone(), output_dir)),
-        Box::new(|| read_grayscale_image(filepath, &chunk_dict, output_format.clone(), output_dir)),
-        Box::new(|| read_thumbnail(filepath, &chunk_dict, output_dir)),
-    ];
+fn watch_command(matches: &ArgMatches) -> Result<Outcome, Box<dyn Error>> {
+    let dir = matches.get_one::<String>("dir").expect("dir is required");
+    let interval = Duration::from_secs(*matches.get_one::<u64>("interval").expect("interval has a default"));
+    let settle = Duration::from_secs(*matches.get_one::<u64>("settle").expect("settle has a default"));
+    let once = matches.get_flag("once");
+    let profile = load_config(matches)?.build_for_watch()?;
+    let mut watcher = Watcher::new(dir, profile, settle, matches.get_one::<String>("ledger").map(String::as_str))?;
+    if is_chatty(matches) && !once {
+        println!("Watching {} every {} s, press Ctrl-C to stop", dir, interval.as_secs());
+    }
 
-    tasks.into_par_iter().for_each(|task| {
-        if let Err(e) = task() {
-            eprintln!("Error: {:?}", e);
+    let mut reports = Vec::new();
+    loop {
+        for handled in watcher.poll() {
+            let outcome = handled.report.as_ref().map_or("already extracted", |report| report.outcome.description());
+            if is_chatty(matches) {
+                match &handled.moved_to {
+                    Some(moved_to) => println!("{}: {}, moved to {}", handled.input.display(), outcome, moved_to.display()),
+                    None => println!("{}: {}, left in place", handled.input.display(), outcome),
+                }
+            }
+            if let Some(report) = handled.report {
+                log_errors(&report);
+                reports.push(report);
+            }
         }
-    });
+        if once && watcher.waiting() == 0 {
+            return Ok(summarise_run(&reports, matches));
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn info_command(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
+    let reader = open_reader(filepath).map_err(Failure::input)?;
+    let lines = summary(reader.as_ref()).map_err(Failure::input)?;
+    if matches.get_flag("json") {
+        let map: serde_json::Map<String, serde_json::Value> = lines.into_iter().map(|(key, value)| (key, value.into())).collect();
+        println!("{}", serde_json::to_string_pretty(&map)?);
+    } else {
+        for (key, value) in lines {
+            println!("{:<16} {}", key, value);
+        }
+    }
+    Ok(())
+}
+
+fn chunks_command(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
+    if detect_format(filepath).map_err(Failure::input)? != InputFormat::Topcon {
+        return Err(format!("Only Topcon files are made of chunks, {} is not one", filepath).into());
+    }
+    let chunks = chunk_table(filepath).map_err(Failure::input)?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&chunks)?);
+    } else {
+        println!("{:<24} {:>10} {:>10}", "NAME", "OFFSET", "SIZE");
+        for chunk in chunks {
+            println!("{:<24} {:>10} {:>10}", chunk.name, chunk.offset, chunk.size);
+        }
+    }
+    Ok(())
+}
+
+fn dump_command(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
+    let chunk = matches.get_one::<String>("chunk").expect("chunk is required");
+    let chunk_name = format!("@{}", chunk.trim_start_matches('@').to_uppercase());
+    let (chunk_dict, _) = get_list_of_file_chunks(filepath, false).map_err(Failure::input)?;
+    let bytes = read_chunk_bytes(filepath, &chunk_dict, &chunk_name).map_err(Failure::input)?;
+
+    if let Some(output) = matches.get_one::<String>("output") {
+        fs::write(output, &bytes)?;
+    } else if matches.get_flag("raw") {
+        std::io::stdout().write_all(&bytes)?;
+    } else {
+        let (offset, _) = chunk_dict[&chunk_name];
+        print!("{}", hex_dump(&bytes, offset));
+    }
+    Ok(())
+}
+
+fn validate_command(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
+    let checks = validate(filepath);
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        for check in &checks {
+            let status = match check.status {
+                CheckStatus::Ok => "OK",
+                CheckStatus::Warning => "WARN",
+                CheckStatus::Error => "FAIL",
+            };
+            println!("{:<5} {:<20} {}", status, check.name, check.detail);
+        }
+    }
+    let failed = checks.iter().filter(|check| check.status == CheckStatus::Error).count();
+    if failed > 0 {
+        return Err(Failure::input(format!("{} failed {} check(s)", filepath, failed)).into());
+    }
+    Ok(())
+}
+
+fn anonymize_command(matches: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let filepath = matches.get_one::<String>("filepath").expect("filepath is required");
+    let output = matches.get_one::<String>("output").expect("output is required");
+    let mapping_path = matches.get_one::<String>("mapping").cloned().unwrap_or_else(|| format!("{}.mapping.json", output));
+    if detect_format(filepath).map_err(Failure::input)? != InputFormat::Topcon {
+        return Err(format!("Only Topcon files can be anonymized, {} is not one", filepath).into());
+    }
+    // Even with --overwrite: the original holds the only copy of the patient data
+    let same_file = |a: &str, b: &str| matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b) || a == b;
+    for path in [output, &mapping_path] {
+        if same_file(path, filepath) {
+            return Err(Failure::output(format!("{} is the input file, write the anonymized copy elsewhere", path)).into());
+        }
+    }
+    if same_file(output, &mapping_path) {
+        return Err(Failure::output(format!("The anonymized file and the mapping would both be written to {}", output)).into());
+    }
+    if !matches.get_flag("overwrite") {
+        if let Some(path) = [output, &mapping_path].into_iter().find(|path| Path::new(path).exists()) {
+            return Err(Failure::output(format!("{} already exists, use --overwrite to replace it", path)).into());
+        }
+    }
+    let options = AnonymizeOptions {
+        pseudonym: matches.get_one::<String>("pseudonym").expect("pseudonym has a default").clone(),
+        keep_birth_year: matches.get_flag("keep_birth_year"),
+        regenerate_thumbnail: matches.get_flag("regenerate_thumbnail"),
+    };
+
+    let mut file = FdaWriter::from_file(filepath).map_err(Failure::input)?;
+    let changes = anonymize(filepath, &mut file, &options)?;
+    file.save(output).map_err(Failure::output)?;
 
+    // The mapping links the pseudonym back to the patient, keep it away from the shared files
+    let mapping = serde_json::json!({
+        "source": filepath,
+        "output": output,
+        "pseudonym": options.pseudonym,
+        "changes": changes,
+    });
+    fs::write(&mapping_path, serde_json::to_string_pretty(&mapping)?).map_err(Failure::output)?;
+    if is_chatty(matches) {
+        println!("Wrote {} ({} fields rewritten), mapping in {}", output, changes.len(), mapping_path);
+    }
     Ok(())
 }
+
+fn main() {
+    // `octExtractor <file> -e png` predates the subcommands and still means extract
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let mut first = 1;
+    while let Some(arg) = args.get(first).and_then(|arg| arg.to_str()) {
+        match arg {
+            "-q" | "--quiet" => first += 1,
+            "--log-format" => first += 2,
+            _ if arg.starts_with("--log-format=") => first += 1,
+            _ => break,
+        }
+    }
+    if let Some(arg) = args.get(first).and_then(|arg| arg.to_str()) {
+        if !SUBCOMMANDS.contains(&arg) && !["-h", "--help", "-V", "--version"].contains(&arg) {
+            args.insert(first, "extract".into());
+        }
+    }
+
+    let matches = Command::new("OCT Extractor")
+        .version("1.0")
+        .author("Jesus Blanco - witeDev")
+        .about("Extracts images from OCT files")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(Arg::new("log_format")
+            .long("log-format")
+            .help("Human-readable logs, or one JSON event per line on stderr")
+            .value_parser(|s: &str| s.parse::<LogFormat>())
+            .default_value("human")
+            .global(true))
+        .arg(Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .help("Only report errors")
+            .action(ArgAction::SetTrue)
+            .global(true))
+        .subcommand(extract_command_args())
+        .subcommand(watch_command_args())
+        .subcommand(Command::new("info")
+            .about("Summarise patient, eye, scan and device")
+            .arg(filepath_arg())
+            .arg(json_arg()))
+        .subcommand(Command::new("chunks")
+            .about("List the chunks of a Topcon file with their offset and size")
+            .arg(filepath_arg())
+            .arg(json_arg()))
+        .subcommand(Command::new("dump")
+            .about("Print the payload of a Topcon chunk as a hex dump")
+            .arg(filepath_arg())
+            .arg(Arg::new("chunk")
+                .help("Chunk name, with or without the leading @")
+                .required(true)
+                .index(2))
+            .arg(Arg::new("raw")
+                .long("raw")
+                .help("Write the raw bytes to stdout")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Write the raw bytes to this file")))
+        .subcommand(Command::new("validate")
+            .about("Check the structure of a file")
+            .arg(filepath_arg())
+            .arg(json_arg()))
+        .subcommand(Command::new("anonymize")
+            .about("Write a copy of a Topcon file with the patient data removed, plus a mapping file")
+            .arg(filepath_arg())
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("The anonymized file to write")
+                .required(true))
+            .arg(Arg::new("pseudonym")
+                .long("pseudonym")
+                .help("Patient id written in place of the original one")
+                .default_value("ANONYMOUS"))
+            .arg(Arg::new("mapping")
+                .long("mapping")
+                .help("Where to write the original -> replacement mapping [default: <output>.mapping.json]"))
+            .arg(Arg::new("keep_birth_year")
+                .long("keep-birth-year")
+                .help("Keep the birth year and set month and day to 1 instead of clearing the birth date")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("regenerate_thumbnail")
+                .long("regenerate-thumbnail")
+                .help("Redraw the thumbnail from the fundus photo")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("overwrite")
+                .long("overwrite")
+                .help("Replace the output and mapping files if they exist")
+                .action(ArgAction::SetTrue)))
+        .get_matches_from(args);
+
+    // Inicializa el registrador
+    let log_format = *matches.get_one::<LogFormat>("log_format").expect("log format has a default");
+    init_logger(log_format, matches.get_flag("quiet"));
+
+    let result = match matches.subcommand() {
+        Some(("extract", sub_matches)) => extract_command(sub_matches),
+        Some(("watch", sub_matches)) => watch_command(sub_matches),
+        Some(("info", sub_matches)) => info_command(sub_matches).map(|_| Outcome::Success),
+        Some(("chunks", sub_matches)) => chunks_command(sub_matches).map(|_| Outcome::Success),
+        Some(("dump", sub_matches)) => dump_command(sub_matches).map(|_| Outcome::Success),
+        Some(("validate", sub_matches)) => validate_command(sub_matches).map(|_| Outcome::Success),
+        Some(("anonymize", sub_matches)) => anonymize_command(sub_matches).map(|_| Outcome::Success),
+        _ => unreachable!(), // Clap requires a subcommand
+    };
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            match log_format {
+                LogFormat::Json => log::error!("{}", e),
+                LogFormat::Human => eprintln!("Error: {}", e),
+            }
+            Outcome::of_error(e.as_ref())
+        }
+    };
+    std::process::exit(outcome.exit_code());
+}
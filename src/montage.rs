@@ -0,0 +1,218 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+
+const MONTAGE_WIDTH: u32 = 1200;
+const PADDING: u32 = 10;
+const TOP_ROW_HEIGHT: u32 = 300;
+const BSCAN_COLUMNS: u32 = 6;
+const MAX_BSCANS: usize = 60;
+const TEXT_SCALE: u32 = 2;
+const LINE_HEIGHT: u32 = 10 * TEXT_SCALE;
+
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+const TEXT_COLOR: Rgb<u8> = Rgb([235, 235, 235]);
+const LABEL_COLOR: Rgb<u8> = Rgb([255, 210, 0]);
+
+pub struct MontageInputs {
+    pub fundus: Option<RgbImage>,
+    pub grayscale: Option<RgbImage>,
+    pub thumbnail: Option<RgbImage>,
+    pub bscans: Vec<GrayImage>,
+    // Header lines as (label, value)
+    pub annotations: Vec<(String, String)>,
+}
+
+pub fn build_montage(inputs: &MontageInputs) -> RgbImage {
+    let header_height = PADDING + inputs.annotations.len() as u32 * LINE_HEIGHT;
+    let tile_width = (MONTAGE_WIDTH - 4 * PADDING) / 3;
+    let top_row_y = header_height + PADDING;
+    let caption_y = top_row_y + TOP_ROW_HEIGHT + PADDING / 2;
+
+    let bscans = sample_evenly(&inputs.bscans, MAX_BSCANS);
+    let cell_width = (MONTAGE_WIDTH - (BSCAN_COLUMNS + 1) * PADDING) / BSCAN_COLUMNS;
+    let cell_height = bscans
+        .first()
+        .map(|(_, b)| ((cell_width as f64 * b.height() as f64 / b.width() as f64).round() as u32).clamp(1, cell_width * 2))
+        .unwrap_or(0);
+    let rows = (bscans.len() as u32).div_ceil(BSCAN_COLUMNS);
+    let grid_y = caption_y + LINE_HEIGHT + PADDING;
+    let height = grid_y + rows * (cell_height + PADDING) + PADDING;
+
+    let mut canvas = RgbImage::from_pixel(MONTAGE_WIDTH, height, BACKGROUND);
+
+    for (line, (label, value)) in inputs.annotations.iter().enumerate() {
+        let y = PADDING + line as u32 * LINE_HEIGHT;
+        let label = format!("{}:", label);
+        draw_text(&mut canvas, &label, PADDING, y, LABEL_COLOR);
+        draw_text(&mut canvas, value, PADDING + (label.len() as u32 + 1) * 6 * TEXT_SCALE, y, TEXT_COLOR);
+    }
+
+    let top_row = [
+        ("FUNDUS", &inputs.fundus),
+        ("GRAYSCALE FUNDUS", &inputs.grayscale),
+        ("THUMBNAIL", &inputs.thumbnail),
+    ];
+    for (column, (caption, image)) in top_row.iter().enumerate() {
+        let x = PADDING + column as u32 * (tile_width + PADDING);
+        match image {
+            Some(image) => place_fitted(&mut canvas, image, x, top_row_y, tile_width, TOP_ROW_HEIGHT),
+            None => draw_text(&mut canvas, "NOT AVAILABLE", x, top_row_y + TOP_ROW_HEIGHT / 2, TEXT_COLOR),
+        }
+        draw_text(&mut canvas, caption, x, caption_y, LABEL_COLOR);
+    }
+
+    for (cell, (index, bscan)) in bscans.iter().enumerate() {
+        let column = cell as u32 % BSCAN_COLUMNS;
+        let row = cell as u32 / BSCAN_COLUMNS;
+        let x = PADDING + column * (cell_width + PADDING);
+        let y = grid_y + row * (cell_height + PADDING);
+        let resized = imageops::resize(*bscan, cell_width, cell_height, FilterType::Triangle);
+        imageops::replace(&mut canvas, &DynamicImage::ImageLuma8(resized).to_rgb8(), x as i64, y as i64);
+        draw_text(&mut canvas, &index.to_string(), x + 2, y + 2, LABEL_COLOR);
+    }
+
+    canvas
+}
+
+// Pick at most `max` items spread over the whole stack, keeping their original index
+fn sample_evenly<T>(items: &[T], max: usize) -> Vec<(usize, &T)> {
+    if items.len() <= max {
+        return items.iter().enumerate().collect();
+    }
+    (0..max)
+        .map(|i| {
+            let index = i * (items.len() - 1) / (max - 1);
+            (index, &items[index])
+        })
+        .collect()
+}
+
+fn place_fitted(canvas: &mut RgbImage, image: &RgbImage, x: u32, y: u32, max_width: u32, max_height: u32) {
+    let scale = (max_width as f64 / image.width() as f64).min(max_height as f64 / image.height() as f64);
+    let width = ((image.width() as f64 * scale).round() as u32).max(1);
+    let height = ((image.height() as f64 * scale).round() as u32).max(1);
+    let resized = imageops::resize(image, width, height, FilterType::Triangle);
+    imageops::replace(canvas, &resized, (x + (max_width - width) / 2) as i64, (y + (max_height - height) / 2) as i64);
+}
+
+fn draw_text(canvas: &mut RgbImage, text: &str, x: u32, y: u32, color: Rgb<u8>) {
+    for (position, character) in text.chars().enumerate() {
+        let glyph = glyph(character.to_ascii_uppercase());
+        let origin_x = x + position as u32 * 6 * TEXT_SCALE;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..5 {
+                if bits & (0x10 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..TEXT_SCALE {
+                    for dx in 0..TEXT_SCALE {
+                        let px = origin_x + column * TEXT_SCALE + dx;
+                        let py = y + row as u32 * TEXT_SCALE + dy;
+                        if px < canvas.width() && py < canvas.height() {
+                            canvas.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 5x7 bitmap font, one byte per row with the leftmost pixel in bit 4
+fn glyph(character: char) -> [u8; 7] {
+    match character {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        ' ' => [0x00; 7],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(bscans: usize, annotations: usize) -> MontageInputs {
+        MontageInputs {
+            fundus: Some(RgbImage::from_pixel(40, 30, Rgb([0, 200, 0]))),
+            grayscale: None,
+            thumbnail: None,
+            bscans: vec![GrayImage::from_pixel(100, 50, image::Luma([255])); bscans],
+            annotations: vec![("EYE".to_string(), "LEFT".to_string()); annotations],
+        }
+    }
+
+    #[test]
+    fn stacks_are_sampled_end_to_end() {
+        let items: Vec<usize> = (0..100).collect();
+        let indices: Vec<usize> = sample_evenly(&items, 5).into_iter().map(|(index, _)| index).collect();
+        assert_eq!(indices, vec![0, 24, 49, 74, 99]);
+        assert_eq!(sample_evenly(&items[..3], 5).len(), 3);
+    }
+
+    #[test]
+    fn grid_grows_with_the_bscans() {
+        let grid_y = 2 * PADDING + TOP_ROW_HEIGHT + PADDING / 2 + LINE_HEIGHT + PADDING;
+        let empty = build_montage(&inputs(0, 0));
+        assert_eq!(empty.dimensions(), (MONTAGE_WIDTH, grid_y + PADDING));
+
+        // Seven 2:1 B-scans take two rows of cells half as tall as they are wide
+        let montage = build_montage(&inputs(7, 2));
+        let cell_width = (MONTAGE_WIDTH - (BSCAN_COLUMNS + 1) * PADDING) / BSCAN_COLUMNS;
+        let cell_height = cell_width / 2;
+        let grid_y = grid_y + 2 * LINE_HEIGHT;
+        assert_eq!(montage.height(), grid_y + 2 * (cell_height + PADDING) + PADDING);
+        assert_eq!(montage.get_pixel(PADDING + cell_width - 1, grid_y + cell_height - 1), &Rgb([255, 255, 255]));
+        // Nothing past the seventh cell
+        assert_eq!(montage.get_pixel(PADDING + 2 * (cell_width + PADDING), grid_y + cell_height + PADDING + 1), &BACKGROUND);
+
+        // Present images are fitted into their tile, missing ones are left empty
+        let tile_width = (MONTAGE_WIDTH - 4 * PADDING) / 3;
+        let top_row_y = PADDING + 2 * LINE_HEIGHT + PADDING;
+        assert_eq!(montage.get_pixel(PADDING + tile_width / 2, top_row_y + TOP_ROW_HEIGHT / 2), &Rgb([0, 200, 0]));
+        assert_eq!(montage.get_pixel(2 * PADDING + tile_width + tile_width / 2, top_row_y + 1), &BACKGROUND);
+    }
+}
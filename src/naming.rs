@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use walkdir::WalkDir;
+
+use crate::extract::metadata_value;
+use crate::formats::Metadata;
+use crate::output::{move_in_memory, output_files};
+
+const PATIENT_CHUNKS: [&str; 4] = ["PATIENT_INFO_03", "PATIENT_INFO_02", "PATIENT_INFO", "CIRRUS_INFO"];
+
+// Template token -> metadata chunks and field it is read from; the first entry with a value wins
+const METADATA_TOKENS: [(&str, &[&str], &str); 11] = [
+    ("patient_id", &PATIENT_CHUNKS, "patient_id"),
+    ("given_name", &PATIENT_CHUNKS, "given_name"),
+    ("surname", &PATIENT_CHUNKS, "surname"),
+    ("sex", &PATIENT_CHUNKS, "sex"),
+    ("birth_date", &PATIENT_CHUNKS, "birth_date"),
+    ("eye", &["CAPTURE_INFO_02", "LATERALITY", "CIRRUS_INFO"], "eye"),
+    ("scan_mode", &["CAPTURE_INFO_02"], "scan_mode"),
+    ("cap_date", &["CAPTURE_INFO_02"], "cap_date"),
+    ("cap_date", &["CIRRUS_INFO"], "scan_date"),
+    ("model_name", &["HW_INFO_03"], "model_name"),
+    ("serial_number", &["HW_INFO_03", "CIRRUS_INFO"], "serial_number"),
+];
+
+// Tokens that describe the output file rather than the input
+const FILE_TOKENS: [&str; 5] = ["file", "category", "name", "index", "ext"];
+
+// Left where they are: downstream tools look for metadata/metadata.json and metadata/volume.json
+const UNTEMPLATED_CATEGORY: &str = "metadata";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    // Zero-padded to `width` characters when given, e.g. {index:03}
+    Token { name: String, width: Option<usize> },
+}
+
+// Output path relative to the output directory, e.g. {patient_id}/{cap_date}_{eye}/{category}_{index:03}.{ext}
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamingTemplate {
+    segments: Vec<Segment>,
+}
+
+fn is_known_token(name: &str) -> bool {
+    FILE_TOKENS.contains(&name) || METADATA_TOKENS.iter().any(|(token, _, _)| *token == name)
+}
+
+fn parse_token(token: &str) -> Result<Segment, String> {
+    let (name, width) = match token.split_once(':') {
+        Some((name, spec)) => {
+            let width = spec
+                .strip_prefix('0')
+                .and_then(|width| width.parse::<usize>().ok())
+                .ok_or_else(|| format!("Invalid format '{}' for {{{}}}, expected a zero-padded width such as :03", spec, name))?;
+            (name, Some(width))
+        }
+        None => (token, None),
+    };
+    if !is_known_token(name) {
+        let mut known: Vec<&str> = FILE_TOKENS.to_vec();
+        known.extend(METADATA_TOKENS.iter().map(|(token, _, _)| *token));
+        known.dedup();
+        return Err(format!("Unknown token {{{}}} in naming template, expected one of: {}", name, known.join(", ")));
+    }
+    Ok(Segment::Token { name: name.to_string(), width })
+}
+
+impl FromStr for NamingTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') || s.contains('\\') || s.split('/').any(|part| part == "..") {
+            return Err(format!("Naming template '{}' must be a relative path without '..' or '\\'", s));
+        }
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                return Err(format!("Unmatched '}}' in naming template '{}'", s));
+            }
+            let close = rest[open..].find('}').ok_or_else(|| format!("Unclosed '{{' in naming template '{}'", s))? + open;
+            if open > 0 {
+                segments.push(Segment::Literal(rest[..open].to_string()));
+            }
+            segments.push(parse_token(&rest[open + 1..close])?);
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        let has = |token: &str| segments.iter().any(|segment| matches!(segment, Segment::Token { name, .. } if name == token));
+        if !has("ext") {
+            return Err(format!("Naming template '{}' has no {{ext}} token", s));
+        }
+        // Without them, outputs of one input would be written to the same path
+        if !(has("name") || (has("category") && has("index"))) {
+            return Err(format!("Naming template '{}' needs {{name}}, or {{category}} and {{index}}, to keep the outputs of a file apart", s));
+        }
+        Ok(NamingTemplate { segments })
+    }
+}
+
+// Keep letters, digits, '-', '_' and inner dots so a value can never add a directory or hide a file
+fn sanitize(value: &str) -> String {
+    let safe: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    let safe = safe.trim_matches(['.', '_']);
+    if safe.is_empty() { "UNKNOWN".to_string() } else { safe.to_string() }
+}
+
+// Token values of one input file: its name and the identifying fields of its metadata
+pub fn metadata_tokens(metadata: &Metadata, file: &str) -> HashMap<String, String> {
+    let mut tokens = HashMap::new();
+    for (token, chunks, field) in METADATA_TOKENS {
+        let value = metadata_value(metadata, chunks, field);
+        if value != "UNKNOWN" || !tokens.contains_key(token) {
+            // Dates keep the day only, the time of capture is rarely wanted in a path
+            let value = if token == "cap_date" { value.split_whitespace().next().unwrap_or("").to_string() } else { value };
+            tokens.insert(token.to_string(), value);
+        }
+    }
+    tokens.insert("file".to_string(), file.to_string());
+    tokens
+}
+
+impl NamingTemplate {
+    pub fn render(&self, tokens: &HashMap<String, String>) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(text) => text.clone(),
+                Segment::Token { name, width } => {
+                    let value = sanitize(tokens.get(name).map_or("UNKNOWN", String::as_str));
+                    format!("{:0>width$}", value, width = width.unwrap_or(0))
+                }
+            })
+            .collect()
+    }
+
+    // Where the file at `relative` (category/name.ext under the default layout) goes
+    fn target(&self, relative: &Path, tokens: &HashMap<String, String>) -> String {
+        let mut tokens = tokens.clone();
+        let category = match relative.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_string_lossy().to_string(),
+            _ => String::new(),
+        };
+        let name = relative.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+        // bscan_12 -> 12; names without a trailing number (thumbnail, metadata) are index 0
+        let index = name.rsplit('_').next().filter(|n| n.parse::<usize>().is_ok()).unwrap_or("0").to_string();
+        tokens.insert("category".to_string(), category);
+        tokens.insert("index".to_string(), index);
+        tokens.insert("ext".to_string(), relative.extension().map_or(String::new(), |ext| ext.to_string_lossy().to_string()));
+        tokens.insert("name".to_string(), name);
+        self.render(&tokens)
+    }
+
+    // Templated paths of the files at `relatives` (under the default layout), failing when two
+    // of them end up at the same path. Metadata files are not templated.
+    pub fn targets<'a>(&self, relatives: impl IntoIterator<Item = &'a Path>, tokens: &HashMap<String, String>) -> Result<Vec<(&'a Path, String)>, String> {
+        let mut sources: HashMap<String, &Path> = HashMap::new();
+        let mut targets = Vec::new();
+        for relative in relatives {
+            if relative.starts_with(UNTEMPLATED_CATEGORY) {
+                continue;
+            }
+            let target = self.target(relative, tokens);
+            if target.split('/').next() == Some(UNTEMPLATED_CATEGORY) {
+                return Err(format!("Naming template puts {} in {}/, which is kept for the metadata files", relative.display(), UNTEMPLATED_CATEGORY));
+            }
+            if let Some(other) = sources.insert(target.clone(), relative) {
+                return Err(format!("Naming template maps both {} and {} to {}, add {{name}} to it", other.display(), relative.display(), target));
+            }
+            targets.push((relative, target));
+        }
+        Ok(targets)
+    }
+
+    // Move every file written under `output_dir` to its templated path; returns where each moved file went
+    pub fn apply(&self, output_dir: &str, tokens: &HashMap<String, String>) -> Result<HashMap<PathBuf, PathBuf>, Box<dyn Error>> {
+        let root = Path::new(output_dir);
+        let files = output_files(root)?;
+        let relatives = files.iter().map(|file| file.strip_prefix(root)).collect::<Result<Vec<_>, _>>()?;
+        let moves = self.targets(relatives, tokens)?;
+        let moved: Vec<(PathBuf, PathBuf)> = moves.iter().map(|(relative, target)| (root.join(relative), root.join(target))).collect();
+        // Outputs held in memory for an archive only need new names
+        if move_in_memory(&moved) {
+            return Ok(moved.into_iter().collect());
+        }
+
+        // Through a scratch directory first, so a target can reuse the path of a file not moved yet
+        let scratch = root.join(".naming");
+        fs::create_dir_all(&scratch)?;
+        for (i, (from, _)) in moved.iter().enumerate() {
+            fs::rename(from, scratch.join(i.to_string()))?;
+        }
+        remove_empty_dirs(root, &scratch)?;
+        for (i, (_, target)) in moved.iter().enumerate() {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(scratch.join(i.to_string()), target)?;
+        }
+        fs::remove_dir(&scratch)?;
+        Ok(moved.into_iter().collect())
+    }
+}
+
+fn remove_empty_dirs(root: &Path, keep: &Path) -> std::io::Result<()> {
+    for entry in WalkDir::new(root).min_depth(1).contents_first(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() && entry.path() != keep && fs::read_dir(entry.path())?.next().is_none() {
+            fs::remove_dir(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> HashMap<String, String> {
+        HashMap::from([
+            ("patient_id".to_string(), "P 01/../x".to_string()),
+            ("file".to_string(), "scan".to_string()),
+        ])
+    }
+
+    #[test]
+    fn renders_tokens_with_padding_and_sanitising() {
+        let template: NamingTemplate = "{patient_id}/{category}_{index:03}.{ext}".parse().unwrap();
+        assert_eq!(template.target(Path::new("oct/bscan_7.png"), &tokens()), "P_01_.._x/oct_007.png");
+        assert_eq!(template.target(Path::new("thumbnail/thumbnail.bmp"), &tokens()), "P_01_.._x/thumbnail_000.bmp");
+        let template: NamingTemplate = "{file}/{name}.{ext}".parse().unwrap();
+        assert_eq!(template.target(Path::new("oct/bscan_7.png"), &HashMap::new()), "UNKNOWN/bscan_7.png");
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        for template in ["/abs/{name}.{ext}", "../{name}.{ext}", "{name}", "{nope}.{ext}", "{name.{ext}", "{name}}.{ext}", "{index:3}.{ext}"] {
+            assert!(template.parse::<NamingTemplate>().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn needs_tokens_that_keep_outputs_apart() {
+        assert!("{patient_id}.{ext}".parse::<NamingTemplate>().is_err());
+        assert!("{index}.{ext}".parse::<NamingTemplate>().is_err());
+        assert!("{category}_{index}.{ext}".parse::<NamingTemplate>().is_ok());
+        assert!("{name}.{ext}".parse::<NamingTemplate>().is_ok());
+    }
+
+    #[test]
+    fn metadata_files_keep_their_place() {
+        let template: NamingTemplate = "{category}_{index:03}.{ext}".parse().unwrap();
+        let files = ["metadata/metadata.json", "metadata/volume.json", "oct/bscan_0.png"].map(Path::new);
+        let targets = template.targets(files, &tokens()).unwrap();
+        assert_eq!(targets, vec![(Path::new("oct/bscan_0.png"), "oct_000.png".to_string())]);
+    }
+
+    #[test]
+    fn collisions_are_reported() {
+        let template: NamingTemplate = "{category}_{index:03}.{ext}".parse().unwrap();
+        let files = ["oct/series1_bscan_0.png", "oct/series2_bscan_0.png"].map(Path::new);
+        assert!(template.targets(files, &tokens()).is_err());
+        let template: NamingTemplate = "metadata/{name}.{ext}".parse().unwrap();
+        assert!(template.targets([Path::new("oct/bscan_0.png")], &tokens()).is_err());
+    }
+
+    #[test]
+    fn apply_moves_files_and_removes_empty_folders() {
+        let root = std::env::temp_dir().join(format!("octExtractor-naming-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("oct")).unwrap();
+        fs::create_dir_all(root.join("metadata")).unwrap();
+        fs::write(root.join("oct/bscan_1.png"), "b").unwrap();
+        fs::write(root.join("metadata/metadata.json"), "{}").unwrap();
+        let template: NamingTemplate = "{file}/{category}_{index:02}.{ext}".parse().unwrap();
+        let moved = template.apply(&root.to_string_lossy(), &tokens()).unwrap();
+        assert_eq!(moved, HashMap::from([(root.join("oct/bscan_1.png"), root.join("scan/oct_01.png"))]));
+        assert_eq!(fs::read_to_string(root.join("scan/oct_01.png")).unwrap(), "b");
+        assert!(root.join("metadata/metadata.json").is_file());
+        assert!(!root.join("oct").exists() && !root.join(".naming").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+// How an extraction ended, from best to worst; a run exits with the code of its worst file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    // Metadata was written but some images could not be
+    Partial,
+    // Any other error, e.g. invalid options
+    Failed,
+    // Missing, truncated or unrecognised input, or missing chunks with --strict
+    UnreadableInput,
+    // The output directory could not be written
+    OutputError,
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::Failed => 1,
+            // 2 is taken by clap for usage errors
+            Outcome::Partial => 3,
+            Outcome::UnreadableInput => 4,
+            Outcome::OutputError => 5,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Outcome::Success => "ok",
+            Outcome::Partial => "partial",
+            Outcome::Failed => "failed",
+            Outcome::UnreadableInput => "unreadable input",
+            Outcome::OutputError => "output error",
+        }
+    }
+
+    // Outcome of a failed extraction; errors that were not classified count as Failed
+    pub fn of_error(error: &(dyn Error + 'static)) -> Outcome {
+        error.downcast_ref::<Failure>().map_or(Outcome::Failed, |failure| failure.outcome)
+    }
+}
+
+// An error tagged with the outcome it stands for
+#[derive(Debug)]
+pub struct Failure {
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn input(error: impl fmt::Display) -> Failure {
+        Failure { outcome: Outcome::UnreadableInput, message: error.to_string() }
+    }
+
+    pub fn output(error: impl fmt::Display) -> Failure {
+        Failure { outcome: Outcome::OutputError, message: error.to_string() }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_exit_codes() {
+        let code = |error: Box<dyn Error>| Outcome::of_error(error.as_ref()).exit_code();
+        assert_eq!(code(Failure::input("truncated").into()), 4);
+        assert_eq!(code(Failure::output("read-only").into()), 5);
+        assert_eq!(code("invalid options".into()), 1);
+        assert_eq!((Outcome::Success.exit_code(), Outcome::Partial.exit_code()), (0, 3));
+    }
+
+    #[test]
+    fn the_worst_outcome_wins() {
+        let outcomes = [Outcome::Partial, Outcome::OutputError, Outcome::Success, Outcome::UnreadableInput, Outcome::Failed];
+        assert_eq!(outcomes.iter().max(), Some(&Outcome::OutputError));
+        assert!(Outcome::Success < Outcome::Partial && Outcome::Partial < Outcome::Failed && Outcome::Failed < Outcome::UnreadableInput);
+    }
+}
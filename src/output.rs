@@ -0,0 +1,335 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+use crate::outcome::Failure;
+
+// What to do when the output directory already holds files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExistingOutput {
+    Refuse,
+    // Replace files with the same name, keep the rest
+    Overwrite,
+    // Delete everything that was there
+    Clean,
+}
+
+// Staging directory -> output directory of the extractions in progress
+static STAGED: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(Vec::new());
+
+pub(crate) fn register_staging(staging: &Path, target: &Path) {
+    STAGED.lock().unwrap_or_else(|e| e.into_inner()).push((staging.to_path_buf(), target.to_path_buf()));
+}
+
+pub(crate) fn unregister_staging(staging: &Path) {
+    STAGED.lock().unwrap_or_else(|e| e.into_inner()).retain(|(path, _)| path != staging);
+}
+
+// Where a file written to a staging directory will be once its extraction is committed.
+// Targets can be staged themselves, e.g. the subfolders of a batch output directory, so the
+// innermost staging directory is resolved first.
+pub fn final_path(path: &str) -> String {
+    let staged = STAGED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut path = PathBuf::from(path);
+    for _ in 0..staged.len() {
+        let innermost = staged
+            .iter()
+            .filter(|(staging, _)| path.starts_with(staging))
+            .max_by_key(|(staging, _)| staging.components().count());
+        match innermost {
+            Some((staging, target)) => {
+                let relative = path.strip_prefix(staging).unwrap_or(Path::new(""));
+                path = if relative.as_os_str().is_empty() { target.clone() } else { target.join(relative) };
+            }
+            None => break,
+        }
+    }
+    path.to_string_lossy().to_string()
+}
+
+// Files written so far, by the path they were written to
+type MemoryFiles = Vec<(PathBuf, Vec<u8>)>;
+
+// Output directories that only exist in memory, e.g. the root of an archive, and their files
+static IN_MEMORY: Mutex<Vec<(PathBuf, MemoryFiles)>> = Mutex::new(Vec::new());
+
+pub(crate) fn hold_in_memory(dir: &Path) {
+    IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner()).push((dir.to_path_buf(), Vec::new()));
+}
+
+// Forget the directory and whatever is still held under it
+pub(crate) fn release_from_memory(dir: &Path) {
+    IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner()).retain(|(held, _)| held != dir);
+}
+
+pub fn is_in_memory(path: &Path) -> bool {
+    IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|(dir, _)| path.starts_with(dir))
+}
+
+// Take the files held under `path` out of memory, sorted by path
+pub(crate) fn take_from_memory(path: &Path) -> MemoryFiles {
+    let mut held = IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    let mut taken = Vec::new();
+    for (_, files) in held.iter_mut() {
+        let (under, rest) = std::mem::take(files).into_iter().partition(|(file, _)| file.starts_with(path));
+        *files = rest;
+        taken.extend(under);
+    }
+    taken.sort_by(|(a, _), (b, _)| a.cmp(b));
+    taken
+}
+
+// Write an output file, or keep it in memory when it belongs to a directory held there
+pub fn write_output(path: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut held = IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, files)) = held.iter_mut().find(|(dir, _)| Path::new(path).starts_with(dir)) {
+        files.retain(|(file, _)| file != Path::new(path));
+        files.push((PathBuf::from(path), bytes.to_vec()));
+        return Ok(());
+    }
+    drop(held);
+    fs::write(path, bytes)
+}
+
+pub fn create_output_dir(path: &str) -> io::Result<()> {
+    if is_in_memory(Path::new(path)) {
+        return Ok(());
+    }
+    fs::create_dir_all(path)
+}
+
+// The files written under `dir`, sorted by path
+pub fn output_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let held = IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    if held.iter().any(|(held, _)| dir.starts_with(held)) {
+        let mut files: Vec<PathBuf> = held.iter().flat_map(|(_, files)| files).map(|(file, _)| file.clone()).filter(|file| file.starts_with(dir)).collect();
+        files.sort();
+        return Ok(files);
+    }
+    drop(held);
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        for entry in WalkDir::new(dir).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Give files held in memory new paths; false if they are on disk
+pub(crate) fn move_in_memory(moves: &[(PathBuf, PathBuf)]) -> bool {
+    let mut held = IN_MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    let Some((from, _)) = moves.first() else {
+        return true;
+    };
+    if !held.iter().any(|(dir, _)| from.starts_with(dir)) {
+        return false;
+    }
+    for (_, files) in held.iter_mut() {
+        for (file, _) in files.iter_mut() {
+            if let Some((_, to)) = moves.iter().find(|(from, _)| from == file) {
+                *file = to.clone();
+            }
+        }
+    }
+    true
+}
+
+fn is_empty_dir(path: &Path) -> io::Result<bool> {
+    Ok(fs::read_dir(path)?.next().is_none())
+}
+
+// Move everything under `from` into `to`, replacing files that exist in both
+fn merge_into(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let destination = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            merge_into(&entry.path(), &destination)?;
+        } else {
+            if destination.is_dir() {
+                fs::remove_dir_all(&destination)?;
+            }
+            fs::rename(entry.path(), &destination)?;
+        }
+    }
+    fs::remove_dir_all(from)
+}
+
+// `target` as an absolute path without `.` or `..`, so that its parent and name are real.
+// Missing directories above it are created.
+fn resolve_target(target: &Path) -> io::Result<PathBuf> {
+    if target.exists() {
+        return fs::canonicalize(target);
+    }
+    let name = target.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} does not name a directory", target.display())))?;
+    let parent = target.parent().filter(|parent| *parent != Path::new("")).unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+    Ok(fs::canonicalize(parent)?.join(name))
+}
+
+// A hidden sibling of the output directory that the outputs are written to first.
+// It only replaces the output directory on `commit`; dropping it throws it away.
+pub struct StagedOutput {
+    target: PathBuf,
+    staging: PathBuf,
+    mode: ExistingOutput,
+}
+
+impl StagedOutput {
+    pub fn prepare(target: &str, mode: ExistingOutput) -> Result<Self, Box<dyn Error>> {
+        let given = PathBuf::from(target);
+        let target = resolve_target(&given)?;
+        if target.is_file() {
+            return Err(format!("{} is a file, not a directory", given.display()).into());
+        }
+        if mode == ExistingOutput::Refuse && target.is_dir() && !is_empty_dir(&target)? {
+            return Err(format!("{} is not empty, use --overwrite or --clean to write into it", given.display()).into());
+        }
+        // Cleaning `.`, `..` or anything above the working directory would delete the ground we stand on
+        let current = std::env::current_dir().and_then(fs::canonicalize)?;
+        if mode == ExistingOutput::Clean && (target.parent().is_none() || current.starts_with(&target)) {
+            return Err(format!("{} contains the working directory and cannot be cleaned", given.display()).into());
+        }
+        let name = target.file_name().map_or("output".to_string(), |name| name.to_string_lossy().to_string());
+        let staging = target.with_file_name(format!(".{}.partial-{}", name, std::process::id()));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        // Paths are reported as given, not resolved
+        register_staging(&staging, &given);
+        Ok(StagedOutput { target, staging, mode })
+    }
+
+    pub fn path(&self) -> String {
+        self.staging.to_string_lossy().to_string()
+    }
+
+    pub fn commit(self) -> io::Result<()> {
+        if self.target.is_dir() {
+            match self.mode {
+                // Never deletes anything: only an empty directory is replaced, and one that was
+                // written to since `prepare` makes the commit fail
+                ExistingOutput::Refuse => fs::remove_dir(&self.target).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{} is not empty anymore, not replacing it: {}", self.target.display(), e))
+                })?,
+                ExistingOutput::Overwrite => return merge_into(&self.staging, &self.target),
+                ExistingOutput::Clean => fs::remove_dir_all(&self.target)?,
+            }
+        }
+        fs::rename(&self.staging, &self.target)
+    }
+}
+
+impl Drop for StagedOutput {
+    fn drop(&mut self) {
+        unregister_staging(&self.staging);
+        if self.staging.exists() {
+            if let Err(e) = fs::remove_dir_all(&self.staging) {
+                log::warn!("Could not remove {}: {}", self.staging.display(), e);
+            }
+        }
+    }
+}
+
+// Run `write` against a staging directory and move its outputs to `target` only if it succeeds
+pub fn with_staging<T>(target: &str, mode: ExistingOutput, write: impl FnOnce(&str) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+    // Held in memory, nothing is archived before the caller takes it; a failure drops it instead
+    if is_in_memory(Path::new(target)) {
+        return write(target).inspect_err(|_| drop(take_from_memory(Path::new(target))));
+    }
+    let staged = StagedOutput::prepare(target, mode).map_err(Failure::output)?;
+    let result = write(&staged.path())?;
+    staged.commit().map_err(Failure::output)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("octExtractor-output-test-{}-{}", std::process::id(), name));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn outputs_held_in_memory_stay_off_the_disk() {
+        let root = scratch("memory");
+        hold_in_memory(&root);
+        let dir = root.join("a").to_string_lossy().to_string();
+        create_output_dir(&format!("{}/oct", dir)).unwrap();
+        write_output(&format!("{}/oct/bscan_0.png", dir), b"b").unwrap();
+        assert_eq!(output_files(&root).unwrap(), vec![root.join("a/oct/bscan_0.png")]);
+        // What a failed extraction wrote is dropped
+        let failed: Result<(), _> = with_staging(&root.join("b").to_string_lossy(), ExistingOutput::Refuse, |dir| {
+            write_output(&format!("{}/oct/bscan_0.png", dir), b"x")?;
+            Err("failed".into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(take_from_memory(&root), vec![(root.join("a/oct/bscan_0.png"), b"b".to_vec())]);
+        assert!(!root.exists());
+        release_from_memory(&root);
+    }
+
+    #[test]
+    fn final_path_resolves_nested_staging() {
+        register_staging(Path::new("/scratch/root"), Path::new("/out/run.zip"));
+        register_staging(Path::new("/scratch/root/.a.partial-1"), Path::new("/scratch/root/a"));
+        assert_eq!(final_path("/scratch/root/.a.partial-1/oct/bscan_0.png"), "/out/run.zip/a/oct/bscan_0.png");
+        assert_eq!(final_path("/scratch/root/.a.partial-1"), "/out/run.zip/a");
+        assert_eq!(final_path("/elsewhere/file.png"), "/elsewhere/file.png");
+        unregister_staging(Path::new("/scratch/root/.a.partial-1"));
+        unregister_staging(Path::new("/scratch/root"));
+    }
+
+    #[test]
+    fn commit_moves_the_staging_directory_into_place() {
+        let target = scratch("commit");
+        let staged = StagedOutput::prepare(&target.to_string_lossy(), ExistingOutput::Refuse).unwrap();
+        fs::write(Path::new(&staged.path()).join("metadata.json"), "{}").unwrap();
+        staged.commit().unwrap();
+        assert!(target.join("metadata.json").is_file());
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn refuse_never_deletes_what_appeared_meanwhile() {
+        let target = scratch("refuse");
+        let staged = StagedOutput::prepare(&target.to_string_lossy(), ExistingOutput::Refuse).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join("other.txt"), "keep me").unwrap();
+        assert!(staged.commit().is_err());
+        assert_eq!(fs::read_to_string(target.join("other.txt")).unwrap(), "keep me");
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_non_empty_target() {
+        let target = scratch("non-empty");
+        fs::create_dir_all(&target).unwrap();
+        fs::write(target.join("other.txt"), "").unwrap();
+        assert!(StagedOutput::prepare(&target.to_string_lossy(), ExistingOutput::Refuse).is_err());
+        assert!(StagedOutput::prepare(&target.to_string_lossy(), ExistingOutput::Overwrite).is_ok());
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn working_directory_cannot_be_cleaned() {
+        assert!(StagedOutput::prepare(".", ExistingOutput::Clean).is_err());
+        assert!(StagedOutput::prepare("..", ExistingOutput::Clean).is_err());
+        let current = std::env::current_dir().unwrap();
+        assert!(StagedOutput::prepare(&current.to_string_lossy(), ExistingOutput::Clean).is_err());
+    }
+}
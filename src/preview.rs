@@ -0,0 +1,203 @@
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::imageops::{self, FilterType};
+use image::{Delay, DynamicImage, Frame, Rgb, RgbImage};
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::events::image_written;
+use crate::output::write_output;
+use crate::volume::Volume;
+
+const MARKER_COLOR: Rgb<u8> = Rgb([255, 40, 40]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreviewFormat {
+    Gif,
+    Apng,
+}
+
+impl FromStr for PreviewFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(PreviewFormat::Gif),
+            "apng" => Ok(PreviewFormat::Apng),
+            _ => Err(format!("Unknown preview format '{}'", s)),
+        }
+    }
+}
+
+impl PreviewFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            PreviewFormat::Gif => "preview.gif",
+            PreviewFormat::Apng => "preview.png",
+        }
+    }
+}
+
+// Fundus photo shown next to the B-scans, with the scanned region in fundus pixels
+pub struct FundusOverlay {
+    pub image: RgbImage,
+    // [x_start, y_start, x_end, y_end]
+    pub scan_region: Option<[u32; 4]>,
+}
+
+// One RGB frame per B-scan, optionally with the fundus and the current scan line on the left
+pub fn render_frames(volume: &Volume, fundus: Option<&FundusOverlay>) -> Vec<RgbImage> {
+    let scaled_fundus = fundus.map(|overlay| {
+        let scale = volume.height as f64 / overlay.image.height() as f64;
+        let width = (overlay.image.width() as f64 * scale).round().max(1.0) as u32;
+        let image = imageops::resize(&overlay.image, width, volume.height as u32, FilterType::Triangle);
+        (image, scale, overlay)
+    });
+
+    (0..volume.depth)
+        .map(|y| {
+            let bscan = DynamicImage::ImageLuma8(volume.slice(y)).to_rgb8();
+            let Some((fundus_image, scale, overlay)) = &scaled_fundus else {
+                return bscan;
+            };
+
+            let mut frame = RgbImage::new(fundus_image.width() + bscan.width(), bscan.height());
+            imageops::replace(&mut frame, fundus_image, 0, 0);
+            imageops::replace(&mut frame, &bscan, fundus_image.width() as i64, 0);
+
+            let [x_start, y_start, x_end, y_end] = scan_region(overlay);
+            let fraction = if volume.depth > 1 { y as f64 / (volume.depth - 1) as f64 } else { 0.5 };
+            let line_y = ((y_start as f64 + (y_end as f64 - y_start as f64) * fraction) * scale).round() as u32;
+            let from = (x_start as f64 * scale).round() as u32;
+            let to = ((x_end as f64 * scale).round() as u32).min(fundus_image.width());
+            draw_horizontal_line(&mut frame, from, to, line_y);
+            frame
+        })
+        .collect()
+}
+
+fn scan_region(overlay: &FundusOverlay) -> [u32; 4] {
+    let (width, height) = overlay.image.dimensions();
+    match overlay.scan_region {
+        Some([x_start, y_start, x_end, y_end]) if x_start < x_end && y_start < y_end && x_end <= width && y_end <= height => {
+            [x_start, y_start, x_end, y_end]
+        }
+        _ => [0, 0, width, height],
+    }
+}
+
+fn draw_horizontal_line(frame: &mut RgbImage, from: u32, to: u32, y: u32) {
+    let y_max = frame.height().saturating_sub(1);
+    for line_y in [y.saturating_sub(1), y, y + 1] {
+        let line_y = line_y.min(y_max);
+        for x in from..to {
+            frame.put_pixel(x, line_y, MARKER_COLOR);
+        }
+    }
+}
+
+pub fn write_preview(frames: &[RgbImage], path: &str, format: PreviewFormat, delay_ms: u16) -> Result<(), Box<dyn Error>> {
+    if frames.is_empty() {
+        return Err("No frames to write".into());
+    }
+    match format {
+        PreviewFormat::Gif => write_gif(frames, path, delay_ms)?,
+        PreviewFormat::Apng => write_apng(frames, path, delay_ms)?,
+    }
+    image_written(path);
+    Ok(())
+}
+
+fn write_gif(frames: &[RgbImage], path: &str, delay_ms: u16) -> Result<(), Box<dyn Error>> {
+    let mut encoded = Vec::new();
+    let mut encoder = GifEncoder::new_with_speed(&mut encoded, 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(delay_ms as u32, 1);
+    encoder.encode_frames(frames.iter().map(|frame| {
+        Frame::from_parts(DynamicImage::ImageRgb8(frame.clone()).to_rgba8(), 0, 0, delay)
+    }))?;
+    drop(encoder);
+    write_output(path, &encoded)?;
+    Ok(())
+}
+
+fn write_apng(frames: &[RgbImage], path: &str, delay_ms: u16) -> Result<(), Box<dyn Error>> {
+    let mut encoded = Vec::new();
+    let (width, height) = frames[0].dimensions();
+    let mut encoder = png::Encoder::new(&mut encoded, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(delay_ms, 1000)?;
+    let mut writer = encoder.write_header()?;
+    for frame in frames {
+        writer.write_image_data(frame.as_raw())?;
+    }
+    writer.finish()?;
+    write_output(path, &encoded)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume::VoxelSpacing;
+    use std::fs;
+
+    // width 4, height 3, depth 3
+    fn volume() -> Volume {
+        Volume { width: 4, height: 3, depth: 3, spacing: VoxelSpacing { x_mm: 0.0, y_mm: 0.0, z_mm: 0.0 }, data: vec![100; 36] }
+    }
+
+    fn fundus(scan_region: Option<[u32; 4]>) -> FundusOverlay {
+        FundusOverlay { image: RgbImage::from_pixel(8, 6, Rgb([0, 0, 0])), scan_region }
+    }
+
+    #[test]
+    fn frames_are_the_bscans_alone_without_a_fundus() {
+        let frames = render_frames(&volume(), None);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].dimensions(), (4, 3));
+        assert_eq!(frames[0].get_pixel(0, 0), &Rgb([100, 100, 100]));
+    }
+
+    #[test]
+    fn scan_line_moves_down_the_fundus() {
+        // The fundus is scaled to the B-scan height, so 8x6 becomes 4x3
+        let frames = render_frames(&volume(), Some(&fundus(None)));
+        assert_eq!(frames[0].dimensions(), (8, 3));
+        assert_eq!(frames[0].get_pixel(0, 0), &MARKER_COLOR);
+        assert_eq!(frames[0].get_pixel(0, 2), &Rgb([0, 0, 0]));
+        assert_eq!(frames[2].get_pixel(0, 2), &MARKER_COLOR);
+        assert_eq!(frames[2].get_pixel(0, 0), &Rgb([0, 0, 0]));
+        // The line stays on the fundus
+        assert_eq!(frames[0].get_pixel(4, 0), &Rgb([100, 100, 100]));
+
+        // Only across the scanned region, and the whole fundus when the region doesn't fit
+        let frames = render_frames(&volume(), Some(&fundus(Some([4, 0, 8, 6]))));
+        assert_eq!(frames[0].get_pixel(1, 0), &Rgb([0, 0, 0]));
+        assert_eq!(frames[0].get_pixel(2, 0), &MARKER_COLOR);
+        let frames = render_frames(&volume(), Some(&fundus(Some([4, 0, 9, 6]))));
+        assert_eq!(frames[0].get_pixel(1, 0), &MARKER_COLOR);
+    }
+
+    #[test]
+    fn previews_are_animated() {
+        let dir = std::env::temp_dir().join(format!("octExtractor-preview-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let frames = render_frames(&volume(), None);
+
+        let gif = dir.join(PreviewFormat::Gif.file_name()).to_string_lossy().to_string();
+        write_preview(&frames, &gif, PreviewFormat::Gif, 100).unwrap();
+        assert!(fs::read(&gif).unwrap().starts_with(b"GIF89a"));
+
+        let apng = dir.join(PreviewFormat::Apng.file_name()).to_string_lossy().to_string();
+        write_preview(&frames, &apng, PreviewFormat::Apng, 100).unwrap();
+        let mut decoder = png::Decoder::new(fs::File::open(&apng).unwrap()).read_info().unwrap();
+        assert_eq!(decoder.info().animation_control.unwrap().num_frames, 3);
+        let mut buffer = vec![0; decoder.output_buffer_size()];
+        decoder.next_frame(&mut buffer).unwrap();
+
+        assert!(write_preview(&[], &gif, PreviewFormat::Gif, 100).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
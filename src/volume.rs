@@ -0,0 +1,480 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+use rayon::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::events::{image_written, slice_written};
+use crate::fda::image_processing::save_image_to_file;
+
+// Largest volume resampling may produce or pass through (2 GiB as f32 voxels)
+const MAX_RESAMPLED_VOXELS: usize = 1 << 29;
+
+// Physical size of one voxel in millimetres.
+// x: lateral (along the B-scan), y: between B-scans, z: axial (depth).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxelSpacing {
+    pub x_mm: f64,
+    pub y_mm: f64,
+    pub z_mm: f64,
+}
+
+// 8-bit OCT cube stored slice by slice: data[(y * height + z) * width + x]
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub spacing: VoxelSpacing,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Trilinear,
+    Lanczos,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Interpolation::Nearest),
+            "trilinear" => Ok(Interpolation::Trilinear),
+            "lanczos" => Ok(Interpolation::Lanczos),
+            _ => Err(format!("Unknown interpolation '{}'", s)),
+        }
+    }
+}
+
+// Requested output spacing, as given on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetSpacing {
+    // Use the native lateral spacing on every axis
+    Isotropic,
+    // Same spacing (mm) on every axis
+    Uniform(f64),
+    Explicit(VoxelSpacing),
+}
+
+impl FromStr for TargetSpacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "isotropic" {
+            return Ok(TargetSpacing::Isotropic);
+        }
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| format!("Invalid spacing value '{}'", v)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        if values.iter().any(|v| !v.is_finite() || *v <= 0.0) {
+            return Err("Spacing values must be positive".to_string());
+        }
+        match values.as_slice() {
+            [v] => Ok(TargetSpacing::Uniform(*v)),
+            [x, y, z] => Ok(TargetSpacing::Explicit(VoxelSpacing { x_mm: *x, y_mm: *y, z_mm: *z })),
+            _ => Err("Expected 'isotropic', a single spacing or 'x,y,z' in mm".to_string()),
+        }
+    }
+}
+
+// B-scan indices to export: "10..20" (end excluded), "10..=20", "10.." or a single "5"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SliceRange {
+    pub start: usize,
+    // Exclusive; None runs to the last B-scan
+    pub end: Option<usize>,
+}
+
+impl SliceRange {
+    pub const ALL: SliceRange = SliceRange { start: 0, end: None };
+
+    pub fn contains(&self, index: usize) -> bool {
+        index >= self.start && self.end.is_none_or(|end| index < end)
+    }
+}
+
+impl FromStr for SliceRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| v.trim().parse::<usize>().map_err(|_| format!("Invalid slice index '{}'", v));
+        let range = match s.split_once("..") {
+            None => {
+                let index = parse(s)?;
+                // The last possible index has no exclusive end, it simply runs to the end
+                SliceRange { start: index, end: index.checked_add(1) }
+            }
+            Some((start, end)) => {
+                let start = if start.trim().is_empty() { 0 } else { parse(start)? };
+                let end = match end.strip_prefix('=') {
+                    Some(last) => parse(last)?.checked_add(1),
+                    None if end.trim().is_empty() => None,
+                    None => Some(parse(end)?),
+                };
+                SliceRange { start, end }
+            }
+        };
+        if range.end.is_some_and(|end| end <= range.start) {
+            return Err(format!("Slice range '{}' is empty", s));
+        }
+        Ok(range)
+    }
+}
+
+impl TargetSpacing {
+    pub fn resolve(&self, native: &VoxelSpacing) -> VoxelSpacing {
+        match *self {
+            TargetSpacing::Isotropic => VoxelSpacing { x_mm: native.x_mm, y_mm: native.x_mm, z_mm: native.x_mm },
+            TargetSpacing::Uniform(v) => VoxelSpacing { x_mm: v, y_mm: v, z_mm: v },
+            TargetSpacing::Explicit(spacing) => spacing,
+        }
+    }
+}
+
+impl VoxelSpacing {
+    // Resampling needs a real spacing along every axis
+    pub fn is_known(&self) -> bool {
+        [self.x_mm, self.y_mm, self.z_mm].iter().all(|v| v.is_finite() && *v > 0.0)
+    }
+}
+
+impl fmt::Display for VoxelSpacing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6} x {:.6} x {:.6} mm", self.x_mm, self.y_mm, self.z_mm)
+    }
+}
+
+impl Volume {
+    pub fn from_slices(slices: &[GrayImage], spacing: VoxelSpacing) -> Result<Self, Box<dyn Error>> {
+        let first = slices.first().ok_or("Volume has no slices")?;
+        let (width, height) = (first.width() as usize, first.height() as usize);
+        let mut data = Vec::with_capacity(width * height * slices.len());
+        for (index, slice) in slices.iter().enumerate() {
+            if slice.width() as usize != width || slice.height() as usize != height {
+                return Err(format!("Slice {} is {}x{}, expected {}x{}", index, slice.width(), slice.height(), width, height).into());
+            }
+            data.extend_from_slice(slice.as_raw());
+        }
+        Ok(Volume { width, height, depth: slices.len(), spacing, data })
+    }
+
+    pub fn voxel(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.data[(y * self.height + z) * self.width + x]
+    }
+
+    pub fn slice(&self, y: usize) -> GrayImage {
+        let size = self.width * self.height;
+        let raw = self.data[y * size..(y + 1) * size].to_vec();
+        GrayImage::from_raw(self.width as u32, self.height as u32, raw).expect("slice buffer matches dimensions")
+    }
+
+    // Slow-axis cross section at lateral position x: B-scans across, depth down
+    pub fn sagittal(&self, x: usize) -> GrayImage {
+        GrayImage::from_fn(self.depth as u32, self.height as u32, |y, z| Luma([self.voxel(x, y as usize, z as usize)]))
+    }
+
+    // En-face plane at depth z: lateral position across, B-scans down
+    pub fn cscan(&self, z: usize) -> GrayImage {
+        GrayImage::from_fn(self.width as u32, self.depth as u32, |x, y| Luma([self.voxel(x as usize, y as usize, z)]))
+    }
+
+    pub fn resample(&self, target: VoxelSpacing, interpolation: Interpolation) -> Result<Volume, Box<dyn Error>> {
+        let new_width = resampled_len(self.width, self.spacing.x_mm, target.x_mm);
+        let new_depth = resampled_len(self.depth, self.spacing.y_mm, target.y_mm);
+        let new_height = resampled_len(self.height, self.spacing.z_mm, target.z_mm);
+
+        // The axes are resampled one at a time, so a pass holds the larger length of each axis
+        let largest = [self.width.max(new_width), self.height.max(new_height), self.depth.max(new_depth)]
+            .iter()
+            .try_fold(1usize, |voxels, &len| voxels.checked_mul(len));
+        if largest.is_none_or(|voxels| voxels > MAX_RESAMPLED_VOXELS) {
+            return Err(format!(
+                "Resampling to {} would make the volume {}x{}x{} voxels, more than the limit of {}",
+                target, new_width, new_height, new_depth, MAX_RESAMPLED_VOXELS
+            )
+            .into());
+        }
+
+        let dims = [self.width, self.height, self.depth];
+        let data: Vec<f32> = self.data.iter().map(|&v| v as f32).collect();
+        let (data, dims) = resample_axis(data, dims, 0, new_width, interpolation);
+        let (data, dims) = resample_axis(data, dims, 1, new_height, interpolation);
+        let (data, _) = resample_axis(data, dims, 2, new_depth, interpolation);
+
+        // Axes that could not be resampled keep their native spacing
+        let spacing = VoxelSpacing {
+            x_mm: self.spacing.x_mm * self.width as f64 / new_width as f64,
+            y_mm: self.spacing.y_mm * self.depth as f64 / new_depth as f64,
+            z_mm: self.spacing.z_mm * self.height as f64 / new_height as f64,
+        };
+
+        Ok(Volume {
+            width: new_width,
+            height: new_height,
+            depth: new_depth,
+            spacing,
+            data: data.into_iter().map(|v| v.round().clamp(0.0, 255.0) as u8).collect(),
+        })
+    }
+}
+
+fn resampled_len(len: usize, spacing: f64, target: f64) -> usize {
+    if len <= 1 || !spacing.is_finite() || !target.is_finite() || spacing <= 0.0 || target <= 0.0 {
+        return len;
+    }
+    ((len as f64 * spacing / target).round() as usize).max(1)
+}
+
+// Source taps and weights contributing to each output sample along one axis
+fn axis_weights(in_len: usize, out_len: usize, interpolation: Interpolation) -> Vec<Vec<(usize, f32)>> {
+    let scale = in_len as f64 / out_len as f64;
+    // Widen the kernel when downsampling so every input sample contributes
+    let filter_scale = scale.max(1.0);
+
+    (0..out_len)
+        .map(|i| {
+            let center = (i as f64 + 0.5) * scale - 0.5;
+            if interpolation == Interpolation::Nearest {
+                let index = (center.round().max(0.0) as usize).min(in_len - 1);
+                return vec![(index, 1.0)];
+            }
+
+            let support = match interpolation {
+                Interpolation::Lanczos => 3.0,
+                _ => 1.0,
+            } * filter_scale;
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).min(in_len - 1);
+
+            let mut taps: Vec<(usize, f32)> = (start..=end)
+                .map(|j| {
+                    let t = (j as f64 - center) / filter_scale;
+                    let weight = match interpolation {
+                        Interpolation::Lanczos => lanczos3(t),
+                        _ => (1.0 - t.abs()).max(0.0),
+                    };
+                    (j, weight as f32)
+                })
+                .filter(|&(_, weight)| weight != 0.0)
+                .collect();
+
+            let total: f32 = taps.iter().map(|&(_, weight)| weight).sum();
+            if total.abs() < f32::EPSILON {
+                let index = (center.round().max(0.0) as usize).min(in_len - 1);
+                return vec![(index, 1.0)];
+            }
+            for tap in taps.iter_mut() {
+                tap.1 /= total;
+            }
+            taps
+        })
+        .collect()
+}
+
+fn lanczos3(t: f64) -> f64 {
+    let t = t.abs();
+    if t < f64::EPSILON {
+        1.0
+    } else if t < 3.0 {
+        let pi_t = std::f64::consts::PI * t;
+        3.0 * pi_t.sin() * (pi_t / 3.0).sin() / (pi_t * pi_t)
+    } else {
+        0.0
+    }
+}
+
+// Resample a [x, z, y] ordered buffer along one axis (0 = x, 1 = z, 2 = y)
+fn resample_axis(data: Vec<f32>, dims: [usize; 3], axis: usize, out_len: usize, interpolation: Interpolation) -> (Vec<f32>, [usize; 3]) {
+    if dims[axis] == out_len {
+        return (data, dims);
+    }
+    let weights = axis_weights(dims[axis], out_len, interpolation);
+    let mut out_dims = dims;
+    out_dims[axis] = out_len;
+    let [in_w, in_h, _] = dims;
+    let [out_w, out_h, _] = out_dims;
+    let mut out = vec![0f32; out_dims.iter().product()];
+
+    // Each output slice (fixed y) is computed independently
+    out.par_chunks_mut(out_w * out_h).enumerate().for_each(|(y, out_slice)| {
+        for z in 0..out_h {
+            for x in 0..out_w {
+                let value = match axis {
+                    0 => weights[x].iter().map(|&(j, w)| data[(y * in_h + z) * in_w + j] * w).sum(),
+                    1 => weights[z].iter().map(|&(j, w)| data[(y * in_h + j) * in_w + x] * w).sum(),
+                    _ => weights[y].iter().map(|&(j, w)| data[(j * in_h + z) * in_w + x] * w).sum(),
+                };
+                out_slice[z * out_w + x] = value;
+            }
+        }
+    });
+
+    (out, out_dims)
+}
+
+// Stretch the coarser axis so that one pixel covers the same distance both ways
+fn correct_aspect(image: GrayImage, horizontal_mm: f64, vertical_mm: f64) -> GrayImage {
+    if !(horizontal_mm.is_finite() && vertical_mm.is_finite()) || horizontal_mm <= 0.0 || vertical_mm <= 0.0 {
+        return image;
+    }
+    let finest = horizontal_mm.min(vertical_mm);
+    let width = (image.width() as f64 * horizontal_mm / finest).round().max(1.0) as u32;
+    let height = (image.height() as f64 * vertical_mm / finest).round().max(1.0) as u32;
+    if width == image.width() && height == image.height() {
+        return image;
+    }
+    imageops::resize(&image, width, height, FilterType::Triangle)
+}
+
+// `prefix` goes in front of the file names, to keep the planes of several volumes apart
+pub fn save_orthogonal_slices(volume: &Volume, output_dir: &str, prefix: &str, format: ImageFormat) -> Result<(), Box<dyn Error>> {
+    let extension = format.extensions_str()[0];
+
+    (0..volume.width).into_par_iter().try_for_each(|x| {
+        let image = correct_aspect(volume.sagittal(x), volume.spacing.y_mm, volume.spacing.z_mm);
+        let path = format!("{}/sagittal/{}sagittal_{}.{}", output_dir, prefix, x, extension);
+        save_image_to_file(&DynamicImage::ImageLuma8(image), &path, Some(format)).map_err(|e| format!("Failed to save {}: {}", path, e))?;
+        image_written(&path);
+        Ok::<(), String>(())
+    })?;
+
+    (0..volume.height).into_par_iter().try_for_each(|z| {
+        let image = correct_aspect(volume.cscan(z), volume.spacing.x_mm, volume.spacing.y_mm);
+        let path = format!("{}/cscan/{}cscan_{}.{}", output_dir, prefix, z, extension);
+        save_image_to_file(&DynamicImage::ImageLuma8(image), &path, Some(format)).map_err(|e| format!("Failed to save {}: {}", path, e))?;
+        image_written(&path);
+        Ok::<(), String>(())
+    })?;
+
+    Ok(())
+}
+
+pub fn save_volume_slices(volume: &Volume, output_dir: &str, prefix: &str, format: ImageFormat, slices: SliceRange) -> Result<(), Box<dyn Error>> {
+    let extension = format.extensions_str()[0];
+    (0..volume.depth).into_par_iter().filter(|y| slices.contains(*y)).try_for_each(|y| {
+        let image = DynamicImage::ImageLuma8(volume.slice(y));
+        let path = format!("{}/{}_{}.{}", output_dir, prefix, y, extension);
+        save_image_to_file(&image, &path, Some(format)).map_err(|e| format!("Failed to save {}: {}", path, e))?;
+        slice_written(&path);
+        Ok::<(), String>(())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spacing(x_mm: f64, y_mm: f64, z_mm: f64) -> VoxelSpacing {
+        VoxelSpacing { x_mm, y_mm, z_mm }
+    }
+
+    // width 4, height 3, depth 2, every voxel distinct
+    fn gradient_volume() -> Volume {
+        Volume { width: 4, height: 3, depth: 2, spacing: spacing(0.01, 0.02, 0.005), data: (0..24).map(|v| v * 10).collect() }
+    }
+
+    #[test]
+    fn slice_range_forms() {
+        assert_eq!("5".parse::<SliceRange>(), Ok(SliceRange { start: 5, end: Some(6) }));
+        assert_eq!("10..20".parse::<SliceRange>(), Ok(SliceRange { start: 10, end: Some(20) }));
+        assert_eq!("10..=20".parse::<SliceRange>(), Ok(SliceRange { start: 10, end: Some(21) }));
+        assert_eq!("10..".parse::<SliceRange>(), Ok(SliceRange { start: 10, end: None }));
+        assert_eq!("..5".parse::<SliceRange>(), Ok(SliceRange { start: 0, end: Some(5) }));
+    }
+
+    #[test]
+    fn slice_range_rejects_empty_and_invalid() {
+        assert!("20..10".parse::<SliceRange>().is_err());
+        assert!("5..5".parse::<SliceRange>().is_err());
+        assert!("a..b".parse::<SliceRange>().is_err());
+        assert!("-1".parse::<SliceRange>().is_err());
+    }
+
+    #[test]
+    fn slice_range_last_index_does_not_overflow() {
+        let max = usize::MAX;
+        assert_eq!(format!("0..={}", max).parse::<SliceRange>(), Ok(SliceRange { start: 0, end: None }));
+        assert_eq!(max.to_string().parse::<SliceRange>(), Ok(SliceRange { start: max, end: None }));
+    }
+
+    #[test]
+    fn slice_range_contains() {
+        let range = SliceRange { start: 2, end: Some(4) };
+        assert!(!range.contains(1));
+        assert!(range.contains(2));
+        assert!(range.contains(3));
+        assert!(!range.contains(4));
+        assert!(SliceRange::ALL.contains(usize::MAX));
+    }
+
+    #[test]
+    fn target_spacing_forms() {
+        assert_eq!("isotropic".parse::<TargetSpacing>(), Ok(TargetSpacing::Isotropic));
+        assert_eq!("0.01".parse::<TargetSpacing>(), Ok(TargetSpacing::Uniform(0.01)));
+        assert_eq!("0.01, 0.02,0.003".parse::<TargetSpacing>(), Ok(TargetSpacing::Explicit(spacing(0.01, 0.02, 0.003))));
+        assert!("0".parse::<TargetSpacing>().is_err());
+        assert!("-1".parse::<TargetSpacing>().is_err());
+        assert!("0.1,0.2".parse::<TargetSpacing>().is_err());
+        assert!("inf".parse::<TargetSpacing>().is_err());
+    }
+
+    #[test]
+    fn isotropic_uses_the_lateral_spacing() {
+        assert_eq!(TargetSpacing::Isotropic.resolve(&spacing(0.01, 0.05, 0.003)), spacing(0.01, 0.01, 0.01));
+    }
+
+    #[test]
+    fn unknown_spacing_is_detected() {
+        assert!(spacing(0.01, 0.05, 0.003).is_known());
+        assert!(!spacing(0.0, 0.0, 0.0039).is_known());
+        assert!(!spacing(0.01, f64::NAN, 0.003).is_known());
+    }
+
+    #[test]
+    fn resample_to_native_spacing_is_identity() {
+        let volume = gradient_volume();
+        for interpolation in [Interpolation::Nearest, Interpolation::Trilinear, Interpolation::Lanczos] {
+            let resampled = volume.resample(volume.spacing, interpolation).unwrap();
+            assert_eq!((resampled.width, resampled.height, resampled.depth), (4, 3, 2));
+            assert_eq!(resampled.data, volume.data, "{:?}", interpolation);
+        }
+    }
+
+    #[test]
+    fn resample_scales_dimensions_and_keeps_constant_volumes() {
+        let volume = Volume { width: 8, height: 6, depth: 4, spacing: spacing(0.01, 0.02, 0.005), data: vec![77; 8 * 6 * 4] };
+        let resampled = volume.resample(spacing(0.02, 0.01, 0.005), Interpolation::Trilinear).unwrap();
+        assert_eq!((resampled.width, resampled.height, resampled.depth), (4, 6, 8));
+        assert_eq!(resampled.data.len(), 4 * 6 * 8);
+        assert!(resampled.data.iter().all(|&v| v == 77));
+        assert!((resampled.spacing.x_mm - 0.02).abs() < 1e-9);
+        assert!((resampled.spacing.y_mm - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn oversized_resampling_is_refused() {
+        let volume = Volume { width: 512, height: 885, depth: 128, spacing: spacing(0.0117, 0.047, 0.0026), data: vec![0; 512 * 885 * 128] };
+        let error = volume.resample(spacing(0.0001, 0.0001, 0.0001), Interpolation::Nearest).unwrap_err();
+        assert!(error.to_string().contains("more than the limit"), "{}", error);
+        // Near-zero spacing overflows the voxel count instead
+        assert!(volume.resample(spacing(1e-300, 1e-300, 1e-300), Interpolation::Nearest).is_err());
+    }
+
+    #[test]
+    fn orthogonal_planes_read_the_right_voxels() {
+        let volume = gradient_volume();
+        let sagittal = volume.sagittal(1);
+        assert_eq!(sagittal.dimensions(), (2, 3));
+        assert_eq!(sagittal.get_pixel(1, 2)[0], volume.voxel(1, 1, 2));
+        let cscan = volume.cscan(2);
+        assert_eq!(cscan.dimensions(), (4, 2));
+        assert_eq!(cscan.get_pixel(3, 1)[0], volume.voxel(3, 1, 2));
+        assert_eq!(volume.slice(1).get_pixel(0, 0)[0], volume.voxel(0, 1, 0));
+    }
+}
@@ -0,0 +1,361 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+use crate::batch::{extract_one, FileReport};
+use crate::config::Profile;
+use crate::extract::{file_token, ExtractOptions};
+use crate::formats::detect_format;
+use crate::outcome::Outcome;
+use crate::output::ExistingOutput;
+
+// Subfolders of the watched directory the originals are moved to
+pub const PROCESSED_DIR: &str = "processed";
+pub const FAILED_DIR: &str = "failed";
+pub const DEFAULT_LEDGER: &str = ".octExtractor-ledger.jsonl";
+
+// One handled input, appended to the ledger as a JSON line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    // Relative to the watched directory
+    pub file: String,
+    pub size: u64,
+    // Seconds since the epoch
+    pub modified: u64,
+    pub outcome: Outcome,
+    pub output_dir: String,
+}
+
+// A file is the same input while its path, size and modification time are
+type FileKey = (String, u64, u64);
+
+struct Ledger {
+    file: File,
+    entries: HashMap<FileKey, LedgerEntry>,
+}
+
+impl Ledger {
+    fn open(path: &Path) -> Result<Ledger, Box<dyn Error>> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A line cut short by a crash is dropped, the file is extracted again
+                match serde_json::from_str::<LedgerEntry>(&line) {
+                    Ok(entry) => {
+                        entries.insert((entry.file.clone(), entry.size, entry.modified), entry);
+                    }
+                    Err(e) => log::warn!("{} line {}: {}", path.display(), n + 1, e),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Cannot open ledger {}: {}", path.display(), e))?;
+        Ok(Ledger { file, entries })
+    }
+
+    fn record(&mut self, entry: LedgerEntry) -> Result<(), Box<dyn Error>> {
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        self.file.sync_data()?;
+        self.entries.insert((entry.file.clone(), entry.size, entry.modified), entry);
+        Ok(())
+    }
+}
+
+// A file that was stable long enough and has been dealt with
+pub struct Handled {
+    // None when the ledger already had it and only the move was left
+    pub report: Option<FileReport>,
+    pub input: PathBuf,
+    pub moved_to: Option<PathBuf>,
+}
+
+struct Observed {
+    size: u64,
+    modified: SystemTime,
+    since: Instant,
+}
+
+// Polls a drop folder and extracts every file whose size and modification time have not changed
+// for `settle`. Polling rather than file system notifications, which network shares rarely deliver.
+pub struct Watcher {
+    dir: PathBuf,
+    output_dir: String,
+    existing: ExistingOutput,
+    options: ExtractOptions,
+    pool: rayon::ThreadPool,
+    settle: Duration,
+    ledger: Mutex<Ledger>,
+    pending: HashMap<PathBuf, Observed>,
+    // Stable files we cannot read or move away, reported once and left alone until they change
+    ignored: HashSet<FileKey>,
+}
+
+fn modified_secs(modified: SystemTime) -> u64 {
+    modified.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// `path`, or `path` with _2, _3, ... before the extension when it is taken
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let mut candidate = path.to_path_buf();
+    let mut n = 2;
+    while candidate.exists() {
+        candidate = path.with_file_name(format!("{}_{}{}", stem, n, extension));
+        n += 1;
+    }
+    candidate
+}
+
+impl Watcher {
+    pub fn new(dir: &str, profile: Profile, settle: Duration, ledger: Option<&str>) -> Result<Watcher, Box<dyn Error>> {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(format!("{} is not a directory", dir.display()).into());
+        }
+        let ledger_path = ledger.map_or_else(|| dir.join(DEFAULT_LEDGER), PathBuf::from);
+        Ok(Watcher {
+            ledger: Mutex::new(Ledger::open(&ledger_path)?),
+            pool: rayon::ThreadPoolBuilder::new().num_threads(profile.jobs.unwrap_or(0)).build()?,
+            dir,
+            output_dir: profile.output_dir,
+            existing: profile.existing,
+            options: profile.options,
+            settle,
+            pending: HashMap::new(),
+            ignored: HashSet::new(),
+        })
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir).unwrap_or(path).to_string_lossy().replace('\\', "/")
+    }
+
+    // Files in the watched tree, leaving out processed/, failed/, the output directory and hidden entries
+    fn scan(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        let output_dir = fs::canonicalize(&self.output_dir).ok();
+        WalkDir::new(&self.dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                let name = entry.file_name().to_string_lossy();
+                let reserved = entry.depth() == 1 && (name == PROCESSED_DIR || name == FAILED_DIR);
+                let hidden = entry.depth() > 0 && name.starts_with('.');
+                let is_output = output_dir.is_some() && fs::canonicalize(entry.path()).ok() == output_dir;
+                !reserved && !hidden && !is_output
+            })
+            .filter_map(|entry| entry.map_err(|e| log::warn!("{}", e)).ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((entry.into_path(), metadata.len(), metadata.modified().ok()?))
+            })
+            .collect()
+    }
+
+    // Files whose size and modification time have held still for `settle`
+    fn stable_files(&mut self) -> Vec<(PathBuf, FileKey)> {
+        let now = Instant::now();
+        let files = self.scan();
+        let present: HashSet<&PathBuf> = files.iter().map(|(path, _, _)| path).collect();
+        self.pending.retain(|path, _| present.contains(path));
+
+        let mut stable = Vec::new();
+        for (path, size, modified) in &files {
+            let key = (self.relative(path), *size, modified_secs(*modified));
+            match self.pending.get(path) {
+                Some(seen) if seen.size == *size && seen.modified == *modified => {
+                    if now.duration_since(seen.since) >= self.settle && !self.ignored.contains(&key) {
+                        stable.push((path.clone(), key));
+                    }
+                }
+                _ => {
+                    self.pending.insert(path.clone(), Observed { size: *size, modified: *modified, since: now });
+                    if self.settle.is_zero() && !self.ignored.contains(&key) {
+                        stable.push((path.clone(), key));
+                    }
+                }
+            }
+        }
+        stable
+    }
+
+    // One output folder per input under the output directory, never one an earlier run wrote to
+    // unless existing outputs may be replaced
+    fn output_dirs(&self, files: &[(PathBuf, FileKey)]) -> Vec<String> {
+        let mut used = HashSet::new();
+        files
+            .iter()
+            .map(|(file, _)| {
+                let stem = file_token(&file.to_string_lossy(), &self.options);
+                let mut name = stem.clone();
+                let mut n = 2;
+                while !used.insert(name.to_lowercase())
+                    || (self.existing == ExistingOutput::Refuse && Path::new(&self.output_dir).join(&name).exists())
+                {
+                    name = format!("{}_{}", stem, n);
+                    n += 1;
+                }
+                format!("{}/{}", self.output_dir, name)
+            })
+            .collect()
+    }
+
+    // The original goes to processed/ or failed/, keeping its path below the watched directory
+    fn move_original(&self, path: &Path, outcome: Outcome) -> Option<PathBuf> {
+        let folder = if outcome == Outcome::Success { PROCESSED_DIR } else { FAILED_DIR };
+        let target = free_path(&self.dir.join(folder).join(self.relative(path)));
+        let moved = target.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::rename(path, &target));
+        match moved {
+            Ok(()) => Some(target),
+            Err(e) => {
+                log::warn!("Cannot move {} to {}: {}", path.display(), target.display(), e);
+                None
+            }
+        }
+    }
+
+    fn handle(&self, file: &Path, key: FileKey, output_dir: &str) -> Handled {
+        let report = extract_one(file, output_dir, self.existing, &self.options);
+        let entry = LedgerEntry { file: key.0, size: key.1, modified: key.2, outcome: report.outcome, output_dir: output_dir.to_string() };
+        let recorded = self.ledger.lock().unwrap_or_else(|e| e.into_inner()).record(entry);
+        // Without a ledger entry the original stays put, so a restart extracts it again
+        let moved_to = match recorded {
+            Ok(()) => self.move_original(file, report.outcome),
+            Err(e) => {
+                log::error!("Cannot write to the ledger: {}", e);
+                None
+            }
+        };
+        Handled { report: Some(report), input: file.to_path_buf(), moved_to }
+    }
+
+    // Scan once and extract the files that are ready
+    pub fn poll(&mut self) -> Vec<Handled> {
+        let mut handled = Vec::new();
+        let mut ready = Vec::new();
+        for (path, key) in self.stable_files() {
+            let known = self.ledger.lock().unwrap_or_else(|e| e.into_inner()).entries.get(&key).map(|entry| entry.outcome);
+            if let Some(outcome) = known {
+                // Extracted before a restart, only the move was left to do
+                log::info!("{} is in the ledger, not extracting it again", path.display());
+                handled.push((Handled { report: None, moved_to: self.move_original(&path, outcome), input: path }, key));
+            } else if detect_format(&path.to_string_lossy()).is_ok() {
+                ready.push((path, key));
+            } else {
+                log::warn!("Ignoring {}, not a supported input file", path.display());
+                self.ignored.insert(key);
+            }
+        }
+
+        let dirs = self.output_dirs(&ready);
+        let extracted: Vec<(Handled, FileKey)> = self.pool.install(|| {
+            ready
+                .into_par_iter()
+                .zip(dirs.into_par_iter())
+                .map(|((path, key), dir)| (self.handle(&path, key.clone(), &dir), key))
+                .collect()
+        });
+        handled.extend(extracted);
+        for (done, key) in &handled {
+            if done.moved_to.is_some() {
+                self.pending.remove(&done.input);
+            } else {
+                self.ignored.insert(key.clone());
+            }
+        }
+        handled.into_iter().map(|(done, _)| done).collect()
+    }
+
+    // Files seen but not stable yet
+    pub fn waiting(&self) -> usize {
+        self.pending
+            .iter()
+            .filter(|(path, seen)| !self.ignored.contains(&(self.relative(path), seen.size, modified_secs(seen.modified))))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExtractConfig;
+
+    // A drop folder holding a truncated Topcon file: recognised, but it cannot be extracted
+    fn drop_folder(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("octExtractor-watch-test-{}-{}", std::process::id(), name));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("scan.fda"), b"FOCTFDA").unwrap();
+        dir
+    }
+
+    fn watcher(dir: &Path, settle: Duration) -> Watcher {
+        let config = ExtractConfig {
+            extension: Some("png".to_string()),
+            output: Some(dir.join("out").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        Watcher::new(&dir.to_string_lossy(), config.build_for_watch().unwrap(), settle, None).unwrap()
+    }
+
+    fn write_ledger(dir: &Path, size_change: u64) {
+        let metadata = fs::metadata(dir.join("scan.fda")).unwrap();
+        let entry = LedgerEntry {
+            file: "scan.fda".to_string(),
+            size: metadata.len() + size_change,
+            modified: modified_secs(metadata.modified().unwrap()),
+            outcome: Outcome::Success,
+            output_dir: "out/scan".to_string(),
+        };
+        fs::write(dir.join(DEFAULT_LEDGER), format!("{}\n", serde_json::to_string(&entry).unwrap())).unwrap();
+    }
+
+    #[test]
+    fn files_in_the_ledger_are_only_moved() {
+        let dir = drop_folder("ledger");
+        write_ledger(&dir, 0);
+        let handled = watcher(&dir, Duration::ZERO).poll();
+        assert_eq!(handled.len(), 1);
+        assert!(handled[0].report.is_none());
+        assert_eq!(handled[0].moved_to, Some(dir.join(PROCESSED_DIR).join("scan.fda")));
+        assert!(!dir.join("out").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_are_extracted_again() {
+        let dir = drop_folder("changed");
+        write_ledger(&dir, 1);
+        let handled = watcher(&dir, Duration::ZERO).poll();
+        assert_eq!(handled.len(), 1);
+        assert_eq!(handled[0].report.as_ref().map(|report| report.outcome), Some(Outcome::UnreadableInput));
+        // Failed inputs go to failed/, and the ledger gets a second line
+        assert_eq!(handled[0].moved_to, Some(dir.join(FAILED_DIR).join("scan.fda")));
+        assert_eq!(fs::read_to_string(dir.join(DEFAULT_LEDGER)).unwrap().lines().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_wait_until_they_settle() {
+        let dir = drop_folder("settle");
+        let mut watcher = watcher(&dir, Duration::from_secs(3600));
+        assert!(watcher.poll().is_empty());
+        assert_eq!(watcher.waiting(), 1);
+        assert!(dir.join("scan.fda").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
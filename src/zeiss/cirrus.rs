@@ -0,0 +1,246 @@
+use image::{DynamicImage, GrayImage};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::formats::{bscan_entries, ImageEntry, Metadata, OctReader};
+use crate::volume::{Volume, VoxelSpacing};
+
+// The Cirrus HD-OCT specifications give 1024 samples over a 2 mm scan depth, and both the macular
+// and the optic disc cubes cover 6 x 6 mm. The files themselves record none of it, so a cube
+// acquired with another field of view gets the wrong spacing.
+const CUBE_DEPTH: usize = 1024;
+const CUBE_WIDTH_MM: f64 = 6.0;
+const CUBE_LENGTH_MM: f64 = 6.0;
+const CUBE_DEPTH_MM: f64 = 2.0;
+
+// Scan protocols used when the file name does not give the geometry: (name, a-scans, b-scans)
+const KNOWN_CUBES: [(&str, usize, usize); 2] = [
+    ("Macular Cube 512x128", 512, 128),
+    ("Cube 200x200", 200, 200),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CirrusGeometry {
+    pub width: usize,
+    pub height: usize,
+    pub slices: usize,
+    pub scan_type: String,
+}
+
+// Cirrus cubes have no header, so an .img file is only taken for one when its name follows the
+// export pattern below or its size is that of a standard cube
+pub fn is_cirrus_img(filepath: &str) -> bool {
+    let path = Path::new(filepath);
+    if !path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("img")) {
+        return false;
+    }
+    let file_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    let info = parse_file_name(&file_name);
+    if info.contains_key("scan_type") && info.contains_key("cube") {
+        return true;
+    }
+    fs::metadata(path).is_ok_and(|metadata| {
+        KNOWN_CUBES.iter().any(|(_, width, slices)| (width * slices * CUBE_DEPTH) as u64 == metadata.len())
+    })
+}
+
+// Cirrus exports are named like
+// PID_Macular Cube 512x128_10-25-2016_14-30-12_OD_sn12345_cube_z.img
+pub fn parse_file_name(file_name: &str) -> HashMap<String, String> {
+    let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _)| stem);
+    let tokens: Vec<&str> = stem.split('_').collect();
+    let mut info = HashMap::new();
+    if tokens.len() < 2 {
+        return info;
+    }
+
+    info.insert("patient_id".to_string(), tokens[0].to_string());
+    let mut dates = Vec::new();
+    for token in &tokens[1..] {
+        let digits_and_dashes = token.chars().all(|c| c.is_ascii_digit() || c == '-') && token.contains('-');
+        match *token {
+            "OD" => { info.insert("eye".to_string(), "RIGHT".to_string()); }
+            "OS" => { info.insert("eye".to_string(), "LEFT".to_string()); }
+            "z" | "raw" => { info.insert("cube".to_string(), token.to_string()); }
+            t if t.contains("Cube") => { info.insert("scan_type".to_string(), t.to_string()); }
+            t if t.len() > 2 && t.starts_with("sn") => { info.insert("serial_number".to_string(), t[2..].to_string()); }
+            t if digits_and_dashes => dates.push(t),
+            _ => {}
+        }
+    }
+    if let Some(date) = dates.first() {
+        info.insert("scan_date".to_string(), date.to_string());
+    }
+    if let Some(time) = dates.get(1) {
+        info.insert("scan_time".to_string(), time.replace('-', ":"));
+    }
+    info
+}
+
+// "Macular Cube 512x128" -> (512, 128)
+fn dimensions_from_scan_type(scan_type: &str) -> Option<(usize, usize)> {
+    let dims = scan_type.split_whitespace().find(|word| word.contains('x'))?;
+    let (width, slices) = dims.split_once('x')?;
+    Some((width.parse().ok()?, slices.parse().ok()?))
+}
+
+pub fn infer_geometry(file_size: usize, scan_type: Option<&str>) -> Result<CirrusGeometry, Box<dyn Error>> {
+    if file_size == 0 {
+        return Err("Cirrus file is empty".into());
+    }
+    if let Some((width, slices)) = scan_type.and_then(dimensions_from_scan_type) {
+        // At least one row per B-scan, an exact number of rows in all
+        let slab = width.checked_mul(slices).filter(|slab| *slab > 0 && *slab <= file_size);
+        if let Some(slab) = slab.filter(|slab| file_size.is_multiple_of(*slab)) {
+            return Ok(CirrusGeometry {
+                width,
+                height: file_size / slab,
+                slices,
+                scan_type: scan_type.unwrap_or_default().to_string(),
+            });
+        }
+        log::warn!("File size {} does not match a {}x{} cube, guessing from size", file_size, width, slices);
+    }
+
+    KNOWN_CUBES
+        .iter()
+        .find(|(_, width, slices)| width * slices * CUBE_DEPTH == file_size)
+        .map(|(name, width, slices)| CirrusGeometry {
+            width: *width,
+            height: CUBE_DEPTH,
+            slices: *slices,
+            scan_type: name.to_string(),
+        })
+        .ok_or_else(|| format!("Cannot infer the cube geometry of a {} byte Cirrus file", file_size).into())
+}
+
+// Geometry and file name fields of a cube, from its size and name alone
+pub fn read_cirrus_info(filepath: &str) -> Result<(CirrusGeometry, HashMap<String, String>), Box<dyn Error>> {
+    let file_size = usize::try_from(fs::metadata(filepath)?.len())?;
+    let file_name = Path::new(filepath).file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    let mut info = parse_file_name(&file_name);
+    let geometry = infer_geometry(file_size, info.get("scan_type").map(String::as_str))?;
+
+    info.insert("scan_type".to_string(), geometry.scan_type.clone());
+    info.insert("width".to_string(), geometry.width.to_string());
+    info.insert("height".to_string(), geometry.height.to_string());
+    info.insert("number_slices".to_string(), geometry.slices.to_string());
+    Ok((geometry, info))
+}
+
+// Raw uint8 cube, one B-scan after the other, each stored bottom row first.
+// Reads `count` B-scans from `first` on, top row first.
+pub fn read_cirrus_slices(filepath: &str, geometry: &CirrusGeometry, first: usize, count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let slice_size = geometry.width * geometry.height;
+    let mut raw = vec![0; slice_size * count];
+    let mut file = File::open(filepath)?;
+    file.seek(SeekFrom::Start((first * slice_size) as u64))?;
+    file.read_exact(&mut raw)?;
+
+    let mut data = Vec::with_capacity(raw.len());
+    for slice in raw.chunks_exact(slice_size) {
+        for row in slice.chunks_exact(geometry.width).rev() {
+            data.extend_from_slice(row);
+        }
+    }
+    Ok(data)
+}
+
+pub struct CirrusReader {
+    filepath: String,
+    geometry: CirrusGeometry,
+    info: HashMap<String, String>,
+}
+
+impl OctReader for CirrusReader {
+    // Cirrus cubes carry no metadata of their own, only what the file name tells
+    fn open(filepath: &str) -> Result<Self, Box<dyn Error>> {
+        let (geometry, info) = read_cirrus_info(filepath)?;
+        Ok(CirrusReader { filepath: filepath.to_string(), geometry, info })
+    }
+
+    fn format_name(&self) -> &'static str {
+        "Zeiss Cirrus IMG"
+    }
+
+    fn metadata(&self) -> Result<Metadata, Box<dyn Error>> {
+        Ok(HashMap::from([("CIRRUS_INFO".to_string(), self.info.clone())]))
+    }
+
+    fn list_images(&self) -> Result<Vec<ImageEntry>, Box<dyn Error>> {
+        Ok(bscan_entries(self.geometry.slices))
+    }
+
+    fn read_image(&self, entry: &ImageEntry) -> Result<DynamicImage, Box<dyn Error>> {
+        if entry.index >= self.geometry.slices {
+            return Err(format!("B-scan {} out of range, the cube has {}", entry.index, self.geometry.slices).into());
+        }
+        let data = read_cirrus_slices(&self.filepath, &self.geometry, entry.index, 1)?;
+        let image = GrayImage::from_raw(self.geometry.width as u32, self.geometry.height as u32, data).ok_or("Failed to create Cirrus B-scan")?;
+        Ok(DynamicImage::ImageLuma8(image))
+    }
+
+    fn read_volume(&self) -> Result<Volume, Box<dyn Error>> {
+        let geometry = &self.geometry;
+        Ok(Volume {
+            width: geometry.width,
+            height: geometry.height,
+            depth: geometry.slices,
+            spacing: VoxelSpacing {
+                x_mm: CUBE_WIDTH_MM / geometry.width as f64,
+                y_mm: CUBE_LENGTH_MM / geometry.slices as f64,
+                z_mm: CUBE_DEPTH_MM / geometry.height as f64,
+            },
+            data: read_cirrus_slices(&self.filepath, geometry, 0, geometry.slices)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_name_fields() {
+        let info = parse_file_name("P0042_Macular Cube 512x128_10-25-2016_14-30-12_OD_sn12345_cube_z.img");
+        assert_eq!(info["patient_id"], "P0042");
+        assert_eq!(info["scan_type"], "Macular Cube 512x128");
+        assert_eq!(info["scan_date"], "10-25-2016");
+        assert_eq!(info["scan_time"], "14:30:12");
+        assert_eq!(info["eye"], "RIGHT");
+        assert_eq!(info["serial_number"], "12345");
+        assert_eq!(info["cube"], "z");
+    }
+
+    #[test]
+    fn unrelated_file_names_give_nothing_to_detect_on() {
+        assert!(parse_file_name("disk.img").is_empty());
+        let info = parse_file_name("backup_2016-10-25.img");
+        assert!(!info.contains_key("scan_type") && !info.contains_key("cube"));
+    }
+
+    #[test]
+    fn geometry_from_scan_type() {
+        let geometry = infer_geometry(512 * 128 * 1024, Some("Macular Cube 512x128")).unwrap();
+        assert_eq!((geometry.width, geometry.height, geometry.slices), (512, 1024, 128));
+        let geometry = infer_geometry(4 * 3 * 5, Some("Cube 4x3")).unwrap();
+        assert_eq!((geometry.width, geometry.height, geometry.slices), (4, 5, 3));
+    }
+
+    #[test]
+    fn geometry_from_size() {
+        let geometry = infer_geometry(200 * 200 * 1024, None).unwrap();
+        assert_eq!(geometry.scan_type, "Cube 200x200");
+        assert!(infer_geometry(12345, None).is_err());
+    }
+
+    #[test]
+    fn empty_and_undersized_files_are_rejected() {
+        assert!(infer_geometry(0, Some("Macular Cube 512x128")).is_err());
+        assert!(infer_geometry(0, None).is_err());
+        assert!(infer_geometry(100, Some("Cube 512x128")).is_err());
+    }
+}